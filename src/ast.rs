@@ -1,31 +1,85 @@
+use super::bignum::{BigInt, Rational};
 use super::bstring::BString;
+use super::number;
+use super::symbol::Symbol;
 use std::fmt;
 use std::rc::Rc;
+use std::sync::Arc;
 
+// The span of source code an expression was parsed from. 'line' and 'column'
+// are where it starts, 'end_line' and 'end_column' are just past where it ends.
 #[derive(Debug, Clone)]
 pub struct Location {
     pub line: u32,
     pub column: u32,
-    pub file: Rc<BString>,
+    pub end_line: u32,
+    pub end_column: u32,
+    pub file: Arc<BString>,
 }
 
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}-{}:{}",
+            self.file, self.line, self.column, self.end_line, self.end_column
+        )
+    }
+}
+
+// Every expression has the location it was parsed from as its last field.
 #[derive()]
 pub enum Expression {
-    String(BString),
-    Number(f64),
-    Lookup(BString),
+    String(Rc<BString>, Location),
+    Number(f64, Location),
+    Int(i64, Location),
+    BigInt(Rc<BigInt>, Location),
+    Rational(Rc<Rational>, Location),
+    Lookup(Symbol, Location),
+    // A lookup which has been resolved to a slot in the call frame of an enclosing
+    // lambda. The first number is how many frames to skip, the second is the slot.
+    // See the 'resolve' module.
+    Local(Symbol, u32, u32, Location),
+    // The name of a keyword argument, like ':width', which is followed
+    // by the argument's value in a call
+    Keyword(Symbol, Location),
     Call(Vec<Expression>, Location),
-    Block(Rc<Vec<Expression>>),
+    Block(Rc<Vec<Expression>>, Location),
+    // A property access, like 'dict.key', 'list.0' or 'obj.method',
+    // with the value and the key
+    Property(Box<Expression>, Box<Expression>, Location),
+}
+
+impl Expression {
+    pub fn loc(&self) -> &Location {
+        match self {
+            Expression::String(_, loc)
+            | Expression::Number(_, loc)
+            | Expression::Int(_, loc)
+            | Expression::BigInt(_, loc)
+            | Expression::Rational(_, loc)
+            | Expression::Lookup(_, loc)
+            | Expression::Local(_, _, _, loc)
+            | Expression::Keyword(_, loc)
+            | Expression::Call(_, loc)
+            | Expression::Block(_, loc)
+            | Expression::Property(_, _, loc) => loc,
+        }
+    }
 }
 
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Expression::String(s) => {
+            Expression::String(s, _) => {
                 write!(f, "{:?}", s)
             }
-            Expression::Number(num) => write!(f, "{}", num),
-            Expression::Lookup(name) => write!(f, "{}", name),
+            Expression::Number(num, _) => number::fmt_float(f, *num),
+            Expression::Int(num, _) => write!(f, "{}", num),
+            Expression::BigInt(num, _) => write!(f, "{}", num),
+            Expression::Rational(num, _) => write!(f, "{}", num),
+            Expression::Lookup(name, _) | Expression::Local(name, ..) => write!(f, "{}", name),
+            Expression::Keyword(name, _) => write!(f, ":{}", name),
             Expression::Call(exprs, _) => {
                 write!(f, "(")?;
                 let mut first = true;
@@ -39,7 +93,7 @@ impl fmt::Display for Expression {
                 }
                 write!(f, ")")
             }
-            Expression::Block(exprs) => {
+            Expression::Block(exprs, _) => {
                 write!(f, "{{")?;
                 let mut first = true;
                 for expr in exprs.iter() {
@@ -52,6 +106,10 @@ impl fmt::Display for Expression {
                 }
                 write!(f, "}}")
             }
+            Expression::Property(val, key, _) => match key.as_ref() {
+                Expression::String(name, _) => write!(f, "{}.{}", val, name),
+                _ => write!(f, "{}.{}", val, key),
+            },
         }
     }
 }
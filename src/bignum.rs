@@ -0,0 +1,660 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Arbitrary-precision integers, stored as a sign and a magnitude.
+// The magnitude is a list of 32-bit limbs, least significant first,
+// with no trailing zero limbs. Zero has an empty magnitude and is never negative.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BigInt {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+fn trim(mut mag: Vec<u32>) -> Vec<u32> {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn mag_cmp(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn mag_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut res = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0u64;
+    for (idx, limb) in a.iter().enumerate() {
+        let sum = *limb as u64 + *b.get(idx).unwrap_or(&0) as u64 + carry;
+        res.push(sum as u32);
+        carry = sum >> 32;
+    }
+
+    if carry > 0 {
+        res.push(carry as u32);
+    }
+    res
+}
+
+// Subtract 'b' from 'a', where 'a' must be at least as big as 'b'
+fn mag_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut res = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (idx, limb) in a.iter().enumerate() {
+        let mut diff = *limb as i64 - *b.get(idx).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        res.push(diff as u32);
+    }
+
+    trim(res)
+}
+
+fn mag_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+
+    let mut res = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let prod = *x as u64 * *y as u64 + res[i + j] as u64 + carry;
+            res[i + j] = prod as u32;
+            carry = prod >> 32;
+        }
+        res[i + b.len()] = carry as u32;
+    }
+
+    trim(res)
+}
+
+// Multiply by a small number and add a small number, in place
+fn mag_mul_add_small(mag: &mut Vec<u32>, mul: u32, add: u32) {
+    let mut carry = add as u64;
+    for limb in mag.iter_mut() {
+        let prod = *limb as u64 * mul as u64 + carry;
+        *limb = prod as u32;
+        carry = prod >> 32;
+    }
+
+    if carry > 0 {
+        mag.push(carry as u32);
+    }
+}
+
+fn mag_divrem_small(a: &[u32], div: u32) -> (Vec<u32>, u32) {
+    let mut quot = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for idx in (0..a.len()).rev() {
+        let cur = (rem << 32) | a[idx] as u64;
+        quot[idx] = (cur / div as u64) as u32;
+        rem = cur % div as u64;
+    }
+
+    (trim(quot), rem as u32)
+}
+
+fn mag_shl(a: &[u32], bits: usize) -> Vec<u32> {
+    if a.is_empty() {
+        return Vec::new();
+    }
+
+    let (limbs, bits) = (bits / 32, bits % 32);
+    let mut res = vec![0u32; limbs];
+    if bits == 0 {
+        res.extend_from_slice(a);
+        return res;
+    }
+
+    let mut carry = 0u32;
+    for limb in a {
+        res.push((limb << bits) | carry);
+        carry = limb >> (32 - bits);
+    }
+
+    if carry > 0 {
+        res.push(carry);
+    }
+    res
+}
+
+fn mag_shr(a: &[u32], bits: usize) -> Vec<u32> {
+    let (limbs, bits) = (bits / 32, bits % 32);
+    if limbs >= a.len() {
+        return Vec::new();
+    }
+
+    let a = &a[limbs..];
+    if bits == 0 {
+        return a.to_vec();
+    }
+
+    let mut res = Vec::with_capacity(a.len());
+    for idx in 0..a.len() {
+        let high = a.get(idx + 1).map_or(0, |limb| limb << (32 - bits));
+        res.push((a[idx] >> bits) | high);
+    }
+
+    trim(res)
+}
+
+fn mag_bits(a: &[u32]) -> usize {
+    match a.last() {
+        Some(top) => a.len() * 32 - top.leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+fn mag_bit(a: &[u32], bit: usize) -> bool {
+    a.get(bit / 32)
+        .is_some_and(|limb| limb & (1 << (bit % 32)) != 0)
+}
+
+// Whether any of the bits below 'bit' are set
+fn mag_any_below(a: &[u32], bit: usize) -> bool {
+    let (limbs, bits) = (bit / 32, bit % 32);
+    a.iter().take(limbs).any(|limb| *limb != 0)
+        || a.get(limbs)
+            .is_some_and(|limb| limb & ((1 << bits) - 1) != 0)
+}
+
+// The nearest float to 'mag * 2^exp', rounding ties to even. 'sticky' is whether
+// some non-zero bits below 'mag' were cut off, which makes it slightly bigger.
+fn mag_to_f64(mag: &[u32], exp: i64, sticky: bool) -> f64 {
+    let bits = mag_bits(mag) as i64;
+    if bits == 0 {
+        return 0.0;
+    }
+
+    // Floats have 53 bits of precision, except for subnormals,
+    // which lose one bit for every power of two below 2^-1022
+    let top = bits - 1 + exp;
+    if top > 1023 {
+        return f64::INFINITY;
+    }
+    let keep = 53 - (-1022 - top).max(0);
+    if keep < 0 {
+        return 0.0;
+    }
+
+    let drop = (bits - keep).max(0);
+    let mut num = mag_shr(mag, drop as usize)
+        .iter()
+        .rev()
+        .fold(0u64, |acc, limb| (acc << 32) | *limb as u64);
+    if drop > 0 && mag_bit(mag, drop as usize - 1) {
+        let above_half = sticky || mag_any_below(mag, drop as usize - 1);
+        if above_half || num & 1 == 1 {
+            num += 1;
+        }
+    }
+
+    // The result is exact, but the scale is applied in two steps so that
+    // the intermediate result doesn't overflow or underflow
+    let exp = (exp + drop) as i32;
+    num as f64 * 2f64.powi(exp / 2) * 2f64.powi(exp - exp / 2)
+}
+
+// Long division (Knuth's algorithm D). 'b' must not be zero.
+fn mag_divrem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if mag_cmp(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+
+    if b.len() == 1 {
+        let (quot, rem) = mag_divrem_small(a, b[0]);
+        return (quot, trim(vec![rem]));
+    }
+
+    // Normalize so that the top bit of the divisor is set,
+    // which keeps the estimated quotient digits within 2 of the real ones
+    let shift = b[b.len() - 1].leading_zeros() as usize;
+    let v = mag_shl(b, shift);
+    let mut u = mag_shl(a, shift);
+    u.resize(a.len() + 1, 0);
+
+    const BASE: u64 = 1 << 32;
+    let n = v.len();
+    let mut quot = vec![0u32; a.len() - n + 1];
+    for j in (0..quot.len()).rev() {
+        let num = ((u[j + n] as u64) << 32) | u[j + n - 1] as u64;
+        let mut qhat = num / v[n - 1] as u64;
+        let mut rhat = num % v[n - 1] as u64;
+        while qhat >= BASE || qhat * v[n - 2] as u64 > ((rhat << 32) | u[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += v[n - 1] as u64;
+            if rhat >= BASE {
+                break;
+            }
+        }
+
+        // Subtract qhat * v from the current window of u
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let prod = qhat * v[i] as u64 + carry;
+            carry = prod >> 32;
+            let diff = u[i + j] as i64 - borrow - (prod & 0xffffffff) as i64;
+            u[i + j] = diff as u32;
+            borrow = (diff < 0) as i64;
+        }
+        let diff = u[j + n] as i64 - borrow - carry as i64;
+        u[j + n] = diff as u32;
+
+        // qhat was one too big, so add v back
+        if diff < 0 {
+            qhat -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = u[i + j] as u64 + v[i] as u64 + carry;
+                u[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            u[j + n] = u[j + n].wrapping_add(carry as u32);
+        }
+
+        quot[j] = qhat as u32;
+    }
+
+    (trim(quot), mag_shr(&u[..n], shift))
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        Self {
+            neg: false,
+            mag: Vec::new(),
+        }
+    }
+
+    pub fn one() -> Self {
+        Self::from_u64(1)
+    }
+
+    fn from_mag(neg: bool, mag: Vec<u32>) -> Self {
+        let mag = trim(mag);
+        Self {
+            neg: neg && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    pub fn from_u64(num: u64) -> Self {
+        Self::from_mag(false, vec![num as u32, (num >> 32) as u32])
+    }
+
+    pub fn from_i64(num: i64) -> Self {
+        let mut big = Self::from_u64(num.unsigned_abs());
+        big.neg = num < 0;
+        big
+    }
+
+    // Build an integer from its digits, most significant first.
+    // Every digit must be less than 'base'.
+    pub fn from_digits(neg: bool, digits: &[u8], base: u32) -> Self {
+        let mut mag = Vec::new();
+        for digit in digits {
+            mag_mul_add_small(&mut mag, base, *digit as u32);
+        }
+
+        Self::from_mag(neg, mag)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.neg
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+
+        let mag = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0u64, |acc, limb| (acc << 32) | *limb as u64);
+        if self.neg {
+            0i64.checked_sub_unsigned(mag)
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    // The nearest float, or infinity if the number is too big
+    pub fn to_f64(&self) -> f64 {
+        let num = mag_to_f64(&self.mag, 0, false);
+        if self.neg {
+            -num
+        } else {
+            num
+        }
+    }
+
+    // The nearest float to 'self / other', which must not be zero
+    pub fn div_to_f64(&self, other: &Self) -> f64 {
+        if self.is_zero() {
+            return 0.0;
+        }
+
+        // Scale the numerator so that the quotient has more bits than a float,
+        // even if both parts are too big for a float. The remainder decides
+        // how to round ties.
+        let shift = other.bits() as i64 - self.bits() as i64 + 66;
+        let (num, den) = if shift > 0 {
+            (mag_shl(&self.mag, shift as usize), other.mag.clone())
+        } else {
+            (self.mag.clone(), mag_shl(&other.mag, -shift as usize))
+        };
+
+        let (quot, rem) = mag_divrem(&num, &den);
+        let num = mag_to_f64(&quot, -shift, !rem.is_empty());
+        if self.neg != other.neg {
+            -num
+        } else {
+            num
+        }
+    }
+
+    // The number of bits in the magnitude
+    pub fn bits(&self) -> usize {
+        mag_bits(&self.mag)
+    }
+
+    // The number of 32-bit limbs used to store the magnitude
+    pub fn limbs(&self) -> usize {
+        self.mag.len()
+    }
+
+    pub fn neg(&self) -> Self {
+        Self::from_mag(!self.neg, self.mag.clone())
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::from_mag(false, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        if self.neg == other.neg {
+            return Self::from_mag(self.neg, mag_add(&self.mag, &other.mag));
+        }
+
+        match mag_cmp(&self.mag, &other.mag) {
+            Ordering::Less => Self::from_mag(other.neg, mag_sub(&other.mag, &self.mag)),
+            _ => Self::from_mag(self.neg, mag_sub(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        Self::from_mag(self.neg != other.neg, mag_mul(&self.mag, &other.mag))
+    }
+
+    // Division which rounds towards zero, so the remainder has the sign of 'self'.
+    // Returns None if 'other' is zero.
+    pub fn divrem(&self, other: &Self) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let (quot, rem) = mag_divrem(&self.mag, &other.mag);
+        Some((
+            Self::from_mag(self.neg != other.neg, quot),
+            Self::from_mag(self.neg, rem),
+        ))
+    }
+
+    pub fn pow(&self, mut exp: u32) -> Self {
+        let mut base = self.clone();
+        let mut res = Self::one();
+        while exp > 0 {
+            if exp & 1 == 1 {
+                res = res.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        res
+    }
+
+    pub fn shl(&self, bits: usize) -> Self {
+        Self::from_mag(self.neg, mag_shl(&self.mag, bits))
+    }
+
+    pub fn gcd(&self, other: &Self) -> Self {
+        let mut a = self.mag.clone();
+        let mut b = other.mag.clone();
+        while !b.is_empty() {
+            let (_, rem) = mag_divrem(&a, &b);
+            a = b;
+            b = rem;
+        }
+
+        Self::from_mag(false, a)
+    }
+
+    // Format the number in a base between 2 and 36
+    pub fn to_string_radix(&self, base: u32) -> String {
+        if self.is_zero() {
+            return "0".to_string();
+        }
+
+        // Divide by the biggest power of the base which fits in a limb,
+        // so that each division produces several digits
+        let mut chunk = base;
+        let mut chunk_digits = 1;
+        while let Some(next) = chunk.checked_mul(base) {
+            chunk = next;
+            chunk_digits += 1;
+        }
+
+        let mut digits = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let (quot, mut rem) = mag_divrem_small(&mag, chunk);
+            mag = quot;
+            for _ in 0..chunk_digits {
+                if mag.is_empty() && rem == 0 {
+                    break;
+                }
+                digits.push(std::char::from_digit(rem % base, base).unwrap());
+                rem /= base;
+            }
+        }
+
+        if self.neg {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.mag, &other.mag),
+            (true, true) => mag_cmp(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_radix(10))
+    }
+}
+
+// Exact fractions. The denominator is always positive,
+// and the numerator and denominator have no common factors.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Rational {
+    num: BigInt,
+    den: BigInt,
+}
+
+impl Rational {
+    // Returns None if the denominator is zero
+    pub fn new(num: BigInt, den: BigInt) -> Option<Self> {
+        if den.is_zero() {
+            return None;
+        }
+
+        let gcd = num.gcd(&den);
+        let (mut num, mut den) = if gcd == BigInt::one() {
+            (num, den)
+        } else {
+            (num.divrem(&gcd)?.0, den.divrem(&gcd)?.0)
+        };
+
+        if den.is_negative() {
+            num = num.neg();
+            den = den.neg();
+        }
+
+        Some(Self { num, den })
+    }
+
+    pub fn from_int(num: BigInt) -> Self {
+        Self {
+            num,
+            den: BigInt::one(),
+        }
+    }
+
+    // The exact value of a float, or None if it's infinite or NaN
+    pub fn from_f64(num: f64) -> Option<Self> {
+        if !num.is_finite() {
+            return None;
+        }
+
+        let bits = num.to_bits();
+        let neg = bits >> 63 == 1;
+        let exp = ((bits >> 52) & 0x7ff) as i64;
+        let frac = bits & ((1 << 52) - 1);
+
+        // Subnormals have no implicit leading bit
+        let (mant, exp) = if exp == 0 {
+            (frac, -1074)
+        } else {
+            (frac | (1 << 52), exp - 1075)
+        };
+
+        let mut mant = BigInt::from_u64(mant);
+        if neg {
+            mant = mant.neg();
+        }
+
+        if exp >= 0 {
+            Some(Self::from_int(mant.shl(exp as usize)))
+        } else {
+            Self::new(mant, BigInt::one().shl(-exp as usize))
+        }
+    }
+
+    pub fn numer(&self) -> &BigInt {
+        &self.num
+    }
+
+    pub fn denom(&self) -> &BigInt {
+        &self.den
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.den == BigInt::one()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.num.div_to_f64(&self.den)
+    }
+
+    // Round towards zero
+    pub fn trunc(&self) -> BigInt {
+        self.num.divrem(&self.den).unwrap().0
+    }
+
+    pub fn neg(&self) -> Self {
+        Self {
+            num: self.num.neg(),
+            den: self.den.clone(),
+        }
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        let num = self.num.mul(&other.den).add(&other.num.mul(&self.den));
+        Self::new(num, self.den.mul(&other.den)).unwrap()
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        Self::new(self.num.mul(&other.num), self.den.mul(&other.den)).unwrap()
+    }
+
+    // Returns None if 'other' is zero
+    pub fn div(&self, other: &Self) -> Option<Self> {
+        Self::new(self.num.mul(&other.den), self.den.mul(&other.num))
+    }
+
+    // The remainder of division rounded towards zero, which has the sign of 'self'.
+    // Returns None if 'other' is zero.
+    pub fn rem(&self, other: &Self) -> Option<Self> {
+        let quot = Self::from_int(self.div(other)?.trunc());
+        Some(self.sub(&other.mul(&quot)))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.num.mul(&other.den).cmp(&other.num.mul(&self.den))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// A small line editor with history, for terminals which understand VT100
+// escape codes. While a line is being edited, the terminal is switched to
+// raw mode with 'stty', so no terminal library is needed.
+// When stdin isn't a terminal, lines are read as they are.
+
+const MAX_HISTORY: usize = 1000;
+
+pub enum ReadLine {
+    Line(String),
+    // The user pressed Ctrl-C
+    Interrupted,
+    Eof,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    WordLeft,
+    WordRight,
+    Home,
+    End,
+    Up,
+    Down,
+    KillToEnd,
+    KillToStart,
+    KillWord,
+    Clear,
+    Interrupt,
+    Eof,
+    // Stdin was closed
+    Closed,
+    Ignore,
+}
+
+// Puts the terminal in raw mode, and restores the old mode when dropped
+struct RawMode {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if out.status.success() {
+        String::from_utf8(out.stdout).ok()
+    } else {
+        None
+    }
+}
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        if !cfg!(unix) {
+            return None;
+        }
+
+        let saved = stty(&["-g"])?.trim().to_string();
+        stty(&[
+            "-icanon", "-echo", "-isig", "-ixon", "-iexten", "min", "1", "time", "0",
+        ])?;
+        Some(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+fn terminal_width() -> usize {
+    stty(&["size"])
+        .and_then(|size| size.split_whitespace().nth(1)?.parse().ok())
+        .filter(|cols| *cols > 0)
+        .unwrap_or(80)
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(..) => return Ok(Some(buf[0])),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Read the rest of an escape sequence, after the ESC
+fn read_escape(input: &mut impl Read) -> io::Result<Key> {
+    match read_byte(input)? {
+        Some(b'[') | Some(b'O') => (),
+        Some(b'b') => return Ok(Key::WordLeft),
+        Some(b'f') => return Ok(Key::WordRight),
+        _ => return Ok(Key::Ignore),
+    }
+
+    // Control sequences are parameters followed by a final byte, like "1;5C"
+    let mut params = String::new();
+    let last = loop {
+        match read_byte(input)? {
+            Some(ch) if (0x40..=0x7e).contains(&ch) => break ch,
+            Some(ch) => params.push(ch as char),
+            None => return Ok(Key::Ignore),
+        }
+    };
+
+    let modified = params.contains(';');
+    Ok(match (last, params.split(';').next().unwrap_or("")) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) if modified => Key::WordRight,
+        (b'D', _) if modified => Key::WordLeft,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) => Key::Home,
+        (b'F', _) => Key::End,
+        (b'~', "1") | (b'~', "7") => Key::Home,
+        (b'~', "4") | (b'~', "8") => Key::End,
+        (b'~', "3") => Key::Delete,
+        _ => Key::Ignore,
+    })
+}
+
+fn read_key(input: &mut impl Read) -> io::Result<Key> {
+    let first = match read_byte(input)? {
+        Some(ch) => ch,
+        None => return Ok(Key::Closed),
+    };
+
+    Ok(match first {
+        0x01 => Key::Home,
+        0x02 => Key::Left,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x05 => Key::End,
+        0x06 => Key::Right,
+        0x08 | 0x7f => Key::Backspace,
+        b'\t' => Key::Char('\t'),
+        b'\r' | b'\n' => Key::Enter,
+        0x0b => Key::KillToEnd,
+        0x0c => Key::Clear,
+        0x0e => Key::Down,
+        0x10 => Key::Up,
+        0x15 => Key::KillToStart,
+        0x17 => Key::KillWord,
+        0x1b => read_escape(input)?,
+        ch if ch < 0x20 => Key::Ignore,
+        ch => {
+            // Read the rest of a UTF-8 character
+            let len = match ch {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+
+            let mut bytes = vec![ch];
+            while bytes.len() < len {
+                match read_byte(input)? {
+                    Some(ch) => bytes.push(ch),
+                    None => break,
+                }
+            }
+
+            let text = String::from_utf8(bytes).unwrap_or_default();
+            match text.chars().next() {
+                Some(ch) => Key::Char(ch),
+                None => Key::Ignore,
+            }
+        }
+    })
+}
+
+fn is_word_char(ch: char) -> bool {
+    !(ch.is_whitespace() || "()[]{}'\".".contains(ch))
+}
+
+// The line which is being edited. Each character is assumed to be one column wide,
+// and tabs are shown as spaces.
+struct Line {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl Line {
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn word_start(&self) -> usize {
+        let mut idx = self.cursor;
+        while idx > 0 && !is_word_char(self.chars[idx - 1]) {
+            idx -= 1;
+        }
+        while idx > 0 && is_word_char(self.chars[idx - 1]) {
+            idx -= 1;
+        }
+        idx
+    }
+
+    fn word_end(&self) -> usize {
+        let mut idx = self.cursor;
+        while idx < self.chars.len() && !is_word_char(self.chars[idx]) {
+            idx += 1;
+        }
+        while idx < self.chars.len() && is_word_char(self.chars[idx]) {
+            idx += 1;
+        }
+        idx
+    }
+
+    // Draw the prompt and the line. Lines which are too long for the terminal
+    // are scrolled horizontally so that the cursor is visible.
+    fn refresh(&self, out: &mut impl Write, prompt: &str, cols: usize) -> io::Result<()> {
+        let prompt_width = prompt.chars().count();
+        let avail = cols.saturating_sub(prompt_width + 1).max(1);
+        let start = (self.cursor + 1).saturating_sub(avail);
+        let end = (start + avail).min(self.chars.len());
+        let text: String = self.chars[start..end]
+            .iter()
+            .map(|ch| if *ch == '\t' { ' ' } else { *ch })
+            .collect();
+
+        write!(out, "\r{}{}\x1b[K\r", prompt, text)?;
+        let col = prompt_width + self.cursor - start;
+        if col > 0 {
+            write!(out, "\x1b[{}C", col)?;
+        }
+        out.flush()
+    }
+}
+
+pub struct Editor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+    interactive: bool,
+}
+
+impl Editor {
+    // History is loaded from and saved to 'history_path',
+    // but only when lines are read from a terminal
+    pub fn new(history_path: Option<PathBuf>) -> Self {
+        let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+        let history_path = history_path.filter(|_| interactive);
+        let mut history: Vec<String> = match &history_path {
+            Some(path) => fs::read(path)
+                .map(|data| {
+                    String::from_utf8_lossy(&data)
+                        .lines()
+                        .map(|line| line.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        // The history file is appended to, so it's trimmed once it grows too long
+        if history.len() > MAX_HISTORY * 2 {
+            history.drain(..history.len() - MAX_HISTORY);
+            if let Some(path) = &history_path {
+                let _ = fs::write(path, history.join("\n") + "\n");
+            }
+        } else if history.len() > MAX_HISTORY {
+            history.drain(..history.len() - MAX_HISTORY);
+        }
+
+        Self {
+            history,
+            history_path,
+            interactive,
+        }
+    }
+
+    // Whether lines are read from a terminal, with line editing
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    // The default history file, from OSYRIS_HISTORY, or ~/.osyris_history
+    pub fn default_history_path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("OSYRIS_HISTORY") {
+            return Some(PathBuf::from(path));
+        }
+
+        let mut path = PathBuf::from(env::var_os("HOME")?);
+        path.push(".osyris_history");
+        Some(path)
+    }
+
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(|s| s.as_str()) == Some(line) {
+            return;
+        }
+
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+
+        if let Some(path) = &self.history_path {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        if self.interactive {
+            if let Some(raw) = RawMode::enable() {
+                let res = self.edit_line(prompt);
+                drop(raw);
+                return res;
+            }
+
+            print!("{}", prompt);
+            io::stdout().flush()?;
+        }
+
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(ReadLine::Eof);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(ReadLine::Line(line))
+    }
+
+    fn edit_line(&mut self, prompt: &str) -> io::Result<ReadLine> {
+        let mut stdin = io::stdin().lock();
+        let mut out = io::stdout().lock();
+        let cols = terminal_width();
+
+        let mut line = Line {
+            chars: Vec::new(),
+            cursor: 0,
+        };
+
+        // 'hist_idx' is the history entry being shown. When it's past the end,
+        // it's the new line, which is kept in 'editing'.
+        let mut hist_idx = self.history.len();
+        let mut editing = String::new();
+
+        line.refresh(&mut out, prompt, cols)?;
+        loop {
+            match read_key(&mut stdin)? {
+                Key::Char(ch) => {
+                    line.chars.insert(line.cursor, ch);
+                    line.cursor += 1;
+                }
+                Key::Enter => {
+                    line.cursor = line.chars.len();
+                    line.refresh(&mut out, prompt, cols)?;
+                    writeln!(out)?;
+                    return Ok(ReadLine::Line(line.chars.iter().collect()));
+                }
+                Key::Backspace => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Delete => {
+                    if line.cursor < line.chars.len() {
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Left => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right => line.cursor = (line.cursor + 1).min(line.chars.len()),
+                Key::WordLeft => line.cursor = line.word_start(),
+                Key::WordRight => line.cursor = line.word_end(),
+                Key::Home => line.cursor = 0,
+                Key::End => line.cursor = line.chars.len(),
+                Key::Up => {
+                    if hist_idx > 0 {
+                        if hist_idx == self.history.len() {
+                            editing = line.chars.iter().collect();
+                        }
+                        hist_idx -= 1;
+                        line.set(&self.history[hist_idx]);
+                    }
+                }
+                Key::Down => {
+                    if hist_idx < self.history.len() {
+                        hist_idx += 1;
+                        match self.history.get(hist_idx) {
+                            Some(text) => line.set(text),
+                            None => line.set(&editing),
+                        }
+                    }
+                }
+                Key::KillToEnd => line.chars.truncate(line.cursor),
+                Key::KillToStart => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::KillWord => {
+                    let start = line.word_start();
+                    line.chars.drain(start..line.cursor);
+                    line.cursor = start;
+                }
+                Key::Clear => write!(out, "\x1b[H\x1b[2J")?,
+                Key::Interrupt => {
+                    writeln!(out, "^C")?;
+                    return Ok(ReadLine::Interrupted);
+                }
+                Key::Closed => {
+                    writeln!(out)?;
+                    return Ok(ReadLine::Eof);
+                }
+                Key::Eof => {
+                    if line.chars.is_empty() {
+                        writeln!(out)?;
+                        return Ok(ReadLine::Eof);
+                    }
+
+                    if line.cursor < line.chars.len() {
+                        line.chars.remove(line.cursor);
+                    }
+                }
+                Key::Ignore => (),
+            }
+
+            line.refresh(&mut out, prompt, cols)?;
+        }
+    }
+}
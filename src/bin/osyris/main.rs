@@ -0,0 +1,270 @@
+use osyris::ast;
+use osyris::bstring::BString;
+use osyris::convert::IntoValRef;
+use osyris::diagnostic;
+use osyris::eval::Backend;
+use osyris::interpreter::{Error, Interpreter};
+use osyris::parse;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process;
+
+mod editor;
+mod repl;
+
+#[cfg(unix)]
+mod sigint {
+    use osyris::eval::InterruptHandle;
+    use std::os::raw::c_int;
+    use std::sync::OnceLock;
+
+    const SIGINT: c_int = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn raise(sig: c_int) -> c_int;
+    }
+
+    static HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+
+    extern "C" fn on_sigint(_: c_int) {
+        if let Some(handle) = HANDLE.get() {
+            // If the last interrupt hasn't been handled yet, the interpreter is stuck
+            // somewhere it can't be interrupted, so fall back to the default behavior
+            if !handle.is_interrupted() {
+                handle.interrupt();
+                return;
+            }
+        }
+
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+            raise(SIGINT);
+        }
+    }
+
+    // Make Ctrl-C interrupt the running script instead of killing the process
+    pub fn install(handle: InterruptHandle) {
+        if HANDLE.set(handle).is_ok() {
+            let handler: extern "C" fn(c_int) = on_sigint;
+            unsafe {
+                signal(SIGINT, handler as usize);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sigint {
+    use osyris::eval::InterruptHandle;
+
+    pub fn install(_: InterruptHandle) {}
+}
+
+fn usage(argv0: &OsStr) {
+    println!(
+        "Usage: {:?} [options] [path | -e <code> | -] [args...]",
+        argv0
+    );
+    println!("Runs the file at 'path', the code given with '-e', or code from stdin with '-'.");
+    println!("Without any of them, a REPL is started.");
+    println!("The program gets its arguments in the list 'argv', after the path.");
+    println!("Options:");
+    println!("  --help, -h:  Show this help text");
+    println!("  -e <code>:   Run 'code' instead of a file");
+    println!("  --repl:      Start a REPL, after running the program if there is one");
+    println!("  --print-ast: Print the syntax tree instead of executing");
+    println!("  --check:     Report all syntax errors instead of executing");
+    println!("  --vm:        Run the code with the bytecode VM");
+}
+
+// Where the code to run comes from
+enum Program {
+    File(BString),
+    Stdin,
+    Code(BString),
+}
+
+impl Program {
+    // The file name which errors refer to
+    fn filename(&self) -> BString {
+        match self {
+            Program::File(path) => path.clone(),
+            Program::Stdin => BString::from_str("<stdin>"),
+            Program::Code(..) => BString::from_str("<command-line>"),
+        }
+    }
+
+    // The first element of 'argv'
+    fn argv0(&self) -> BString {
+        match self {
+            Program::File(path) => path.clone(),
+            Program::Stdin => BString::from_str("-"),
+            Program::Code(..) => BString::from_str("-e"),
+        }
+    }
+
+    // Read the code. Exits if it can't be read.
+    fn read(&self) -> Vec<u8> {
+        let res = match self {
+            Program::File(path) => fs::read(path.to_path()),
+            Program::Stdin => {
+                let mut code = Vec::new();
+                io::stdin().read_to_end(&mut code).map(|_| code)
+            }
+            Program::Code(code) => Ok(code.as_bytes().to_vec()),
+        };
+
+        match res {
+            Ok(code) => code,
+            Err(err) => {
+                eprintln!("{}: {}", self.filename(), err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+// Errors are rendered with colors when they're printed to a terminal,
+// unless the NO_COLOR environment variable is set
+fn use_color() -> bool {
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+// Parse all of the code, printing every syntax error
+fn parse_code(filename: &BString, code: &[u8]) -> (Vec<ast::Expression>, bool) {
+    let mut reader = parse::Reader::new(code, filename.clone());
+    let (exprs, errors) = parse::parse_all(&mut reader);
+    for err in &errors {
+        eprint!(
+            "{}",
+            diagnostic::render_parse_error(err, Some(code), use_color())
+        );
+    }
+
+    (exprs, errors.is_empty())
+}
+
+fn main() {
+    let mut args = env::args_os();
+    let argv0 = args.next().unwrap();
+
+    // Options are only accepted before the program,
+    // everything after it is passed to the program
+    let mut program: Option<Program> = None;
+    let mut argv: Vec<BString> = Vec::new();
+    let mut print_ast = false;
+    let mut check = false;
+    let mut start_repl = false;
+    let mut backend = Backend::Tree;
+    let mut dashes = false;
+    while let Some(arg) = args.next() {
+        if program.is_some() {
+            argv.push(BString::from_os_str(&arg));
+            continue;
+        }
+
+        if !dashes && (arg == "--help" || arg == "-h") {
+            usage(&argv0);
+            return;
+        }
+
+        if !dashes && (arg == "--print-ast") {
+            print_ast = true;
+        } else if !dashes && arg == "--check" {
+            check = true;
+        } else if !dashes && arg == "--repl" {
+            start_repl = true;
+        } else if !dashes && arg == "--vm" {
+            backend = Backend::Vm;
+        } else if !dashes && arg == "-e" {
+            match args.next() {
+                Some(code) => program = Some(Program::Code(BString::from_os_str(&code))),
+                None => {
+                    eprintln!("Option '-e' requires an argument");
+                    process::exit(1);
+                }
+            }
+        } else if !dashes && arg == "--" {
+            dashes = true;
+        } else if arg == "-" {
+            program = Some(Program::Stdin);
+        } else if !dashes && arg.as_encoded_bytes().starts_with(b"-") {
+            eprintln!("Unknown option: {:?}", arg);
+            process::exit(1);
+        } else {
+            program = Some(Program::File(BString::from_os_str(&arg)));
+        }
+    }
+
+    let program = match program {
+        Some(program) => program,
+        None if print_ast || check => {
+            usage(&argv0);
+            process::exit(1);
+        }
+        None => {
+            let mut interp = Interpreter::builder().dotlib(true).backend(backend).build();
+            sigint::install(interp.context().interrupt_handle());
+            interp.set("argv", argv.into_valref());
+            if let Some(status) = repl::run(&mut interp, use_color()) {
+                process::exit(status);
+            }
+            return;
+        }
+    };
+
+    if print_ast || check {
+        let (exprs, ok) = parse_code(&program.filename(), &program.read());
+        if print_ast {
+            for expr in exprs {
+                println!("{}", expr);
+            }
+        }
+
+        if !ok {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut interp = Interpreter::builder().dotlib(true).backend(backend).build();
+    sigint::install(interp.context().interrupt_handle());
+    argv.insert(0, program.argv0());
+    interp.set("argv", argv.into_valref());
+
+    let res = match &program {
+        Program::File(path) => interp.eval_file(path),
+        _ => interp.eval_bytes(&program.read(), program.filename()),
+    };
+
+    if let Some(status) = res.as_ref().err().and_then(Error::exit_status) {
+        process::exit(status);
+    }
+
+    let ok = match res {
+        // Parsing stops at the first error, but it's more helpful to report all of them
+        Err(Error::Parse(..)) => {
+            let filename = program.filename();
+            let code = interp.source(&filename).unwrap_or_default();
+            parse_code(&filename, &code);
+            false
+        }
+        Err(err) => {
+            eprint!("{}", interp.render_error(&err, use_color()));
+            false
+        }
+        Ok(..) => true,
+    };
+
+    if start_repl {
+        if let Some(status) = repl::run(&mut interp, use_color()) {
+            process::exit(status);
+        }
+    } else if !ok {
+        process::exit(1);
+    }
+}
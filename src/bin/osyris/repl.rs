@@ -0,0 +1,159 @@
+use crate::editor::{Editor, ReadLine};
+use osyris::bstring::BString;
+use osyris::diagnostic;
+use osyris::eval::ValRef;
+use osyris::interpreter::{Error, Interpreter};
+use osyris::parse::{self, Next, StreamReader};
+
+// The interactive REPL. Every line is fed to a StreamReader, and each expression
+// is evaluated as soon as it's complete, so expressions can span several lines.
+// Lines which start with a ':' outside of an expression are REPL commands.
+// When a script calls 'exit', the REPL stops with its status.
+
+fn help() {
+    println!("Enter expressions to evaluate them. Commands:");
+    println!("  :help         Show this help text");
+    println!("  :load <path>  Evaluate a file");
+    println!("  :ast <code>   Print the syntax tree of some code");
+    println!("  :scope        Print the variables which have been defined");
+    println!("  :quit         Exit the REPL");
+}
+
+struct Repl<'a> {
+    interp: &'a mut Interpreter,
+    stream: StreamReader,
+    filename: BString,
+    color: bool,
+    exit: Option<i32>,
+}
+
+impl Repl<'_> {
+    fn print_result(&mut self, res: Result<ValRef, Error>) {
+        match res {
+            Ok(ValRef::None) => (),
+            Ok(val) => println!("{}", val),
+            Err(err) if err.exit_status().is_some() => self.exit = err.exit_status(),
+            Err(err) => eprint!("{}", self.interp.render_error(&err, self.color)),
+        }
+    }
+
+    // Evaluate the expressions which are complete
+    fn eval_complete(&mut self) {
+        while self.exit.is_none() {
+            let expr = match self.stream.next_expr() {
+                Ok(Next::Expr(expr)) => expr,
+                Ok(Next::NeedInput) | Ok(Next::End) => return,
+                Err(err) => {
+                    self.print_result(Err(Error::Parse(err)));
+                    continue;
+                }
+            };
+
+            let res = self.interp.eval_expr(&expr);
+            self.print_result(res);
+        }
+    }
+
+    fn feed(&mut self, line: &str) {
+        let mut code = line.as_bytes().to_vec();
+        code.push(b'\n');
+        self.interp.append_source(&self.filename, &code);
+        self.stream.feed(&code);
+        self.eval_complete();
+    }
+
+    // Returns false if the REPL should exit
+    fn command(&mut self, line: &str) -> bool {
+        let (cmd, arg) = match line.split_once(char::is_whitespace) {
+            Some((cmd, arg)) => (cmd, arg.trim()),
+            None => (line, ""),
+        };
+
+        match cmd {
+            ":help" => help(),
+            ":quit" => return false,
+            ":load" if arg.is_empty() => eprintln!("Usage: :load <path>"),
+            ":load" => {
+                let res = self.interp.eval_file(&BString::from_str(arg));
+                self.print_result(res);
+            }
+            ":ast" => {
+                let filename = BString::from_str("<ast>");
+                let mut reader = parse::Reader::new(arg.as_bytes(), filename);
+                let (exprs, errors) = parse::parse_all(&mut reader);
+                for expr in exprs {
+                    println!("{}", expr);
+                }
+                for err in errors {
+                    eprint!(
+                        "{}",
+                        diagnostic::render_parse_error(&err, Some(arg.as_bytes()), self.color)
+                    );
+                }
+            }
+            ":scope" => {
+                for (name, val) in self.interp.variables() {
+                    println!("{} = {}", name, val);
+                }
+            }
+            _ => eprintln!("Unknown command '{}', try :help", cmd),
+        }
+
+        true
+    }
+}
+
+// Returns the exit status if a script called 'exit'
+pub fn run(interp: &mut Interpreter, color: bool) -> Option<i32> {
+    let mut editor = Editor::new(Editor::default_history_path());
+    if editor.is_interactive() {
+        println!("Osyris {}. Type :help for help.", env!("CARGO_PKG_VERSION"));
+    }
+
+    let filename = BString::from_str("<repl>");
+    let mut stream = StreamReader::new(filename.clone());
+    stream.set_max_depth(interp.context().max_parse_depth());
+    stream.set_interactive(true);
+    let mut repl = Repl {
+        interp,
+        stream,
+        filename,
+        color,
+        exit: None,
+    };
+
+    loop {
+        let partial = repl.stream.has_partial();
+        let prompt = if partial { "... " } else { "> " };
+        let line = match editor.read_line(prompt) {
+            Ok(ReadLine::Line(line)) => line,
+            Ok(ReadLine::Interrupted) => {
+                repl.stream.discard();
+                continue;
+            }
+            Ok(ReadLine::Eof) => break,
+            Err(err) => {
+                eprintln!("Failed to read input: {}", err);
+                break;
+            }
+        };
+
+        editor.add_history(&line);
+        if !partial && line.trim_start().starts_with(':') {
+            if !repl.command(line.trim()) {
+                return None;
+            }
+        } else {
+            repl.feed(&line);
+        }
+
+        if repl.exit.is_some() {
+            return repl.exit;
+        }
+    }
+
+    // Input which ended in the middle of an expression is an error
+    repl.stream.close();
+    repl.eval_complete();
+    repl.exit
+}
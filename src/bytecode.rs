@@ -0,0 +1,210 @@
+use super::ast;
+use super::bignum::{BigInt, Rational};
+use super::bstring::BString;
+use super::symbol::Symbol;
+
+use std::rc::Rc;
+
+// Every expression is compiled to a sequence of ops which leaves exactly
+// one value on the stack. Indices refer to the tables in the Chunk.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    // The start of a call expression, which consumes fuel like any other expression
+    Step,
+    String(u32),
+    Number(f64),
+    Int(i64),
+    BigInt(u32),
+    Rational(u32),
+    Lookup(u32),
+    // A name, the number of call frames to skip and the slot; see 'Scope::lookup_local'
+    Local(u32, u32, u32),
+    // A keyword argument's name, which is only valid as an argument of a call;
+    // see 'eval::split_named'. 'BadKeyword' is a keyword anywhere else.
+    Keyword(u32),
+    BadKeyword(u32),
+    Block(u32),
+    // Pop the function, then the arguments, and call the function.
+    // The second operand is an index into the call sites.
+    Call(u32, u32),
+    // The empty call expression '()'
+    EmptyCall,
+    // Pop the value, then the key, and get the property; see 'eval::get_property'
+    Property,
+}
+
+// The location and name of a call expression. 'start' is the op index of its
+// Step op, 'end' is the op index of its Call op.
+pub struct Site {
+    pub start: usize,
+    pub end: usize,
+    pub location: ast::Location,
+    pub name: String,
+}
+
+pub struct Chunk {
+    pub ops: Vec<Op>,
+    // The location of the expression each op was compiled from
+    pub locations: Vec<ast::Location>,
+    // How deeply nested the expression each op was compiled from is,
+    // where top-level expressions are at level 1; see 'vm::run'
+    pub levels: Vec<u32>,
+    pub strings: Vec<Rc<BString>>,
+    pub bigints: Vec<Rc<BigInt>>,
+    pub rationals: Vec<Rc<Rational>>,
+    pub names: Vec<Symbol>,
+    pub blocks: Vec<Rc<Vec<ast::Expression>>>,
+    // Sites are in the order their Call ops appear,
+    // so nested calls come before the calls which contain them
+    pub sites: Vec<Site>,
+    // The op index where each top-level expression starts,
+    // followed by the length of 'ops'
+    pub starts: Vec<usize>,
+}
+
+impl Chunk {
+    pub fn len(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The sites of the call expressions which were being evaluated at 'pc',
+    // innermost first
+    pub fn sites_at(&self, pc: usize) -> impl Iterator<Item = &Site> {
+        self.sites.iter().filter(move |site| site.start < pc && pc <= site.end)
+    }
+}
+
+struct Compiler {
+    chunk: Chunk,
+    level: u32,
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, loc: &ast::Location) {
+        self.chunk.ops.push(op);
+        self.chunk.locations.push(loc.clone());
+        self.chunk.levels.push(self.level);
+    }
+
+    fn compile_expr(&mut self, expr: &ast::Expression) {
+        self.level += 1;
+        self.compile_expr_ops(expr);
+        self.level -= 1;
+    }
+
+    // Compile an argument of a call, which is the only place a keyword is valid
+    fn compile_arg(&mut self, arg: &ast::Expression) {
+        match arg {
+            ast::Expression::Keyword(name, loc) => {
+                let idx = self.chunk.names.len() as u32;
+                self.chunk.names.push(*name);
+                self.level += 1;
+                self.emit(Op::Keyword(idx), loc);
+                self.level -= 1;
+            }
+            _ => self.compile_expr(arg),
+        }
+    }
+
+    fn compile_expr_ops(&mut self, expr: &ast::Expression) {
+        match expr {
+            ast::Expression::String(s, loc) => {
+                let idx = self.chunk.strings.len() as u32;
+                self.chunk.strings.push(s.clone());
+                self.emit(Op::String(idx), loc);
+            }
+            ast::Expression::Number(num, loc) => self.emit(Op::Number(*num), loc),
+            ast::Expression::Int(num, loc) => self.emit(Op::Int(*num), loc),
+            ast::Expression::BigInt(num, loc) => {
+                let idx = self.chunk.bigints.len() as u32;
+                self.chunk.bigints.push(num.clone());
+                self.emit(Op::BigInt(idx), loc);
+            }
+            ast::Expression::Rational(num, loc) => {
+                let idx = self.chunk.rationals.len() as u32;
+                self.chunk.rationals.push(num.clone());
+                self.emit(Op::Rational(idx), loc);
+            }
+            ast::Expression::Lookup(name, loc) => {
+                let idx = self.chunk.names.len() as u32;
+                self.chunk.names.push(*name);
+                self.emit(Op::Lookup(idx), loc);
+            }
+            ast::Expression::Local(name, frames, slot, loc) => {
+                let idx = self.chunk.names.len() as u32;
+                self.chunk.names.push(*name);
+                self.emit(Op::Local(idx, *frames, *slot), loc);
+            }
+            ast::Expression::Keyword(name, loc) => {
+                let idx = self.chunk.names.len() as u32;
+                self.chunk.names.push(*name);
+                self.emit(Op::BadKeyword(idx), loc);
+            }
+            ast::Expression::Call(exprs, loc) => {
+                let start = self.chunk.ops.len();
+                self.emit(Op::Step, loc);
+
+                if exprs.is_empty() {
+                    self.emit(Op::EmptyCall, loc);
+                    return;
+                }
+
+                // Like the tree-walking evaluator, arguments are evaluated
+                // before the function
+                for arg in &exprs[1..] {
+                    self.compile_arg(arg);
+                }
+                self.compile_expr(&exprs[0]);
+
+                let site = self.chunk.sites.len() as u32;
+                self.chunk.sites.push(Site {
+                    start,
+                    end: self.chunk.ops.len(),
+                    location: loc.clone(),
+                    name: format!("{}", exprs[0]),
+                });
+                self.emit(Op::Call(exprs.len() as u32 - 1, site), loc);
+            }
+            ast::Expression::Block(exprs, loc) => {
+                let idx = self.chunk.blocks.len() as u32;
+                self.chunk.blocks.push(exprs.clone());
+                self.emit(Op::Block(idx), loc);
+            }
+            ast::Expression::Property(val, key, loc) => {
+                self.compile_expr(key);
+                self.compile_expr(val);
+                self.emit(Op::Property, loc);
+            }
+        }
+    }
+}
+
+pub fn compile(exprs: &[ast::Expression]) -> Chunk {
+    let mut comp = Compiler {
+        chunk: Chunk {
+            ops: Vec::new(),
+            locations: Vec::new(),
+            levels: Vec::new(),
+            strings: Vec::new(),
+            bigints: Vec::new(),
+            rationals: Vec::new(),
+            names: Vec::new(),
+            blocks: Vec::new(),
+            sites: Vec::new(),
+            starts: Vec::with_capacity(exprs.len() + 1),
+        },
+        level: 0,
+    };
+
+    for expr in exprs {
+        comp.chunk.starts.push(comp.chunk.ops.len());
+        comp.compile_expr(expr);
+    }
+    comp.chunk.starts.push(comp.chunk.ops.len());
+
+    comp.chunk
+}
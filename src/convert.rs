@@ -0,0 +1,411 @@
+use super::bignum::{BigInt, Rational};
+use super::bstring::BString;
+use super::eval::{self, Context, DictVal, FuncResult, FuncVal, Scope, StackTrace, ValRef};
+use super::number;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+pub trait FromValRef: Sized {
+    // Whether the argument can be left out, in which case the value is made from 'none'.
+    // Only parameters after the last one which isn't optional can be left out.
+    const OPTIONAL: bool = false;
+
+    fn from_valref(val: ValRef) -> Result<Self, String>;
+}
+
+pub trait IntoValRef: Sized {
+    fn into_valref(self) -> ValRef;
+
+    // Like 'into_valref', but the memory of the strings, lists and dicts which are
+    // created counts towards the context's memory limit, like the values which
+    // the stdlib creates. Values which already existed aren't counted again.
+    fn into_counted_valref(self, _ctx: &Context) -> Result<ValRef, StackTrace> {
+        Ok(self.into_valref())
+    }
+}
+
+fn counted_string(s: BString, ctx: &Context) -> Result<ValRef, StackTrace> {
+    let s = Rc::new(s);
+    ctx.alloc(&s, eval::string_size(s.as_bytes().len()))?;
+    Ok(ValRef::String(s))
+}
+
+fn expected(what: &str, val: &ValRef) -> String {
+    format!("Expected {}, got {}", what, val.type_name())
+}
+
+impl FromValRef for ValRef {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        Ok(val)
+    }
+}
+
+impl FromValRef for f64 {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::Number(num) => Ok(num),
+            ValRef::Int(..) | ValRef::BigInt(..) | ValRef::Rational(..) => Ok(val.to_num()),
+            _ => Err(expected("number", &val)),
+        }
+    }
+}
+
+impl FromValRef for i64 {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::Int(num) => Ok(num),
+            ValRef::Number(num) => match eval::int_from_float(num) {
+                Some(num) => Ok(num),
+                None => Err(format!("Expected int, got non-integral number {}", num)),
+            },
+            ValRef::BigInt(num) => Err(format!("Integer {} is out of range", num)),
+            _ => Err(expected("int", &val)),
+        }
+    }
+}
+
+impl FromValRef for BigInt {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::Int(num) => Ok(BigInt::from_i64(num)),
+            ValRef::BigInt(num) => Ok(num.as_ref().clone()),
+            _ => Err(expected("int", &val)),
+        }
+    }
+}
+
+impl FromValRef for Rational {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        if !number::is_exact(&val) {
+            return Err(expected("exact number", &val));
+        }
+
+        Ok(number::to_rational(&val).unwrap())
+    }
+}
+
+impl FromValRef for bool {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::Bool(b) => Ok(b),
+            _ => Err(expected("bool", &val)),
+        }
+    }
+}
+
+impl FromValRef for BString {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::String(s) => Ok(s.as_ref().clone()),
+            _ => Err(expected("string", &val)),
+        }
+    }
+}
+
+impl FromValRef for String {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::String(s) => match std::str::from_utf8(s.as_bytes()) {
+                Ok(s) => Ok(s.to_string()),
+                Err(err) => Err(format!("Invalid UTF-8: {}", err)),
+            },
+            _ => Err(expected("string", &val)),
+        }
+    }
+}
+
+impl<T: FromValRef> FromValRef for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        match val {
+            ValRef::None => Ok(None),
+            _ => Ok(Some(T::from_valref(val)?)),
+        }
+    }
+}
+
+impl<T: FromValRef> FromValRef for Vec<T> {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        let lst = match val {
+            ValRef::List(lst) => lst,
+            _ => return Err(expected("list", &val)),
+        };
+
+        let lst = lst.borrow();
+        let mut vec = Vec::with_capacity(lst.len());
+        for (idx, item) in lst.iter().enumerate() {
+            match T::from_valref(item.clone()) {
+                Ok(item) => vec.push(item),
+                Err(err) => return Err(format!("Element {}: {}", idx, err)),
+            }
+        }
+
+        Ok(vec)
+    }
+}
+
+impl<T: FromValRef> FromValRef for HashMap<BString, T> {
+    fn from_valref(val: ValRef) -> Result<Self, String> {
+        let dict = match val {
+            ValRef::Dict(dict) => dict,
+            _ => return Err(expected("dict", &val)),
+        };
+
+        let dict = dict.borrow();
+        let mut map = HashMap::with_capacity(dict.len());
+        for (key, item) in dict.iter() {
+            match T::from_valref(item.clone()) {
+                Ok(item) => map.insert(key.clone(), item),
+                Err(err) => return Err(format!("Key '{}': {}", key, err)),
+            };
+        }
+
+        Ok(map)
+    }
+}
+
+impl IntoValRef for ValRef {
+    fn into_valref(self) -> ValRef {
+        self
+    }
+}
+
+impl IntoValRef for () {
+    fn into_valref(self) -> ValRef {
+        ValRef::None
+    }
+}
+
+impl IntoValRef for f64 {
+    fn into_valref(self) -> ValRef {
+        ValRef::Number(self)
+    }
+}
+
+impl IntoValRef for i64 {
+    fn into_valref(self) -> ValRef {
+        ValRef::Int(self)
+    }
+}
+
+impl IntoValRef for BigInt {
+    fn into_valref(self) -> ValRef {
+        number::from_bigint(self)
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        let val = self.into_valref();
+        ctx.alloc_number(&val)?;
+        Ok(val)
+    }
+}
+
+impl IntoValRef for Rational {
+    fn into_valref(self) -> ValRef {
+        number::from_rational(self)
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        let val = self.into_valref();
+        ctx.alloc_number(&val)?;
+        Ok(val)
+    }
+}
+
+impl IntoValRef for bool {
+    fn into_valref(self) -> ValRef {
+        ValRef::Bool(self)
+    }
+}
+
+impl IntoValRef for BString {
+    fn into_valref(self) -> ValRef {
+        ValRef::String(Rc::new(self))
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        counted_string(self, ctx)
+    }
+}
+
+impl IntoValRef for String {
+    fn into_valref(self) -> ValRef {
+        ValRef::String(Rc::new(BString::from_string(self)))
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        counted_string(BString::from_string(self), ctx)
+    }
+}
+
+impl IntoValRef for &str {
+    fn into_valref(self) -> ValRef {
+        ValRef::String(Rc::new(BString::from_str(self)))
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        counted_string(BString::from_str(self), ctx)
+    }
+}
+
+impl<T: IntoValRef> IntoValRef for Option<T> {
+    fn into_valref(self) -> ValRef {
+        match self {
+            Some(val) => val.into_valref(),
+            None => ValRef::None,
+        }
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        match self {
+            Some(val) => val.into_counted_valref(ctx),
+            None => Ok(ValRef::None),
+        }
+    }
+}
+
+impl<T: IntoValRef> IntoValRef for Vec<T> {
+    fn into_valref(self) -> ValRef {
+        let vec = self.into_iter().map(|x| x.into_valref()).collect();
+        ValRef::List(Rc::new(RefCell::new(vec)))
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        let vec = self
+            .into_iter()
+            .map(|x| x.into_counted_valref(ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+        let size = eval::list_size(vec.len());
+        let lst = Rc::new(RefCell::new(vec));
+        ctx.alloc(&lst, size)?;
+        Ok(ValRef::List(lst))
+    }
+}
+
+impl<T: IntoValRef> IntoValRef for HashMap<BString, T> {
+    fn into_valref(self) -> ValRef {
+        let map = self
+            .into_iter()
+            .map(|(k, v)| (k, v.into_valref()))
+            .collect();
+        ValRef::Dict(Rc::new(RefCell::new(map)))
+    }
+
+    fn into_counted_valref(self, ctx: &Context) -> Result<ValRef, StackTrace> {
+        let mut map = DictVal::new();
+        for (k, v) in self {
+            map.insert(k, v.into_counted_valref(ctx)?);
+        }
+        let size = eval::dict_size(&map);
+        let dict = Rc::new(RefCell::new(map));
+        ctx.alloc(&dict, size)?;
+        Ok(ValRef::Dict(dict))
+    }
+}
+
+// The return type of a function registered with 'Scope::register_fn'.
+// Either a plain value, or a Result where the error becomes a StackTrace.
+pub trait IntoFuncResult {
+    fn into_func_result(self, scope: Scope) -> FuncResult;
+}
+
+impl<T: IntoValRef> IntoFuncResult for T {
+    fn into_func_result(self, scope: Scope) -> FuncResult {
+        let val = self.into_counted_valref(scope.context())?;
+        Ok((val, scope))
+    }
+}
+
+impl<T: IntoValRef, E: fmt::Display> IntoFuncResult for Result<T, E> {
+    fn into_func_result(self, scope: Scope) -> FuncResult {
+        match self {
+            Ok(val) => {
+                let val = val.into_counted_valref(scope.context())?;
+                Ok((val, scope))
+            }
+            Err(err) => Err(StackTrace::from_string(err.to_string()).into()),
+        }
+    }
+}
+
+// Rust functions and closures which can be turned into native osyris functions.
+// 'Args' is a tuple of the parameter types, and is only used to tell
+// the implementations for different numbers of parameters apart.
+pub trait IntoFunc<Args> {
+    fn into_func(self, name: &str) -> Rc<FuncVal>;
+}
+
+fn arity_error(name: &str, min: usize, max: usize, count: usize) -> StackTrace {
+    if min == max {
+        StackTrace::from_string(format!(
+            "'{}' expects {} argument{}, got {}",
+            name,
+            max,
+            if max == 1 { "" } else { "s" },
+            count
+        ))
+    } else {
+        StackTrace::from_string(format!(
+            "'{}' expects {} to {} arguments, got {}",
+            name, min, max, count
+        ))
+    }
+}
+
+macro_rules! impl_into_func {
+    ($count:expr; $($param:ident $arg:ident),*) => {
+        impl<F, R, $($param,)*> IntoFunc<($($param,)*)> for F
+        where
+            F: Fn($($param),*) -> R + 'static,
+            R: IntoFuncResult,
+            $($param: FromValRef,)*
+        {
+            #[allow(unused_variables, unused_mut)]
+            fn into_func(self, name: &str) -> Rc<FuncVal> {
+                let name = name.to_string();
+                let optional: [bool; $count] = [$($param::OPTIONAL),*];
+                let required = match optional.iter().rposition(|opt| !opt) {
+                    Some(idx) => idx + 1,
+                    None => 0,
+                };
+
+                Rc::new(move |args: Vec<ValRef>, scope: Scope| {
+                    eval::check_named(&args, &[])?;
+                    if args.len() < required || args.len() > $count {
+                        return Err(arity_error(&name, required, $count, args.len()).into());
+                    }
+
+                    let mut args = args.into_iter();
+                    let mut idx = 0;
+                    $(
+                        idx += 1;
+                        let val = args.next().unwrap_or(ValRef::None);
+                        let $arg = match $param::from_valref(val) {
+                            Ok(val) => val,
+                            Err(err) => {
+                                return Err(StackTrace::from_string(format!(
+                                    "'{}' parameter {}: {}",
+                                    name, idx, err
+                                )).into())
+                            }
+                        };
+                    )*
+
+                    self($($arg),*).into_func_result(scope)
+                })
+            }
+        }
+    };
+}
+
+impl_into_func!(0;);
+impl_into_func!(1; A a);
+impl_into_func!(2; A a, B b);
+impl_into_func!(3; A a, B b, C c);
+impl_into_func!(4; A a, B b, C c, D d);
+impl_into_func!(5; A a, B b, C c, D d, E e);
+impl_into_func!(6; A a, B b, C c, D d, E e, G g);
@@ -0,0 +1,152 @@
+use super::ast::Location;
+use super::eval::{StackTrace, StackTraceEntry};
+use super::parse::ParseError;
+
+use std::fmt::Write;
+
+// Rendering of errors together with the line of source code they came from,
+// with the failing part of the line underlined:
+//
+//   Error: Variable 'y' doesn't exist
+//    --> test.os:2:14
+//     |
+//   2 |     (print [x + y])
+//     |                 ^
+//
+// The source is optional; without it, only the location is printed.
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+struct Painter {
+    color: bool,
+}
+
+impl Painter {
+    fn paint(&self, out: &mut String, style: &str, text: &str) {
+        if self.color {
+            out.push_str(style);
+            out.push_str(text);
+            out.push_str(RESET);
+        } else {
+            out.push_str(text);
+        }
+    }
+}
+
+// Get a line of the source code, without the line ending. Lines start at 1.
+fn source_line(source: &[u8], line: u32) -> Option<&[u8]> {
+    let text = source
+        .split(|ch| *ch == b'\n')
+        .nth(line.checked_sub(1)? as usize)?;
+    Some(text.strip_suffix(b"\r").unwrap_or(text))
+}
+
+fn is_continuation(ch: u8) -> bool {
+    ch & 0xc0 == 0x80
+}
+
+// Write the line where the span starts, with the span underlined.
+// Spans which continue onto later lines are underlined to the end of the line.
+fn write_snippet(out: &mut String, source: Option<&[u8]>, loc: &Location, p: &Painter) {
+    let lineno = loc.line.to_string();
+    let pad = " ".repeat(lineno.len());
+    p.paint(out, BLUE, &format!("{}--> ", pad));
+    let _ = writeln!(out, "{}:{}:{}", loc.file, loc.line, loc.column);
+
+    let text = match source.and_then(|source| source_line(source, loc.line)) {
+        Some(text) => text,
+        None => return,
+    };
+
+    // Columns count bytes
+    let start = (loc.column as usize).saturating_sub(1).min(text.len());
+    let end = if loc.end_line == loc.line {
+        (loc.end_column as usize)
+            .saturating_sub(1)
+            .clamp(start, text.len())
+    } else {
+        text.len()
+    };
+
+    // Tabs are kept, so that the underline lines up however wide tabs are
+    let mut indent = String::new();
+    for ch in &text[..start] {
+        if *ch == b'\t' {
+            indent.push('\t');
+        } else if !is_continuation(*ch) {
+            indent.push(' ');
+        }
+    }
+
+    let width = text[start..end]
+        .iter()
+        .filter(|ch| !is_continuation(**ch))
+        .count();
+
+    p.paint(out, BLUE, &format!("{} |\n{} | ", pad, lineno));
+    out.push_str(&String::from_utf8_lossy(text));
+    out.push('\n');
+    p.paint(out, BLUE, &format!("{} | ", pad));
+    out.push_str(&indent);
+    p.paint(out, RED, &"^".repeat(width.max(1)));
+    out.push('\n');
+}
+
+fn write_heading(out: &mut String, heading: &str, message: &str, p: &Painter) {
+    p.paint(out, RED, heading);
+    p.paint(out, BOLD, &format!(": {}", message));
+    out.push('\n');
+}
+
+// Render a parse error. 'source' is the code which was being parsed.
+pub fn render_parse_error(err: &ParseError, source: Option<&[u8]>, color: bool) -> String {
+    let p = Painter { color };
+    let mut out = String::new();
+    write_heading(&mut out, "Parse error", &err.msg, &p);
+    write_snippet(&mut out, source, &err.location(), &p);
+
+    if let Some(hint) = &err.hint {
+        let pad = " ".repeat(err.line.to_string().len());
+        p.paint(&mut out, BLUE, &format!("{} = ", pad));
+        let _ = writeln!(out, "hint: {}", hint);
+    }
+
+    out
+}
+
+// Render a runtime error. 'source' is the code of the file which
+// the error's span is in.
+pub fn render_stack_trace(trace: &StackTrace, source: Option<&[u8]>, color: bool) -> String {
+    let message = trace.message_string();
+    render_trace(&message, trace.span.as_ref(), &trace.trace, source, color)
+}
+
+// Render a runtime error from its parts, like for an 'interpreter::Error'
+pub fn render_trace(
+    message: &str,
+    span: Option<&Location>,
+    trace: &[StackTraceEntry],
+    source: Option<&[u8]>,
+    color: bool,
+) -> String {
+    let p = Painter { color };
+    let mut out = String::new();
+    write_heading(&mut out, "Error", message, &p);
+
+    if let Some(span) = span {
+        write_snippet(&mut out, source, span, &p);
+    }
+
+    for entry in trace {
+        let _ = writeln!(
+            out,
+            "  {}: {}:{}: {}",
+            entry.location.file, entry.location.line, entry.location.column, entry.name
+        );
+    }
+
+    out
+}
@@ -14,7 +14,19 @@ where
             name = parent;
             writeln!(w, "{} [label=\"None\" shape=box]", name)?;
         }
-        ValRef::Number(num) => {
+        ValRef::Number(..) => {
+            name = parent;
+            writeln!(w, "{} [label=\"{}\" shape=box]", name, val)?;
+        }
+        ValRef::Int(num) => {
+            name = parent;
+            writeln!(w, "{} [label=\"{}\" shape=box]", name, num)?;
+        }
+        ValRef::BigInt(num) => {
+            name = parent;
+            writeln!(w, "{} [label=\"{}\" shape=box]", name, num)?;
+        }
+        ValRef::Rational(num) => {
             name = parent;
             writeln!(w, "{} [label=\"{}\" shape=box]", name, num)?;
         }
@@ -22,6 +34,10 @@ where
             name = parent;
             writeln!(w, "{} [label=\"{}\" shape=box]", name, b)?;
         }
+        ValRef::Keyword(..) => {
+            name = parent;
+            writeln!(w, "{} [label=\"{}\" shape=box]", name, val)?;
+        }
         ValRef::String(s) => {
             name = format!("v{:p}", s.as_ref());
             writeln!(w, "{} [label=\"string rc={}\"]", name, Rc::strong_count(s))?;
@@ -82,6 +98,12 @@ where
             name = format!("v{:p}", s.m.as_ref());
             writeln!(w, "{} [label=\"scope rc={}\"]", name, Rc::strong_count(&s.m))?;
         }
+        ValRef::TailCall(tc) => {
+            name = format!("v{:p}", tc.as_ref());
+            let fname = write_val(w, &tc.func, format!("{}f", name))?;
+            writeln!(w, "{} [label=\"tail call\"]", name)?;
+            writeln!(w, "{} -> {} [label=\"::func\"]", name, fname)?;
+        }
     }
 
     Ok(name)
@@ -131,7 +153,7 @@ where
 fn lib_print_scope_dot(_: Vec<ValRef>, scope: Scope) -> FuncResult {
     match write_dot(&mut io::stdout(), &scope) {
         Ok(()) => Ok((ValRef::None, scope)),
-        Err(err) => Err(StackTrace::from_string(err.to_string())),
+        Err(err) => Err(StackTrace::from_string(err.to_string()).into()),
     }
 }
 
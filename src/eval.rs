@@ -1,50 +1,228 @@
 use super::ast;
+use super::bignum::{BigInt, Rational};
 use super::bstring::BString;
+use super::bytecode::{self, Chunk};
+use super::convert::IntoFunc;
+use super::number;
+use super::parse;
+use super::resolve;
+use super::symbol::{Symbol, SymbolMap};
+use super::vm::{self, CodeCache};
 
 use std::any::Any;
-use std::cell::RefCell;
-use std::cmp::PartialEq;
+use std::cell::{Cell, RefCell};
+use std::cmp::{self, PartialEq};
 use std::collections::HashMap;
+use std::error;
 use std::fmt;
 use std::io;
-use std::rc::Rc;
+use std::mem;
+use std::ops::Range;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec;
 
+// Natives take their arguments with these methods. Keyword arguments, like
+// ':width 10', come after the positional arguments, so 'next_val' and 'has_next'
+// stop at the first keyword; use 'named' to get their values.
+// 'done' checks that every positional argument was taken, and that there are no
+// keyword arguments. Natives which take keyword arguments use 'check_named' instead.
 pub trait FuncArgs {
     fn next_val(&mut self) -> Result<ValRef, StackTrace>;
     fn has_next(&self) -> bool;
     fn done(&self) -> Result<(), StackTrace>;
+    fn named(&self, key: &str) -> Option<ValRef>;
+    fn check_named(&self, keys: &[&str]) -> Result<(), StackTrace>;
 }
 
 impl FuncArgs for vec::Drain<'_, ValRef> {
     fn next_val(&mut self) -> Result<ValRef, StackTrace> {
         match self.next() {
+            Some(ValRef::Keyword(..)) | None => Err(StackTrace::from_str("Not enough parameters")),
             Some(val) => Ok(val),
-            None => Err(StackTrace::from_str("Not enough parameters")),
         }
     }
 
     fn has_next(&self) -> bool {
-        self.len() > 0
+        !matches!(self.as_slice().first(), None | Some(ValRef::Keyword(..)))
     }
 
     fn done(&self) -> Result<(), StackTrace> {
         if self.has_next() {
             Err(StackTrace::from_str("Too many arguments"))
         } else {
-            Ok(())
+            self.check_named(&[])
         }
     }
+
+    fn named(&self, key: &str) -> Option<ValRef> {
+        let args = self.as_slice();
+        let start = args
+            .iter()
+            .position(|arg| matches!(arg, ValRef::Keyword(..)))?;
+        let key = Symbol::get(&BString::from_str(key))?;
+        args[start..]
+            .chunks_exact(2)
+            .find(|pair| matches!(pair[0], ValRef::Keyword(name) if name == key))
+            .map(|pair| pair[1].clone())
+    }
+
+    fn check_named(&self, keys: &[&str]) -> Result<(), StackTrace> {
+        check_named(self.as_slice(), keys)
+    }
+}
+
+// Check that the only keyword arguments are the ones in 'keys'. Natives which
+// take their arguments as a whole, rather than with 'FuncArgs', use this directly.
+pub fn check_named(args: &[ValRef], keys: &[&str]) -> Result<(), StackTrace> {
+    for arg in args {
+        if let ValRef::Keyword(name) = arg {
+            if !keys
+                .iter()
+                .any(|key| Symbol::get(&BString::from_str(key)) == Some(*name))
+            {
+                return Err(StackTrace::from_string(format!(
+                    "Unexpected keyword argument ':{}'",
+                    name
+                )));
+            }
+        }
+    }
+
+    Ok(())
 }
 
-pub type FuncResult = Result<(ValRef, Scope), StackTrace>;
+pub type FuncResult = Result<(ValRef, Scope), Unwind>;
 pub type DictVal = HashMap<BString, ValRef>;
 pub type FuncVal = dyn Fn(Vec<ValRef>, Scope) -> FuncResult;
+pub type NamedArgs = Vec<(Symbol, ValRef)>;
+
+// The parameters of a lambda. Parameters which were given as a '{name default...}'
+// block are optional, and when the argument is missing, the rest of the block
+// is evaluated in the lambda's scope to get the value. A '...name' parameter
+// gets the remaining arguments as a list.
+#[derive(Clone, Default)]
+pub struct Params {
+    pub names: Vec<Symbol>,
+    pub defaults: Vec<Option<Rc<Vec<ast::Expression>>>>,
+    pub rest: Option<Symbol>,
+}
+
+impl Params {
+    pub fn positional(names: Vec<Symbol>) -> Self {
+        let defaults = vec![None; names.len()];
+        Self {
+            names,
+            defaults,
+            rest: None,
+        }
+    }
+
+    // The names of the parameters, in the order of their frame slots
+    pub fn slot_names(&self) -> Vec<Symbol> {
+        let mut names = self.names.clone();
+        names.extend(self.rest);
+        names
+    }
+
+    // The number of arguments which must be passed
+    pub fn required(&self) -> usize {
+        self.defaults.iter().take_while(|d| d.is_none()).count()
+    }
+}
 
+// A lambda with 'strict' set raises an error when it's called with the wrong
+// number of arguments. Otherwise, missing arguments are left unbound, and extra
+// arguments are ignored. 'name' is the name given to 'func' or 'def', for errors.
+#[derive(Clone)]
 pub struct LambdaVal {
-    pub args: Vec<BString>,
+    pub params: Params,
     pub body: Rc<Vec<ast::Expression>>,
     pub scope: Scope,
+    pub name: Option<Symbol>,
+    pub strict: bool,
+    pub layout: Rc<FrameLayout>,
+}
+
+impl LambdaVal {
+    pub fn new(args: Vec<Symbol>, body: Rc<Vec<ast::Expression>>, scope: Scope) -> Self {
+        Self::with_params(Params::positional(args), body, scope)
+    }
+
+    pub fn with_params(params: Params, body: Rc<Vec<ast::Expression>>, scope: Scope) -> Self {
+        let layout = Rc::new(FrameLayout::new(resolve::frame_names(&params.slot_names())));
+        Self {
+            params,
+            body,
+            scope,
+            name: None,
+            strict: false,
+            layout,
+        }
+    }
+
+    // Check the number of arguments of a call to a strict lambda
+    fn check_arity(&self, count: usize) -> Result<(), StackTrace> {
+        let min = self.params.required();
+        let max = self.params.names.len();
+        if count >= min && (count <= max || self.params.rest.is_some()) {
+            return Ok(());
+        }
+
+        let (expected, last) = if self.params.rest.is_some() {
+            (format!("at least {}", min), min)
+        } else if min == max {
+            (format!("{}", min), min)
+        } else {
+            (format!("{} to {}", min, max), max)
+        };
+
+        let plural = if last == 1 { "" } else { "s" };
+        Err(StackTrace::from_string(format!(
+            "{} expects {} argument{}, but got {}",
+            self.describe(),
+            expected,
+            plural,
+            count
+        )))
+    }
+
+    // Check that a call to a strict lambda has an argument for every required parameter,
+    // since keyword arguments can leave gaps
+    fn check_required(&self, vals: &[Option<ValRef>]) -> Result<(), StackTrace> {
+        let required = &vals[..self.params.required()];
+        match required.iter().position(Option::is_none) {
+            Some(idx) => Err(StackTrace::from_string(format!(
+                "{} is missing an argument for '{}'",
+                self.describe(),
+                self.params.names[idx]
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // The lambda's name for errors
+    fn describe(&self) -> String {
+        match self.name {
+            Some(name) => format!("Lambda '{}'", name),
+            None => "Lambda".to_string(),
+        }
+    }
+}
+
+// A call which has been deferred so that it can be run by the trampoline in 'call'
+// instead of recursing. 'site' is the location and name of the call expression,
+// or None if the tail call was produced by a native function.
+#[derive(Clone)]
+pub struct TailCallVal {
+    pub func: ValRef,
+    pub args: Vec<ValRef>,
+    pub scope: Scope,
+    pub site: Option<(ast::Location, String)>,
 }
 
 pub trait PortVal {
@@ -65,9 +243,76 @@ pub trait PortVal {
     }
 }
 
+// Host types which are exposed to scripts as ValRef::Native.
+// Methods take '&self', because a call into a native object may call back
+// into scripts which use the same object; use interior mutability for state.
+//
+// The dot syntax ('obj.name') looks up methods first, then properties with 'get'.
+// Calls to the object itself, like '(obj "name")', always go to 'call'.
+pub trait NativeObject: Any {
+    fn type_name(&self) -> &str {
+        "native"
+    }
+
+    fn fmt_display(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(native {})", self.type_name())
+    }
+
+    fn equals(&self, _: &dyn NativeObject) -> bool {
+        false
+    }
+
+    fn get(&self, _: &BString) -> Option<ValRef> {
+        None
+    }
+
+    fn set(&self, key: &BString, _: ValRef) -> Result<(), String> {
+        Err(format!("Can't set '{}' on {}", key, self.type_name()))
+    }
+
+    fn has_method(&self, _: &BString) -> bool {
+        false
+    }
+
+    fn call_method(&self, name: &BString, _: Vec<ValRef>, _: Scope) -> FuncResult {
+        Err(
+            StackTrace::from_string(format!("{} has no method '{}'", self.type_name(), name))
+                .into(),
+        )
+    }
+
+    fn call(&self, _: Vec<ValRef>, _: Scope) -> FuncResult {
+        Err(
+            StackTrace::from_string(format!("Attempt to call non-function {}", self.type_name()))
+                .into(),
+        )
+    }
+}
+
+impl dyn NativeObject {
+    pub fn downcast_ref<T: NativeObject>(&self) -> Option<&T> {
+        let any: &dyn Any = self;
+        any.downcast_ref()
+    }
+
+    // Get the value of an 'Opaque' object
+    pub fn opaque_ref<T: 'static>(&self) -> Option<&T> {
+        self.downcast_ref::<Opaque<T>>().map(|obj| &obj.0)
+    }
+}
+
+// A native object which holds any value, but doesn't expose anything to scripts,
+// for host values which scripts only pass around.
+pub struct Opaque<T: 'static>(pub T);
+
+impl<T: 'static> NativeObject for Opaque<T> {}
+
 pub enum ValRef {
     None,
     Number(f64),
+    Int(i64),
+    BigInt(Rc<BigInt>),
+    Rational(Rc<Rational>),
     Bool(bool),
     String(Rc<BString>),
     List(Rc<RefCell<Vec<ValRef>>>),
@@ -77,9 +322,13 @@ pub enum ValRef {
     Block(Rc<Vec<ast::Expression>>),
     Lazy(Rc<ValRef>),
     ProtectedLazy(Rc<ValRef>),
-    Native(Rc<dyn Any>),
+    Native(Rc<dyn NativeObject>),
     Port(Rc<RefCell<dyn PortVal>>),
     Scope(Scope),
+    // The name of a keyword argument; see 'split_named'. Keywords only appear
+    // in the arguments of a call, so they can't be stored.
+    Keyword(Symbol),
+    TailCall(Box<TailCallVal>),
 }
 
 impl ValRef {
@@ -95,13 +344,10 @@ impl ValRef {
     pub fn to_num(&self) -> f64 {
         match self {
             ValRef::Number(num) => *num,
-            ValRef::Bool(b) => {
-                if *b {
-                    1.0
-                } else {
-                    0.0
-                }
-            }
+            ValRef::Int(num) => *num as f64,
+            ValRef::BigInt(num) => num.to_f64(),
+            ValRef::Rational(num) => num.to_f64(),
+            ValRef::Bool(true) => 1.0,
             _ => 0.0,
         }
     }
@@ -117,12 +363,17 @@ impl ValRef {
     }
 
     pub fn equals(a: &Self, b: &Self) -> bool {
-        #[allow(clippy::vtable_address_comparisons)]
+        #[allow(ambiguous_wide_pointer_comparisons)]
         match (a, b) {
             (ValRef::None, ValRef::None) => true,
             (ValRef::Number(a), ValRef::Number(b)) => a == b,
+            (ValRef::Int(a), ValRef::Int(b)) => a == b,
+            (a, b) if a.is_number() && b.is_number() => {
+                ValRef::compare_numbers(a, b) == Some(cmp::Ordering::Equal)
+            }
             (ValRef::Bool(a), ValRef::Bool(b)) => a == b,
             (ValRef::String(a), ValRef::String(b)) => a == b,
+            (ValRef::Keyword(a), ValRef::Keyword(b)) => a == b,
             (ValRef::List(a), ValRef::List(b)) => {
                 let (a, b) = (a.borrow(), b.borrow());
                 if a.len() != b.len() {
@@ -162,19 +413,72 @@ impl ValRef {
             (ValRef::Block(a), ValRef::Block(b)) => Rc::ptr_eq(a, b),
             (ValRef::Lazy(a), ValRef::Lazy(b)) => Rc::ptr_eq(a, b),
             (ValRef::ProtectedLazy(a), ValRef::ProtectedLazy(b)) => Rc::ptr_eq(a, b),
-            (ValRef::Native(a), ValRef::Native(b)) => Rc::ptr_eq(a, b),
+            (ValRef::Native(a), ValRef::Native(b)) => Rc::ptr_eq(a, b) || a.equals(b.as_ref()),
             (ValRef::Port(a), ValRef::Port(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
 
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ValRef::None => "none",
+            ValRef::Number(..) => "number",
+            ValRef::Int(..) => "int",
+            ValRef::BigInt(..) => "int",
+            ValRef::Rational(..) => "rational",
+            ValRef::Bool(..) => "bool",
+            ValRef::String(..) => "string",
+            ValRef::List(..) => "list",
+            ValRef::Dict(..) => "dict",
+            ValRef::Func(..) => "func",
+            ValRef::Lambda(..) => "lambda",
+            ValRef::Block(..) => "block",
+            ValRef::Lazy(..) => "lazy",
+            ValRef::ProtectedLazy(..) => "protected-lazy",
+            ValRef::Native(..) => "native",
+            ValRef::Port(..) => "port",
+            ValRef::Scope(..) => "scope",
+            ValRef::Keyword(..) => "keyword",
+            ValRef::TailCall(..) => "tail-call",
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            ValRef::Number(..) | ValRef::Int(..) | ValRef::BigInt(..) | ValRef::Rational(..)
+        )
+    }
+
+    // Compare two values as numbers. Ints, big ints and rationals are
+    // compared exactly, even with floats which can't represent them.
+    pub fn compare_numbers(a: &Self, b: &Self) -> Option<cmp::Ordering> {
+        number::compare(a, b)
+    }
+
     pub fn get_number(self) -> Result<f64, StackTrace> {
         match self {
             ValRef::Number(n) => Ok(n),
+            ValRef::Int(n) => Ok(n as f64),
+            ValRef::BigInt(n) => Ok(n.to_f64()),
+            ValRef::Rational(n) => Ok(n.to_f64()),
             _ => Err(StackTrace::from_str("Expected number")),
         }
     }
 
+    // Get an integer. Floats are accepted if they represent an integer exactly.
+    pub fn get_int(self) -> Result<i64, StackTrace> {
+        match self {
+            ValRef::Int(n) => Ok(n),
+            ValRef::Number(n) => match int_from_float(n) {
+                Some(n) => Ok(n),
+                None => Err(StackTrace::from_str("Expected integer")),
+            },
+            ValRef::BigInt(..) => Err(StackTrace::from_str("Integer is out of range")),
+            _ => Err(StackTrace::from_str("Expected integer")),
+        }
+    }
+
     pub fn get_string(self) -> Result<Rc<BString>, StackTrace> {
         match self {
             ValRef::String(s) => Ok(s),
@@ -222,6 +526,9 @@ impl Clone for ValRef {
         match self {
             Self::None => Self::None,
             Self::Number(num) => Self::Number(*num),
+            Self::Int(num) => Self::Int(*num),
+            Self::BigInt(num) => Self::BigInt(num.clone()),
+            Self::Rational(num) => Self::Rational(num.clone()),
             Self::Bool(b) => Self::Bool(*b),
             Self::String(s) => Self::String(s.clone()),
             Self::List(l) => Self::List(l.clone()),
@@ -234,6 +541,8 @@ impl Clone for ValRef {
             Self::Native(n) => Self::Native(n.clone()),
             Self::Port(p) => Self::Port(p.clone()),
             Self::Scope(s) => Self::Scope(s.clone()),
+            Self::Keyword(name) => Self::Keyword(*name),
+            Self::TailCall(tc) => Self::TailCall(tc.clone()),
         }
     }
 }
@@ -242,7 +551,10 @@ impl fmt::Display for ValRef {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::None => write!(f, "None"),
-            Self::Number(num) => write!(f, "{}", num),
+            Self::Number(num) => number::fmt_float(f, *num),
+            Self::Int(num) => write!(f, "{}", num),
+            Self::BigInt(num) => write!(f, "{}", num),
+            Self::Rational(num) => write!(f, "{}", num),
             Self::Bool(b) => write!(f, "{}", b),
             Self::String(s) => write!(f, "{:?}", s),
             Self::Dict(m) => {
@@ -271,12 +583,14 @@ impl fmt::Display for ValRef {
             }
             Self::Func(func) => write!(f, "(func {:p})", func.as_ref()),
             Self::Block(b) => write!(f, "{:?}", b),
-            Self::Lambda(l) => write!(f, "(lambda {:?} {:?})", l.args, l.body),
+            Self::Lambda(l) => write!(f, "(lambda {:?} {:?})", l.params.names, l.body),
             Self::Lazy(val) => write!(f, "(lazy {})", val),
             Self::ProtectedLazy(val) => write!(f, "(protected-lazy {})", val),
-            Self::Native(n) => write!(f, "(native {:p})", n.as_ref()),
+            Self::Native(n) => n.fmt_display(f),
             Self::Port(p) => write!(f, "(port {:p})", p.as_ref()),
             Self::Scope(s) => write!(f, "(scope {:p})", s.m.as_ref()),
+            Self::Keyword(name) => write!(f, ":{}", name),
+            Self::TailCall(tc) => write!(f, "(tail-call {})", tc.func),
         }
     }
 }
@@ -287,14 +601,53 @@ impl fmt::Debug for ValRef {
     }
 }
 
+#[derive(Debug)]
 pub struct StackTraceEntry {
     pub location: ast::Location,
     pub name: String,
 }
 
+// Reasons for aborting evaluation entirely.
+// Errors with an abort reason can't be caught by scripts.
+// 'Exit' is a script asking to exit with a status, using 'exit'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abort {
+    OutOfFuel,
+    Interrupted,
+    DeadlineExceeded,
+    MemoryLimitExceeded,
+    Exit(i32),
+}
+
+// Non-local exits, which unwind like errors until the loop or lambda which
+// handles them. Break and Continue carry the scope the loop goes on with,
+// since the loop body may have changed variables before the signal.
+pub enum Signal {
+    Break(ValRef, Scope),
+    Continue(ValRef, Scope),
+    Return(ValRef),
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Break(val, _) => write!(f, "Break({})", val),
+            Self::Continue(val, _) => write!(f, "Continue({})", val),
+            Self::Return(val) => write!(f, "Return({})", val),
+        }
+    }
+}
+
+// 'raised' is true for errors raised by scripts with 'error',
+// in which case 'message' is the error's value. 'span' is the location of
+// the innermost expression whose evaluation failed.
+#[derive(Debug)]
 pub struct StackTrace {
     pub message: ValRef,
+    pub span: Option<ast::Location>,
     pub trace: Vec<StackTraceEntry>,
+    pub abort: Option<Abort>,
+    pub raised: bool,
 }
 
 impl StackTrace {
@@ -302,297 +655,1385 @@ impl StackTrace {
     pub fn from_str(message: &str) -> Self {
         Self {
             message: ValRef::String(Rc::new(BString::from_str(message))),
+            span: None,
             trace: Vec::new(),
+            abort: None,
+            raised: false,
         }
     }
 
     pub fn from_string(message: String) -> Self {
         Self {
             message: ValRef::String(Rc::new(BString::from_string(message))),
+            span: None,
             trace: Vec::new(),
+            abort: None,
+            raised: false,
         }
     }
 
     pub fn from_val(message: ValRef) -> Self {
         Self {
             message,
+            span: None,
             trace: Vec::new(),
+            abort: None,
+            raised: false,
         }
     }
 
-    fn push(mut self, location: ast::Location, name: String) -> Self {
-        self.trace.push(StackTraceEntry { location, name });
-        self
+    pub fn raised(value: ValRef) -> Self {
+        Self {
+            message: value,
+            span: None,
+            trace: Vec::new(),
+            abort: None,
+            raised: true,
+        }
     }
-}
 
-impl fmt::Display for StackTrace {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.message {
-            ValRef::String(bs) => write!(f, "{}", String::from_utf8_lossy(bs.as_bytes())),
-            _ => write!(f, "{}", self.message),
-        }?;
+    pub fn from_abort(abort: Abort) -> Self {
+        let message = match abort {
+            Abort::OutOfFuel => "Out of fuel".to_string(),
+            Abort::Interrupted => "Interrupted".to_string(),
+            Abort::DeadlineExceeded => "Deadline exceeded".to_string(),
+            Abort::MemoryLimitExceeded => "Memory limit exceeded".to_string(),
+            Abort::Exit(status) => format!("Exited with status {}", status),
+        };
 
-        for entry in &self.trace {
-            write!(
-                f,
-                "\n  {}: {}:{}: {}",
-                entry.location.file, entry.location.line, entry.location.column, entry.name
-            )?;
+        Self {
+            message: ValRef::String(Rc::new(BString::from_string(message))),
+            span: None,
+            trace: Vec::new(),
+            abort: Some(abort),
+            raised: false,
         }
-
-        Ok(())
     }
-}
 
-pub struct ScopeImpl {
-    pub parent: Option<Rc<ScopeImpl>>,
-    pub map: Option<Rc<RefCell<HashMap<BString, ValRef>>>>,
-}
+    pub(crate) fn push(mut self, location: ast::Location, name: String) -> Self {
+        self.trace.push(StackTraceEntry { location, name });
+        self
+    }
 
-impl ScopeImpl {
-    pub fn lookup(&self, name: &BString) -> Option<ValRef> {
-        if let Some(map) = &self.map {
-            if let Some(val) = map.borrow().get(name) {
-                return Some(val.clone());
-            }
+    // Record the location of the failing expression, unless a more deeply
+    // nested expression has already been recorded
+    pub(crate) fn at(mut self, location: &ast::Location) -> Self {
+        if self.span.is_none() {
+            self.span = Some(location.clone());
         }
+        self
+    }
 
-        if let Some(parent) = &self.parent {
-            return parent.lookup(name);
+    // The message as text. Strings are used as they are, other values are formatted.
+    pub fn message_string(&self) -> String {
+        match &self.message {
+            ValRef::String(bs) => String::from_utf8_lossy(bs.as_bytes()).into_owned(),
+            message => format!("{}", message),
         }
-
-        None
     }
 }
 
-#[derive(Clone)]
-pub struct Scope {
-    pub m: Rc<ScopeImpl>,
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_trace(f, &self.message_string(), self.span.as_ref(), &self.trace)
+    }
 }
 
-impl Scope {
-    pub fn new() -> Self {
-        Self {
-            m: Rc::new(ScopeImpl {
-                parent: None,
-                map: None,
-            }),
-        }
-    }
+pub(crate) fn write_trace(
+    f: &mut fmt::Formatter,
+    message: &str,
+    span: Option<&ast::Location>,
+    trace: &[StackTraceEntry],
+) -> fmt::Result {
+    write!(f, "{}", message)?;
 
-    pub fn subscope(&self) -> Self {
-        Self {
-            m: Rc::new(ScopeImpl {
-                parent: Some(self.m.clone()),
-                map: None,
-            })
-        }
+    if let Some(span) = span {
+        write!(f, "\n  at {}", span)?;
     }
 
-    pub fn lookup(&self, name: &BString) -> Option<ValRef> {
-        self.m.lookup(name)
+    for entry in trace {
+        write!(
+            f,
+            "\n  {}: {}:{}: {}",
+            entry.location.file, entry.location.line, entry.location.column, entry.name
+        )?;
     }
 
-    pub fn lookup_shallow(&self, name: &BString) -> Option<ValRef> {
-        if let Some(map) = &self.m.map {
-            return map.borrow().get(name).map(|x| x.clone());
-        }
+    Ok(())
+}
 
-        None
+impl error::Error for StackTrace {}
+
+// How evaluation stops early. Errors unwind until they're caught by 'try',
+// or until they reach the embedder. Signals unwind until the loop or lambda
+// which handles them, and carry the error they become if nothing does,
+// like "'break' used outside of a loop".
+#[derive(Debug)]
+pub enum Unwind {
+    Error(StackTrace),
+    Signal(Box<Signal>, StackTrace),
+}
+
+impl Unwind {
+    pub fn signal(signal: Signal) -> Self {
+        let message = match signal {
+            Signal::Break(..) => "'break' used outside of a loop",
+            Signal::Continue(..) => "'continue' used outside of a loop",
+            Signal::Return(..) => "'return' used outside of a lambda",
+        };
+
+        Unwind::Signal(Box::new(signal), StackTrace::from_str(message))
     }
 
-    // Erase a key from the scope if doing so wouldn't require creating a new scope.
-    // If erasing would require creating a new scope, it's left 
-    pub fn maybe_inplace_erase(&mut self, name: &BString) {
-        if let Some(map) = &self.m.map {
-            if Rc::strong_count(map) == 1 && Rc::strong_count(&self.m) == 1 {
-                map.borrow_mut().remove(name);
-            }
+    // The error, or the error for a signal which wasn't handled
+    pub fn into_error(self) -> StackTrace {
+        match self {
+            Unwind::Error(trace) | Unwind::Signal(_, trace) => trace,
         }
     }
 
-    pub fn insert(self, name: BString, val: ValRef) -> Self {
-        if let Some(map) = &self.m.map {
-            if Rc::strong_count(map) == 1 && Rc::strong_count(&self.m) == 1 {
-                map.borrow_mut().insert(name, val);
-                self
-            } else {
-                let mut map = map.borrow().clone();
-                map.insert(name, val);
-                Self {
-                    m: Rc::new(ScopeImpl {
-                        parent: self.m.parent.clone(),
-                        map: Some(Rc::new(RefCell::new(map))),
-                    })
-                }
-            }
-        } else {
-            let mut map = HashMap::new();
-            map.insert(name, val);
-            Self {
-                m: Rc::new(ScopeImpl {
-                    parent: self.m.parent.clone(),
-                    map: Some(Rc::new(RefCell::new(map))),
-                }),
+    pub fn is_catchable(&self) -> bool {
+        matches!(self, Unwind::Error(trace) if trace.abort.is_none())
+    }
+
+    // Natives which run code in a scope of their own, like a subscope, use this
+    // to make a loop which handles the signal go on in the native's scope instead
+    pub fn resume_in(mut self, scope: &Scope) -> Self {
+        if let Unwind::Signal(signal, _) = &mut self {
+            if let Signal::Break(_, s) | Signal::Continue(_, s) = signal.as_mut() {
+                *s = scope.clone();
             }
         }
+        self
     }
 
-    pub fn has_shallow(&self, name: &BString) -> bool {
-        if let Some(map) = &self.m.map {
-            map.borrow().contains_key(name)
-        } else {
-            false
+    fn map_trace(self, f: impl FnOnce(StackTrace) -> StackTrace) -> Self {
+        match self {
+            Unwind::Error(trace) => Unwind::Error(f(trace)),
+            Unwind::Signal(signal, trace) => Unwind::Signal(signal, f(trace)),
         }
     }
 
-    pub fn put(self, name: &str, val: ValRef) -> Self {
-        self.insert(BString::from_str(name), val)
+    pub(crate) fn push(self, location: ast::Location, name: String) -> Self {
+        self.map_trace(|trace| trace.push(location, name))
     }
 
-    pub fn put_lazy(self, name: &str, func: Rc<FuncVal>) -> Self {
-        self.insert(BString::from_str(name), ValRef::Lazy(Rc::new(ValRef::Func(func))))
+    pub(crate) fn at(self, location: &ast::Location) -> Self {
+        self.map_trace(|trace| trace.at(location))
     }
+}
 
-    pub fn put_func(self, name: &str, func: Rc<FuncVal>) -> Self {
-        self.insert(BString::from_str(name), ValRef::Func(func))
+impl From<StackTrace> for Unwind {
+    fn from(trace: StackTrace) -> Self {
+        Unwind::Error(trace)
     }
 }
 
-impl Default for Scope {
-    fn default() -> Self {
-        Self::new()
+// The default maximum evaluation depth. The deepest recursion through
+// natives like 'try' takes about 4MiB of stack at this depth in debug builds,
+// and a lot less in release builds. Embedders which evaluate code on threads
+// with smaller stacks should lower it.
+pub const DEFAULT_MAX_DEPTH: usize = 300;
+
+// Convert a float to an integer, if it represents one exactly
+pub fn int_from_float(num: f64) -> Option<i64> {
+    // 2^63 is exactly representable, while i64::MAX isn't
+    const LIMIT: f64 = 9223372036854775808.0;
+    if num.fract() == 0.0 && (-LIMIT..LIMIT).contains(&num) {
+        Some(num as i64)
+    } else {
+        None
     }
 }
 
-pub fn call(func: &ValRef, mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    match func {
-        ValRef::Func(func) => func(args, scope),
-        ValRef::Block(b) => {
-            let (retval, _) = eval_multiple(&b[..], scope.subscope())?;
-            Ok((retval, scope))
-        },
-        ValRef::Lambda(l) => {
-            let mut args = args.drain(0..);
+// Approximate sizes of values, used for memory accounting.
+pub fn string_size(len: usize) -> usize {
+    mem::size_of::<BString>() + len
+}
 
-            let mut subscope = l.scope.subscope();
-            for name in &l.args {
-                let val = match args.next() {
-                    Some(val) => val,
-                    None => break,
-                };
+pub fn bigint_size(num: &BigInt) -> usize {
+    mem::size_of::<BigInt>() + num.limbs() * mem::size_of::<u32>()
+}
 
-                subscope = subscope.insert(name.clone(), val);
-            }
+// The size of a number, which is only counted for big ints and rationals
+pub fn number_size(val: &ValRef) -> usize {
+    match val {
+        ValRef::BigInt(num) => bigint_size(num),
+        ValRef::Rational(num) => bigint_size(num.numer()) + bigint_size(num.denom()),
+        _ => 0,
+    }
+}
 
-            subscope = subscope.insert(BString::from_str("self"), func.clone());
-            subscope = subscope.insert(
-                BString::from_str("caller-scope"), ValRef::Scope(scope.clone()));
+pub fn list_size(len: usize) -> usize {
+    mem::size_of::<Vec<ValRef>>() + len * mem::size_of::<ValRef>()
+}
 
-            let (retval, _) = eval_multiple(&l.body[..], subscope)?;
-            Ok((retval, scope))
-        }
-        ValRef::List(list) => {
-            if args.len() != 1 {
-                return Err(StackTrace::from_str("Array lookup requires 1 argument"));
-            }
+pub fn frame_size(slots: usize) -> usize {
+    mem::size_of::<ScopeImpl>() + mem::size_of::<Frame>() + slots * mem::size_of::<Option<ValRef>>()
+}
 
-            let idx = match args[0] {
-                ValRef::Number(idx) => idx,
-                _ => {
-                    return Err(StackTrace::from_str(
-                        "Attempt to index array with non-number",
-                    ))
-                }
-            };
+pub fn dict_entry_size(key: &BString) -> usize {
+    string_size(key.as_bytes().len()) + mem::size_of::<ValRef>()
+}
 
-            if idx as usize >= list.borrow().len() || idx < 0.0 {
-                Ok((ValRef::None, scope))
-            } else {
-                Ok((list.borrow()[idx as usize].clone(), scope))
-            }
-        }
-        ValRef::Dict(map) => {
-            if args.len() != 1 {
-                return Err(StackTrace::from_str(
-                    "Dict lookup requires exactly 1 argument",
-                ));
-            }
+pub fn dict_size(dict: &DictVal) -> usize {
+    mem::size_of::<DictVal>() + dict.keys().map(dict_entry_size).sum::<usize>()
+}
 
-            let key = match &args[0] {
-                ValRef::String(key) => key,
-                _ => return Err(StackTrace::from_str("Attempt to index map with non-string")),
-            };
+// How many steps to take between each time the deadline is checked,
+// since reading the clock is comparatively expensive
+const DEADLINE_INTERVAL: u64 = 256;
 
-            match map.borrow().get(key.as_ref()) {
-                Some(val) => Ok((val.clone(), scope)),
-                None => Ok((ValRef::None, scope)),
-            }
-        }
-        ValRef::Scope(s) => {
-            if args.len() == 0 {
-                return Err(StackTrace::from_str(
-                    "Scoped execution requires arguments",
-                ));
-            }
+// A handle which can be used to interrupt evaluation from elsewhere,
+// such as from a signal handler or from another thread.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
 
-            let a = args.drain(1..).collect();
-            let f = &args[0];
-            let (res, _) = call(f, a, s.clone())?;
-            Ok((res, scope))
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self {
+            flag: Arc::new(AtomicBool::new(false)),
         }
-        _ => Err(StackTrace::from_string(format!(
-            "Attempt to call non-function {}",
-            func
-        ))),
     }
-}
 
-pub fn eval_call(exprs: &[ast::Expression], mut scope: Scope) -> FuncResult {
-    if exprs.is_empty() {
-        return Err(StackTrace::from_str("Call list has no elements"));
+    // Make evaluation abort at the next safe point.
+    // The interrupt is consumed once evaluation has been aborted.
+    // The 'Interpreter' methods which evaluate code discard an interrupt
+    // which is still pending when they start.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
     }
 
-    let mut args: Vec<ValRef> = Vec::new();
-    args.reserve(exprs.len() - 1);
-    for item in exprs.iter().skip(1) {
-        let arg;
-        (arg, scope) = eval(item, scope)?;
-        args.push(arg);
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
     }
 
-    let (func, scope) = eval(&exprs[0], scope)?;
-    call(&func, args, scope)
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) && self.flag.swap(false, Ordering::Relaxed)
+    }
 }
 
-fn resolve_lazy(lazy: &ValRef, scope: Scope) -> FuncResult {
-    match lazy {
-        ValRef::Func(func) => func(Vec::new(), scope),
-        ValRef::Lambda(l) => eval_multiple(&l.body[..], l.scope.subscope()),
-        ValRef::Block(b) => eval_multiple(&b[..], scope.subscope()),
-        _ => Ok((lazy.clone(), scope)),
+impl Default for InterruptHandle {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub fn eval(expr: &ast::Expression, scope: Scope) -> FuncResult {
-    let (mut val, mut scope) = match expr {
-        ast::Expression::String(s) => Ok((ValRef::String(Rc::new(s.clone())), scope)),
-        ast::Expression::Number(num) => Ok((ValRef::Number(*num), scope)),
-        ast::Expression::Lookup(name) => match scope.lookup(name) {
-            Some(val) => Ok((val, scope)),
-            None => Err(StackTrace::from_string(format!(
-                "Variable '{}' doesn't exist",
-                name
-            ))),
+// How expressions are evaluated. 'Tree' walks the syntax tree directly,
+// 'Vm' compiles blocks to bytecode and runs that. Both behave the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Tree,
+    Vm,
+}
+
+const MIN_PRUNE_AT: usize = 64;
+
+// The memory which has been counted for values which are still alive.
+// Each allocation is kept with a weak reference to the value which owns it,
+// and the allocations of values which have been freed are pruned when the limit
+// would be exceeded, or when there are twice as many as after the last pruning.
+struct Allocations {
+    owners: Vec<(Weak<dyn Any>, usize)>,
+    // Memory which is never freed, like the names of interned symbols
+    permanent: usize,
+    used: usize,
+    prune_at: usize,
+}
+
+impl Allocations {
+    fn new() -> Self {
+        Self {
+            owners: Vec::new(),
+            permanent: 0,
+            used: 0,
+            prune_at: MIN_PRUNE_AT,
+        }
+    }
+
+    fn prune(&mut self) {
+        self.owners.retain(|(owner, _)| owner.strong_count() > 0);
+        self.used = self.permanent + self.owners.iter().map(|(_, bytes)| bytes).sum::<usize>();
+        self.prune_at = (self.owners.len() * 2).max(MIN_PRUNE_AT);
+    }
+}
+
+// State which is shared by every scope which descends from the same root scope.
+pub struct Context {
+    depth: Cell<usize>,
+    max_depth: Cell<usize>,
+    max_parse_depth: Cell<usize>,
+    fuel: Cell<Option<u64>>,
+    fuel_used: Cell<u64>,
+    interrupt: RefCell<InterruptHandle>,
+    deadline: Cell<Option<Instant>>,
+    max_memory: Cell<Option<usize>>,
+    allocations: RefCell<Allocations>,
+    backend: Cell<Backend>,
+    code_cache: RefCell<CodeCache>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self {
+            depth: Cell::new(0),
+            max_depth: Cell::new(DEFAULT_MAX_DEPTH),
+            max_parse_depth: Cell::new(parse::DEFAULT_MAX_DEPTH),
+            fuel: Cell::new(None),
+            fuel_used: Cell::new(0),
+            interrupt: RefCell::new(InterruptHandle::new()),
+            deadline: Cell::new(None),
+            max_memory: Cell::new(None),
+            allocations: RefCell::new(Allocations::new()),
+            backend: Cell::new(Backend::Tree),
+            code_cache: RefCell::new(CodeCache::new()),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.get()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.get()
+    }
+
+    pub fn set_max_depth(&self, max_depth: usize) {
+        self.max_depth.set(max_depth);
+    }
+
+    // The nesting limit for code which is parsed during evaluation, such as imports
+    pub fn max_parse_depth(&self) -> usize {
+        self.max_parse_depth.get()
+    }
+
+    pub fn set_max_parse_depth(&self, max_parse_depth: usize) {
+        self.max_parse_depth.set(max_parse_depth);
+    }
+
+    // Every evaluated expression and every call to a native function consumes
+    // one unit of fuel. Setting the fuel to None (the default) makes it unlimited.
+    // Setting the fuel resets the count of used fuel.
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel);
+        self.fuel_used.set(0);
+    }
+
+    // The remaining fuel, or None if it's unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used.get()
+    }
+
+    pub fn consume_fuel(&self) -> Result<(), StackTrace> {
+        if let Some(fuel) = self.fuel.get() {
+            if fuel == 0 {
+                return Err(StackTrace::from_abort(Abort::OutOfFuel));
+            }
+
+            self.fuel.set(Some(fuel - 1));
+        }
+
+        self.fuel_used.set(self.fuel_used.get() + 1);
+        Ok(())
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.borrow().clone()
+    }
+
+    // Replace the interrupt handle, so that several contexts can share one handle
+    pub fn set_interrupt_handle(&self, handle: InterruptHandle) {
+        *self.interrupt.borrow_mut() = handle;
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get()
+    }
+
+    // Abort evaluation once the deadline has passed. None (the default) means no deadline.
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.deadline.set(Some(Instant::now() + timeout));
+    }
+
+    // Memory is counted whenever a string, list, dict or big number is created
+    // or grown during evaluation, and given back once the value is freed,
+    // so the limit bounds the memory used by the values which are alive.
+    // Memory is only counted while there's a limit. Setting the limit to None
+    // (the default) makes it unlimited. Setting the limit resets the count.
+    pub fn set_max_memory(&self, max_memory: Option<usize>) {
+        self.max_memory.set(max_memory);
+        *self.allocations.borrow_mut() = Allocations::new();
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory.get()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        let mut allocations = self.allocations.borrow_mut();
+        allocations.prune();
+        allocations.used
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend.get()
+    }
+
+    pub fn set_backend(&self, backend: Backend) {
+        self.backend.set(backend);
+    }
+
+    // The bytecode for a block, compiled the first time it's needed
+    pub(crate) fn compiled(&self, block: &Rc<Vec<ast::Expression>>) -> Rc<Chunk> {
+        self.code_cache.borrow_mut().get(block)
+    }
+
+    // Count memory which stays in use for as long as 'owner' is alive,
+    // like the contents of a string, or the elements which were added to a list.
+    pub fn alloc<T: 'static>(&self, owner: &Rc<T>, bytes: usize) -> Result<(), StackTrace> {
+        let max = match self.max_memory.get() {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+
+        let mut allocations = self.allocations.borrow_mut();
+        if allocations.owners.len() >= allocations.prune_at
+            || allocations.used.saturating_add(bytes) > max
+        {
+            allocations.prune();
+        }
+
+        if allocations.used.saturating_add(bytes) > max {
+            return Err(StackTrace::from_abort(Abort::MemoryLimitExceeded));
+        }
+
+        allocations.used += bytes;
+
+        // Values which grow one element at a time would otherwise get an entry for each
+        if let Some((last, last_bytes)) = allocations.owners.last_mut() {
+            if ptr::addr_eq(last.as_ptr(), Rc::as_ptr(owner)) {
+                *last_bytes += bytes;
+                return Ok(());
+            }
+        }
+
+        let owner: Weak<T> = Rc::downgrade(owner);
+        allocations.owners.push((owner, bytes));
+        Ok(())
+    }
+
+    // Count the memory of a big int or a rational, see 'number_size'
+    pub fn alloc_number(&self, num: &ValRef) -> Result<(), StackTrace> {
+        match num {
+            ValRef::BigInt(n) => self.alloc(n, number_size(num)),
+            ValRef::Rational(n) => self.alloc(n, number_size(num)),
+            _ => Ok(()),
+        }
+    }
+
+    // Get the symbol for a name which a script gave at runtime, like the name
+    // given to 'def'. Interned names are never freed, so a name which hasn't been
+    // interned before counts towards the memory limit from then on.
+    pub fn intern(&self, name: &BString) -> Result<Symbol, StackTrace> {
+        if let Some(sym) = Symbol::get(name) {
+            return Ok(sym);
+        }
+
+        if let Some(max) = self.max_memory.get() {
+            let bytes = string_size(name.as_bytes().len());
+            let mut allocations = self.allocations.borrow_mut();
+            if allocations.used.saturating_add(bytes) > max {
+                allocations.prune();
+                if allocations.used.saturating_add(bytes) > max {
+                    return Err(StackTrace::from_abort(Abort::MemoryLimitExceeded));
+                }
+            }
+
+            allocations.used += bytes;
+            allocations.permanent += bytes;
+        }
+
+        Ok(Symbol::intern(name))
+    }
+
+    // Consume fuel and check whether evaluation has been interrupted
+    // or has passed its deadline. This is done for every evaluated expression
+    // and every call to a native function, but long-running native functions
+    // may want to call it too.
+    pub fn step(&self) -> Result<(), StackTrace> {
+        self.consume_fuel()?;
+
+        if self.interrupt.borrow().take() {
+            return Err(StackTrace::from_abort(Abort::Interrupted));
+        }
+
+        if let Some(deadline) = self.deadline.get() {
+            if self.fuel_used.get().is_multiple_of(DEADLINE_INTERVAL) && Instant::now() >= deadline {
+                return Err(StackTrace::from_abort(Abort::DeadlineExceeded));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enter(&self) -> Result<(), StackTrace> {
+        self.enter_at(self.depth.get() + 1)
+    }
+
+    // Like 'enter', for an expression which is at 'depth'.
+    // The VM doesn't recurse for nested expressions, so it sets the depth
+    // of each expression directly, to count the depth like the tree-walking evaluator.
+    pub(crate) fn enter_at(&self, depth: usize) -> Result<(), StackTrace> {
+        self.step()?;
+        if depth > self.max_depth.get() {
+            return Err(StackTrace::from_str("Maximum recursion depth exceeded"));
+        }
+
+        self.depth.set(depth);
+        Ok(())
+    }
+
+    pub(crate) fn set_depth(&self, depth: usize) {
+        self.depth.set(depth);
+    }
+
+    pub(crate) fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The names of the variables in a lambda's call frame: its parameters,
+// followed by 'self' and 'caller-scope'.
+pub struct FrameLayout {
+    pub names: Vec<Symbol>,
+    // Whether no name occurs more than once, so each variable has exactly one slot
+    pub unique: bool,
+}
+
+impl FrameLayout {
+    pub fn new(names: Vec<Symbol>) -> Self {
+        let unique = names
+            .iter()
+            .enumerate()
+            .all(|(idx, name)| !names[..idx].contains(name));
+        Self { names, unique }
+    }
+
+    // The slot a name is stored in. With duplicate names, later ones win.
+    fn slot(&self, name: Symbol) -> Option<usize> {
+        self.names.iter().rposition(|n| *n == name)
+    }
+}
+
+// The variables of a lambda's call frame, stored in slots rather than in a map.
+// A slot which is None is unbound, so lookups continue to the parent scope.
+pub struct Frame {
+    pub layout: Rc<FrameLayout>,
+    pub vals: RefCell<Vec<Option<ValRef>>>,
+}
+
+impl Frame {
+    fn lookup(&self, name: Symbol) -> Option<ValRef> {
+        let vals = self.vals.borrow();
+        for (idx, n) in self.layout.names.iter().enumerate().rev() {
+            if *n == name {
+                if let Some(val) = &vals[idx] {
+                    return Some(val.clone());
+                }
+            }
+        }
+
+        None
+    }
+}
+
+pub struct ScopeImpl {
+    pub parent: Option<Rc<ScopeImpl>>,
+    pub map: Option<Rc<RefCell<SymbolMap<ValRef>>>>,
+    pub frame: Option<Rc<Frame>>,
+    pub ctx: Rc<Context>,
+}
+
+// The parts of a scope which can refer to other scopes, see 'ScopeImpl::drop'
+type ScopeParts = (
+    Option<Rc<ScopeImpl>>,
+    Option<Rc<RefCell<SymbolMap<ValRef>>>>,
+    Option<Rc<Frame>>,
+);
+
+thread_local! {
+    static DROP_DEPTH: Cell<usize> = const { Cell::new(0) };
+    static DROP_QUEUE: RefCell<Vec<ScopeParts>> = const { RefCell::new(Vec::new()) };
+}
+
+const MAX_DROP_DEPTH: usize = 64;
+
+// Scopes can refer to each other arbitrarily deeply, like the frames of a long
+// chain of tail calls, which each refer to the previous one as 'caller-scope',
+// so dropping them recursively could overflow the stack. Past a certain depth,
+// the parts of a scope are queued instead, and the outermost drop frees them.
+impl Drop for ScopeImpl {
+    fn drop(&mut self) {
+        let unique = |count: Option<usize>| count == Some(1);
+        if !unique(self.parent.as_ref().map(Rc::strong_count))
+            && !unique(self.map.as_ref().map(Rc::strong_count))
+            && !unique(self.frame.as_ref().map(Rc::strong_count))
+        {
+            return;
+        }
+
+        let parts = (self.parent.take(), self.map.take(), self.frame.take());
+        // If the thread is exiting, the parts are just dropped
+        let depth = match DROP_DEPTH.try_with(Cell::get) {
+            Ok(depth) => depth,
+            Err(_) => return,
+        };
+
+        if depth >= MAX_DROP_DEPTH {
+            let _ = DROP_QUEUE.try_with(|queue| queue.borrow_mut().push(parts));
+            return;
+        }
+
+        DROP_DEPTH.with(|d| d.set(depth + 1));
+        drop(parts);
+        if depth == 0 {
+            while let Some(parts) = DROP_QUEUE.with(|queue| queue.borrow_mut().pop()) {
+                drop(parts);
+            }
+        }
+        DROP_DEPTH.with(|d| d.set(depth));
+    }
+}
+
+impl ScopeImpl {
+    pub fn lookup(&self, name: Symbol) -> Option<ValRef> {
+        if let Some(map) = &self.map {
+            if let Some(val) = map.borrow().get(&name) {
+                return Some(val.clone());
+            }
+        }
+
+        if let Some(frame) = &self.frame {
+            if let Some(val) = frame.lookup(name) {
+                return Some(val);
+            }
+        }
+
+        if let Some(parent) = &self.parent {
+            return parent.lookup(name);
+        }
+
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct Scope {
+    pub m: Rc<ScopeImpl>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::with_context(Rc::new(Context::new()))
+    }
+
+    pub fn with_context(ctx: Rc<Context>) -> Self {
+        Self {
+            m: Rc::new(ScopeImpl {
+                parent: None,
+                map: None,
+                frame: None,
+                ctx,
+            }),
+        }
+    }
+
+    pub fn subscope(&self) -> Self {
+        Self {
+            m: Rc::new(ScopeImpl {
+                parent: Some(self.m.clone()),
+                map: None,
+                frame: None,
+                ctx: self.m.ctx.clone(),
+            })
+        }
+    }
+
+    // A subscope for a lambda's call frame
+    pub fn frame_subscope(&self, layout: Rc<FrameLayout>, vals: Vec<Option<ValRef>>) -> Self {
+        Self {
+            m: Rc::new(ScopeImpl {
+                parent: Some(self.m.clone()),
+                map: None,
+                frame: Some(Rc::new(Frame {
+                    layout,
+                    vals: RefCell::new(vals),
+                })),
+                ctx: self.m.ctx.clone(),
+            }),
+        }
+    }
+
+    pub fn context(&self) -> &Rc<Context> {
+        &self.m.ctx
+    }
+
+    pub fn lookup(&self, name: Symbol) -> Option<ValRef> {
+        self.m.lookup(name)
+    }
+
+    // Look up a variable which was resolved to a slot in a lambda's call frame,
+    // 'frames' call frames up. The slot is only used if no scope on the way
+    // could have a variable with the same name; otherwise, this is the same as 'lookup'.
+    pub fn lookup_local(&self, name: Symbol, frames: u32, slot: u32) -> Option<ValRef> {
+        let mut m = &self.m;
+        let mut frames = frames;
+        loop {
+            if m.map.is_some() {
+                break;
+            }
+
+            if let Some(frame) = &m.frame {
+                if frames == 0 {
+                    let slot = slot as usize;
+                    if frame.layout.unique && frame.layout.names.get(slot) == Some(&name) {
+                        if let Some(val) = &frame.vals.borrow()[slot] {
+                            return Some(val.clone());
+                        }
+                    }
+
+                    break;
+                }
+
+                if frame.layout.names.contains(&name) {
+                    break;
+                }
+
+                frames -= 1;
+            }
+
+            m = match &m.parent {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+
+        self.lookup(name)
+    }
+
+    pub fn lookup_shallow(&self, name: Symbol) -> Option<ValRef> {
+        if let Some(map) = &self.m.map {
+            if let Some(val) = map.borrow().get(&name) {
+                return Some(val.clone());
+            }
+        }
+
+        if let Some(frame) = &self.m.frame {
+            return frame.lookup(name);
+        }
+
+        None
+    }
+
+    // Erase a key from the scope if doing so wouldn't require creating a new scope.
+    // If erasing would require creating a new scope, it's left 
+    pub fn maybe_inplace_erase(&mut self, name: Symbol) {
+        if let Some(map) = &self.m.map {
+            if Rc::strong_count(map) == 1 && Rc::strong_count(&self.m) == 1 {
+                map.borrow_mut().remove(&name);
+            }
+        }
+
+        if let Some(frame) = &self.m.frame {
+            if Rc::strong_count(frame) == 1 && Rc::strong_count(&self.m) == 1 {
+                let mut vals = frame.vals.borrow_mut();
+                for (idx, n) in frame.layout.names.iter().enumerate() {
+                    if *n == name {
+                        vals[idx] = None;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn insert(self, name: Symbol, val: ValRef) -> Self {
+        // Variables in a call frame's layout are always stored in their slot
+        if let Some(frame) = &self.m.frame {
+            if let Some(slot) = frame.layout.slot(name) {
+                if Rc::strong_count(frame) == 1 && Rc::strong_count(&self.m) == 1 {
+                    frame.vals.borrow_mut()[slot] = Some(val);
+                    return self;
+                }
+
+                let mut vals = frame.vals.borrow().clone();
+                vals[slot] = Some(val);
+                return Self {
+                    m: Rc::new(ScopeImpl {
+                        parent: self.m.parent.clone(),
+                        map: self.m.map.clone(),
+                        frame: Some(Rc::new(Frame {
+                            layout: frame.layout.clone(),
+                            vals: RefCell::new(vals),
+                        })),
+                        ctx: self.m.ctx.clone(),
+                    }),
+                };
+            }
+        }
+
+        if let Some(map) = &self.m.map {
+            if Rc::strong_count(map) == 1 && Rc::strong_count(&self.m) == 1 {
+                map.borrow_mut().insert(name, val);
+                self
+            } else {
+                let mut map = map.borrow().clone();
+                map.insert(name, val);
+                Self {
+                    m: Rc::new(ScopeImpl {
+                        parent: self.m.parent.clone(),
+                        map: Some(Rc::new(RefCell::new(map))),
+                        frame: self.m.frame.clone(),
+                        ctx: self.m.ctx.clone(),
+                    })
+                }
+            }
+        } else {
+            let mut map = SymbolMap::default();
+            map.insert(name, val);
+            Self {
+                m: Rc::new(ScopeImpl {
+                    parent: self.m.parent.clone(),
+                    map: Some(Rc::new(RefCell::new(map))),
+                    frame: self.m.frame.clone(),
+                    ctx: self.m.ctx.clone(),
+                }),
+            }
+        }
+    }
+
+    pub fn has_shallow(&self, name: Symbol) -> bool {
+        self.lookup_shallow(name).is_some()
+    }
+
+    pub fn put(self, name: &str, val: ValRef) -> Self {
+        self.insert(Symbol::from_str(name), val)
+    }
+
+    pub fn put_lazy(self, name: &str, func: Rc<FuncVal>) -> Self {
+        self.insert(Symbol::from_str(name), ValRef::Lazy(Rc::new(ValRef::Func(func))))
+    }
+
+    pub fn put_func(self, name: &str, func: Rc<FuncVal>) -> Self {
+        self.insert(Symbol::from_str(name), ValRef::Func(func))
+    }
+
+    // Define a native function from an ordinary Rust function or closure,
+    // such as '|a: f64, b: String| -> Result<f64, String>'.
+    // Arguments are converted with 'FromValRef', and the return value with 'IntoValRef'.
+    // Trailing 'Option' parameters can be left out.
+    pub fn register_fn<Args, F: IntoFunc<Args>>(self, name: &str, func: F) -> Self {
+        let func = func.into_func(name);
+        self.put_func(name, func)
+    }
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A keyword argument is a ValRef::Keyword followed by the argument's value.
+// Split a call's arguments into the positional arguments and the keyword arguments.
+pub fn split_named(args: Vec<ValRef>) -> Result<(Vec<ValRef>, NamedArgs), StackTrace> {
+    if !args.iter().any(|arg| matches!(arg, ValRef::Keyword(..))) {
+        return Ok((args, Vec::new()));
+    }
+
+    let mut positional = Vec::with_capacity(args.len());
+    let mut named = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg {
+            ValRef::Keyword(name) => match args.next() {
+                Some(val) => named.push((name, val)),
+                None => return Err(missing_value_error(name)),
+            },
+            arg => positional.push(arg),
+        }
+    }
+
+    Ok((positional, named))
+}
+
+fn missing_value_error(name: Symbol) -> StackTrace {
+    StackTrace::from_string(format!("Keyword argument ':{}' has no value", name))
+}
+
+pub(crate) fn keyword_error(name: Symbol) -> StackTrace {
+    StackTrace::from_string(format!(
+        "Keyword ':{}' can only be used as an argument",
+        name
+    ))
+}
+
+// Call a native function or object. Natives get their keyword arguments after
+// the positional ones, where the 'FuncArgs' methods expect them.
+// This isn't part of 'call_step' to keep its stack frame small.
+fn call_native(
+    func: impl FnOnce(Vec<ValRef>, Scope) -> FuncResult,
+    args: Vec<ValRef>,
+    scope: Scope,
+) -> FuncResult {
+    let (mut args, named) = split_named(args)?;
+    for (name, val) in named {
+        args.push(ValRef::Keyword(name));
+        args.push(val);
+    }
+
+    // Natives like 'try' and 'list-map' call back into the evaluator,
+    // and take a lot more stack than a single expression, so they count
+    // towards the depth too
+    let ctx = scope.context().clone();
+    ctx.enter()?;
+    let res = func(args, scope);
+    ctx.leave();
+    res
+}
+
+// Create the scope for a call to a lambda, with its parameters bound to the arguments.
+// The VM passes the arguments straight from its stack, so they're taken as an iterator.
+// This isn't part of 'call_step' to keep its stack frame small, since it recurses.
+pub(crate) fn lambda_frame(
+    l: &LambdaVal,
+    func: &ValRef,
+    mut args: impl Iterator<Item = ValRef>,
+    scope: &Scope,
+    tail: bool,
+) -> Result<Scope, Unwind> {
+    // Parameters without an argument are left unbound
+    let mut vals = Vec::with_capacity(l.layout.names.len());
+    let mut rest = Vec::new();
+    let mut named = Vec::new();
+    let mut positional = 0;
+    while let Some(arg) = args.next() {
+        match arg {
+            ValRef::Keyword(name) => match args.next() {
+                Some(val) => named.push((name, val)),
+                None => return Err(missing_value_error(name).into()),
+            },
+            arg => {
+                if positional < l.params.names.len() {
+                    vals.push(Some(arg));
+                } else if l.params.rest.is_some() {
+                    rest.push(arg);
+                }
+                positional += 1;
+            }
+        }
+    }
+    vals.resize(l.params.names.len(), None);
+    let count = positional + named.len();
+
+    for (name, val) in named {
+        let idx = match l.params.names.iter().rposition(|n| *n == name) {
+            Some(idx) => idx,
+            None => {
+                return Err(StackTrace::from_string(format!(
+                    "{} has no parameter '{}'",
+                    l.describe(),
+                    name
+                ))
+                .into())
+            }
+        };
+
+        if vals[idx].is_some() {
+            return Err(StackTrace::from_string(format!(
+                "Parameter '{}' was given more than one argument",
+                name
+            ))
+            .into());
+        }
+
+        vals[idx] = Some(val);
+    }
+
+    if l.strict {
+        l.check_arity(count)?;
+        l.check_required(&vals)?;
+    }
+
+    // Defaults are evaluated in order, so they can refer to earlier parameters
+    let defaults: Vec<usize> = (0..l.params.names.len())
+        .filter(|idx| vals[*idx].is_none() && l.params.defaults[*idx].is_some())
+        .collect();
+
+    if l.params.rest.is_some() {
+        let size = list_size(rest.len());
+        let rest = Rc::new(RefCell::new(rest));
+        scope.context().alloc(&rest, size)?;
+        vals.push(Some(ValRef::List(rest)));
+    }
+
+    vals.push(Some(func.clone()));
+    vals.push(Some(ValRef::Scope(scope.clone())));
+
+    // A tail call's frame keeps the frame of its caller alive, as its 'caller-scope',
+    // so a chain of tail calls takes memory for each call
+    let size = frame_size(vals.len());
+    let mut subscope = l.scope.frame_subscope(l.layout.clone(), vals);
+    if tail {
+        scope.context().alloc(&subscope.m, size)?;
+    }
+    for idx in defaults {
+        if let Some(block) = &l.params.defaults[idx] {
+            let val;
+            (val, subscope) = eval_block_range(block, 1..block.len(), false, subscope)?;
+            subscope = subscope.insert(l.params.names[idx], val);
+        }
+    }
+
+    Ok(subscope)
+}
+
+// Run a single call. If the function body ends in a call, that call isn't run,
+// but returned as a ValRef::TailCall for the trampoline in 'call' to run.
+pub(crate) fn call_step(
+    func: &ValRef,
+    mut args: Vec<ValRef>,
+    scope: Scope,
+    tail: bool,
+) -> FuncResult {
+    match func {
+        ValRef::Func(func) => call_native(|args, scope| func(args, scope), args, scope),
+        ValRef::Block(b) => match eval_block_tail(b, scope.subscope()) {
+            Ok((retval, _)) => Ok((retval, scope)),
+            Err(err) => Err(err.resume_in(&scope)),
         },
-        ast::Expression::Call(exprs, loc) => match eval_call(exprs, scope) {
-            Ok(res) => Ok(res),
-            Err(trace) => Err(trace.push(loc.clone(), format!("{}", exprs[0]))),
+        ValRef::Lambda(l) => {
+            let subscope = lambda_frame(l, func, args.into_iter(), &scope, tail)?;
+            match eval_block_tail(&l.body, subscope) {
+                Ok((retval, _)) => Ok((retval, scope)),
+                Err(err) => Err(err.resume_in(&scope)),
+            }
+        }
+        ValRef::List(list) => {
+            if args.len() != 1 {
+                return Err(StackTrace::from_str("Array lookup requires 1 argument").into());
+            }
+
+            Ok((index_list(list, &args[0])?, scope))
+        }
+        ValRef::Dict(map) => {
+            if args.len() != 1 {
+                return Err(StackTrace::from_str("Dict lookup requires exactly 1 argument").into());
+            }
+
+            Ok((index_dict(map, &args[0])?, scope))
+        }
+        ValRef::Native(obj) => call_native(|args, scope| obj.call(args, scope), args, scope),
+        ValRef::Scope(s) => {
+            if args.is_empty() {
+                return Err(StackTrace::from_str("Scoped execution requires arguments").into());
+            }
+
+            let a = args.drain(1..).collect();
+            let f = &args[0];
+            let ctx = scope.context().clone();
+            ctx.enter()?;
+            let res = call(f, a, s.clone());
+            ctx.leave();
+            match res {
+                Ok((res, _)) => Ok((res, scope)),
+                Err(err) => Err(err.resume_in(&scope)),
+            }
+        }
+        _ => Err(StackTrace::from_string(format!("Attempt to call non-function {}", func)).into()),
+    }
+}
+
+fn index_list(list: &RefCell<Vec<ValRef>>, idx: &ValRef) -> Result<ValRef, StackTrace> {
+    let idx = match idx {
+        ValRef::Int(idx) => Some(*idx),
+        ValRef::Number(idx) => int_from_float(*idx),
+        ValRef::BigInt(..) | ValRef::Rational(..) => None,
+        _ => {
+            return Err(StackTrace::from_str(
+                "Attempt to index array with non-number",
+            ))
+        }
+    };
+
+    // There's no element at a negative or fractional index
+    let idx = idx.and_then(|idx| usize::try_from(idx).ok());
+    match idx.and_then(|idx| list.borrow().get(idx).cloned()) {
+        Some(val) => Ok(val),
+        None => Ok(ValRef::None),
+    }
+}
+
+fn index_dict(map: &RefCell<DictVal>, key: &ValRef) -> Result<ValRef, StackTrace> {
+    let key = match key {
+        ValRef::String(key) => key,
+        _ => return Err(StackTrace::from_str("Attempt to index map with non-string")),
+    };
+
+    match map.borrow().get(key.as_ref()) {
+        Some(val) => Ok(val.clone()),
+        None => Ok(ValRef::None),
+    }
+}
+
+// Get a property with the '.' syntax. Lists and dicts are indexed like when
+// they're called, and native objects look for a method, then a property.
+// A method is returned as a function which calls the method on the object.
+pub fn get_property(val: &ValRef, key: &ValRef) -> Result<ValRef, StackTrace> {
+    match val {
+        ValRef::List(list) => index_list(list, key),
+        ValRef::Dict(map) => index_dict(map, key),
+        ValRef::Native(obj) => {
+            let key = match key {
+                ValRef::String(key) => key,
+                _ => {
+                    return Err(StackTrace::from_string(format!(
+                        "Attempt to get property {} of {}",
+                        key,
+                        obj.type_name()
+                    )))
+                }
+            };
+
+            if obj.has_method(key) {
+                let obj = obj.clone();
+                let name = key.as_ref().clone();
+                let method = move |args: Vec<ValRef>, scope: Scope| {
+                    obj.call_method(&name, args, scope)
+                };
+                return Ok(ValRef::Func(Rc::new(method)));
+            }
+
+            match obj.get(key) {
+                Some(val) => Ok(val),
+                None => Err(StackTrace::from_string(format!(
+                    "{} has no property '{}'",
+                    obj.type_name(),
+                    key
+                ))),
+            }
+        }
+        _ => Err(StackTrace::from_string(format!(
+            "Attempt to get property {} of {}",
+            key,
+            val.type_name()
+        ))),
+    }
+}
+
+// Whose body a 'break', 'continue' or 'return' is lexically in, going by the
+// last lambda in a chain of tail calls
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Owner {
+    Caller,
+    Lambda,
+    LoopBody,
+}
+
+pub fn call(func: &ValRef, args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    call_chain(func, args, scope, false)
+}
+
+// Call the body of a loop, like the function given to 'list-for'. Unlike with
+// 'call', a 'break' or 'continue' in the body of a lambda which is called this
+// way unwinds to the loop, rather than being an error.
+pub fn call_loop_body(func: &ValRef, args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    call_chain(func, args, scope, true)
+}
+
+// Handle a signal which unwound out of a step of a call. A 'return' returns from
+// the innermost lambda, and a 'break' or 'continue' can't leave a lambda's body
+// unless it's the body of a loop.
+pub(crate) fn unwind_step(err: Unwind, owner: Owner, scope: &Scope) -> Result<ValRef, Unwind> {
+    match err {
+        Unwind::Signal(signal, trace) => match *signal {
+            Signal::Return(val) if owner != Owner::Caller => Ok(val),
+            Signal::Break(..) | Signal::Continue(..) if owner == Owner::Lambda => {
+                Err(Unwind::Error(trace))
+            }
+            signal => Err(Unwind::Signal(Box::new(signal), trace).resume_in(scope)),
+        },
+        err => Err(err),
+    }
+}
+
+fn call_chain(func: &ValRef, args: Vec<ValRef>, scope: Scope, loop_body: bool) -> FuncResult {
+    let mut owner = match func {
+        ValRef::Lambda(..) if loop_body => Owner::LoopBody,
+        ValRef::Lambda(..) => Owner::Lambda,
+        _ => Owner::Caller,
+    };
+    let (mut val, scope) = match owner {
+        Owner::Caller => call_step(func, args, scope, false)?,
+        _ => {
+            let caller = scope.clone();
+            match call_step(func, args, scope, false) {
+                Ok(res) => res,
+                Err(err) => return Ok((unwind_step(err, owner, &caller)?, caller)),
+            }
+        }
+    };
+
+    // Each tail call which came from a call expression would have had its
+    // return value resolved by 'eval' if it wasn't deferred, so count them
+    // and do the resolving once the chain of tail calls is done.
+    // Keeping the scope of every call site would make a chain of tail calls
+    // take memory for each call, so all of the resolving happens in the scope
+    // of the last call site. This only matters for a lazy value which resolves
+    // to another lazy value, since a lazy block is evaluated in the scope
+    // it's resolved in.
+    let mut resolves = 0;
+    let mut last_scope = None;
+    while let ValRef::TailCall(tc) = val {
+        let TailCallVal {
+            func,
+            args,
+            scope: tc_scope,
+            site,
+        } = *tc;
+
+        // A lambda in tail position is lexically in the body of the lambda
+        // which called it, so signals from it can't reach the loop either
+        if matches!(func, ValRef::Lambda(..)) {
+            owner = Owner::Lambda;
+        }
+        (val, _) = match call_step(&func, args, tc_scope.clone(), true) {
+            Ok(res) => res,
+            // Tail calls may run in the subscope of a block which was
+            // called earlier in the chain, so loops go on in the caller's scope
+            Err(err) => match (unwind_step(err, owner, &scope), site) {
+                (Ok(val), _) => return Ok((val, scope)),
+                (Err(err), Some((loc, name))) => return Err(err.at(&loc).push(loc, name)),
+                (Err(err), None) => return Err(err),
+            },
+        };
+
+        if site.is_some() {
+            resolves += 1;
+        }
+        last_scope = Some(tc_scope);
+    }
+
+    if let Some(mut s) = last_scope {
+        for _ in 0..resolves {
+            if !matches!(val, ValRef::Lazy(..) | ValRef::ProtectedLazy(..)) {
+                break;
+            }
+
+            (val, s) = resolve(val, s)?;
+        }
+    }
+
+    Ok((val, scope))
+}
+
+// How a loop goes on after calling its body
+pub enum LoopStep {
+    Next(ValRef, Scope),
+    Break(ValRef, Scope),
+}
+
+// Handle the 'break' and 'continue' signals from a call to a loop's body.
+// A 'continue' ends the iteration, with its value as the body's result.
+pub fn loop_step(res: FuncResult) -> Result<LoopStep, Unwind> {
+    match res {
+        Ok((val, scope)) => Ok(LoopStep::Next(val, scope)),
+        Err(Unwind::Signal(signal, trace)) => match *signal {
+            Signal::Continue(val, scope) => Ok(LoopStep::Next(val, scope)),
+            Signal::Break(val, scope) => Ok(LoopStep::Break(val, scope)),
+            signal => Err(Unwind::Signal(Box::new(signal), trace)),
         },
-        ast::Expression::Block(exprs) => Ok((ValRef::Block(exprs.clone()), scope)),
-    }?;
+        Err(err) => Err(err),
+    }
+}
 
+// Defer a call, so that it's run by the trampoline in 'call' instead of recursing.
+// Native functions can use this to call a function in tail position.
+pub fn tail_call(func: ValRef, args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    Ok((
+        ValRef::TailCall(Box::new(TailCallVal {
+            func,
+            args,
+            scope: scope.clone(),
+            site: None,
+        })),
+        scope,
+    ))
+}
+
+fn eval_args(
+    exprs: &[ast::Expression],
+    mut scope: Scope,
+) -> Result<(ValRef, Vec<ValRef>, Scope), Unwind> {
+    if exprs.is_empty() {
+        return Err(StackTrace::from_str("Call list has no elements").into());
+    }
+
+    let mut args: Vec<ValRef> = Vec::with_capacity(exprs.len() - 1);
+    for item in exprs.iter().skip(1) {
+        let arg;
+        (arg, scope) = match item {
+            // Keywords only evaluate to a value as an argument, so that the value
+            // can't end up anywhere but in the call. They count like any other expression.
+            ast::Expression::Keyword(name, _) => {
+                let ctx = scope.context().clone();
+                ctx.enter()?;
+                ctx.leave();
+                (ValRef::Keyword(*name), scope)
+            }
+            _ => eval_tree(item, scope)?,
+        };
+        args.push(arg);
+    }
+
+    let (func, scope) = eval_tree(&exprs[0], scope)?;
+    Ok((func, args, scope))
+}
+
+pub fn eval_call(exprs: &[ast::Expression], scope: Scope) -> FuncResult {
+    let (func, args, scope) = eval_args(exprs, scope)?;
+    call(&func, args, scope)
+}
+
+fn resolve_lazy(lazy: &ValRef, scope: Scope) -> FuncResult {
+    match lazy {
+        ValRef::Func(..) => call(lazy, Vec::new(), scope),
+        ValRef::Lambda(l) => eval_block(&l.body, l.scope.subscope()),
+        ValRef::Block(b) => eval_block(b, scope.subscope()),
+        _ => Ok((lazy.clone(), scope)),
+    }
+}
+
+pub(crate) fn resolve(mut val: ValRef, mut scope: Scope) -> FuncResult {
     loop {
         match val {
             ValRef::Lazy(lazy) => (val, scope) = resolve_lazy(&lazy, scope)?,
@@ -602,12 +2043,167 @@ pub fn eval(expr: &ast::Expression, scope: Scope) -> FuncResult {
     }
 }
 
+pub fn eval(expr: &ast::Expression, scope: Scope) -> FuncResult {
+    match scope.context().backend() {
+        Backend::Tree => eval_tree(expr, scope),
+        Backend::Vm => vm::run(
+            Rc::new(bytecode::compile(slice::from_ref(expr))),
+            0..1,
+            false,
+            scope,
+        ),
+    }
+}
+
 pub fn eval_multiple(exprs: &[ast::Expression], scope: Scope) -> FuncResult {
+    match scope.context().backend() {
+        Backend::Tree => eval_multiple_tree(exprs, scope),
+        Backend::Vm => vm::run(
+            Rc::new(bytecode::compile(exprs)),
+            0..exprs.len(),
+            false,
+            scope,
+        ),
+    }
+}
+
+// Like 'eval_multiple', but if the last expression is a call, it's returned as
+// a ValRef::TailCall. The result must be returned to the trampoline in 'call'.
+pub fn eval_multiple_tail(exprs: &[ast::Expression], scope: Scope) -> FuncResult {
+    match scope.context().backend() {
+        Backend::Tree => eval_multiple_tail_tree(exprs, scope),
+        Backend::Vm => vm::run(
+            Rc::new(bytecode::compile(exprs)),
+            0..exprs.len(),
+            true,
+            scope,
+        ),
+    }
+}
+
+// Like 'eval_multiple' and 'eval_multiple_tail', but for the expressions
+// in a range of a block. Prefer these over the slice versions when evaluating
+// blocks, since the VM only has to compile each block once.
+pub fn eval_block_range(
+    block: &Rc<Vec<ast::Expression>>,
+    range: Range<usize>,
+    tail: bool,
+    scope: Scope,
+) -> FuncResult {
+    match scope.context().backend() {
+        Backend::Tree if tail => eval_multiple_tail_tree(&block[range], scope),
+        Backend::Tree => eval_multiple_tree(&block[range], scope),
+        Backend::Vm => {
+            let chunk = scope.context().compiled(block);
+            vm::run(chunk, range, tail, scope)
+        }
+    }
+}
+
+pub fn eval_block(block: &Rc<Vec<ast::Expression>>, scope: Scope) -> FuncResult {
+    eval_block_range(block, 0..block.len(), false, scope)
+}
+
+pub fn eval_block_tail(block: &Rc<Vec<ast::Expression>>, scope: Scope) -> FuncResult {
+    eval_block_range(block, 0..block.len(), true, scope)
+}
+
+fn eval_tree(expr: &ast::Expression, scope: Scope) -> FuncResult {
+    let ctx = scope.context().clone();
+    ctx.enter()?;
+    let res = eval_expr(expr, scope);
+    ctx.leave();
+    res
+}
+
+fn eval_expr(expr: &ast::Expression, scope: Scope) -> FuncResult {
+    match eval_expr_value(expr, scope) {
+        Ok((val, scope)) => resolve(val, scope).map_err(|err| err.at(expr.loc())),
+        Err(err) => Err(err.at(expr.loc())),
+    }
+}
+
+fn eval_expr_value(expr: &ast::Expression, scope: Scope) -> FuncResult {
+    match expr {
+        // Literals share the string with the syntax tree, so they aren't counted
+        // as allocated memory
+        ast::Expression::String(s, _) => Ok((ValRef::String(s.clone()), scope)),
+        ast::Expression::Number(num, _) => Ok((ValRef::Number(*num), scope)),
+        ast::Expression::Int(num, _) => Ok((ValRef::Int(*num), scope)),
+        ast::Expression::BigInt(num, _) => Ok((ValRef::BigInt(num.clone()), scope)),
+        ast::Expression::Rational(num, _) => Ok((ValRef::Rational(num.clone()), scope)),
+        ast::Expression::Lookup(name, _) => match scope.lookup(*name) {
+            Some(val) => Ok((val, scope)),
+            None => {
+                Err(StackTrace::from_string(format!("Variable '{}' doesn't exist", name)).into())
+            }
+        },
+        ast::Expression::Local(name, frames, slot, _) => {
+            match scope.lookup_local(*name, *frames, *slot) {
+                Some(val) => Ok((val, scope)),
+                None => Err(
+                    StackTrace::from_string(format!("Variable '{}' doesn't exist", name)).into(),
+                ),
+            }
+        }
+        ast::Expression::Keyword(name, _) => Err(keyword_error(*name).into()),
+        ast::Expression::Call(exprs, _) if exprs.is_empty() => {
+            Err(StackTrace::from_str("Call list has no elements").into())
+        }
+        ast::Expression::Call(exprs, loc) => match eval_call(exprs, scope) {
+            Ok(res) => Ok(res),
+            Err(trace) => Err(trace.push(loc.clone(), format!("{}", exprs[0]))),
+        },
+        ast::Expression::Block(exprs, _) => Ok((ValRef::Block(exprs.clone()), scope)),
+        // Like with calls, the key is evaluated first
+        ast::Expression::Property(val, key, _) => {
+            let (key, scope) = eval_tree(key, scope)?;
+            let (val, scope) = eval_tree(val, scope)?;
+            Ok((get_property(&val, &key)?, scope))
+        }
+    }
+}
+
+// Like 'eval_tree', but a call expression isn't run; it's returned as a ValRef::TailCall.
+fn eval_tail_tree(expr: &ast::Expression, scope: Scope) -> FuncResult {
+    match expr {
+        ast::Expression::Call(exprs, loc) if exprs.is_empty() => {
+            Err(StackTrace::from_str("Call list has no elements")
+                .at(loc)
+                .into())
+        }
+        ast::Expression::Call(exprs, loc) => match eval_args(exprs, scope) {
+            Ok((func, args, scope)) => Ok((
+                ValRef::TailCall(Box::new(TailCallVal {
+                    func,
+                    args,
+                    scope: scope.clone(),
+                    site: Some((loc.clone(), format!("{}", exprs[0]))),
+                })),
+                scope,
+            )),
+            Err(trace) => Err(trace.at(loc).push(loc.clone(), format!("{}", exprs[0]))),
+        },
+        _ => eval_tree(expr, scope),
+    }
+}
+
+fn eval_multiple_tree(exprs: &[ast::Expression], scope: Scope) -> FuncResult {
     let (mut retval, mut scope) = (ValRef::None, scope);
     for expr in exprs {
         drop(retval);
-        (retval, scope) = eval(expr, scope)?;
+        (retval, scope) = eval_tree(expr, scope)?;
     }
 
     Ok((retval, scope))
 }
+
+fn eval_multiple_tail_tree(exprs: &[ast::Expression], scope: Scope) -> FuncResult {
+    let (last, init) = match exprs.split_last() {
+        Some(split) => split,
+        None => return Ok((ValRef::None, scope)),
+    };
+
+    let (_, scope) = eval_multiple_tree(init, scope)?;
+    eval_tail_tree(last, scope)
+}
@@ -1,6 +1,7 @@
 use super::bstring::BString;
 use super::eval::{eval, FuncArgs, FuncResult, Scope, StackTrace, ValRef};
 use super::parse;
+use super::resolve;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs;
@@ -16,6 +17,12 @@ pub enum ImportResult {
 pub trait Import {
     fn import(&self, ctx: &ImportCtx, name: &BString) -> ImportResult;
     fn insert(&mut self, path: BString, val: ValRef);
+
+    // The code of a file which was imported, by its path,
+    // which is used to show the code when errors are rendered
+    fn source(&self, _path: &BString) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 pub struct ImportCtx {
@@ -24,7 +31,7 @@ pub struct ImportCtx {
 }
 
 impl ImportCtx {
-    fn new(importer: Rc<RefCell<dyn Import>>, cwd: BString) -> Self {
+    pub fn new(importer: Rc<RefCell<dyn Import>>, cwd: BString) -> Self {
         Self { importer, cwd }
     }
 }
@@ -35,7 +42,7 @@ pub struct DefaultImporter {
 }
 
 impl DefaultImporter {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             cache: HashMap::new(),
             builtins: HashMap::new(),
@@ -47,6 +54,12 @@ impl DefaultImporter {
     }
 }
 
+impl Default for DefaultImporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Import for DefaultImporter {
     fn import(&self, ctx: &ImportCtx, name: &BString) -> ImportResult {
         if let Some(val) = self.builtins.get(name) {
@@ -75,11 +88,15 @@ impl Import for DefaultImporter {
     fn insert(&mut self, path: BString, val: ValRef) {
         self.cache.insert(path, val);
     }
+
+    fn source(&self, path: &BString) -> Option<Vec<u8>> {
+        fs::read(path.to_path()).ok()
+    }
 }
 
 fn import(ctx: &Rc<ImportCtx>, name: &BString, mut scope: Scope) -> FuncResult {
     let (abspath, code) = match ctx.importer.borrow().import(ctx, name) {
-        ImportResult::Err(err) => return Err(err),
+        ImportResult::Err(err) => return Err(err.into()),
         ImportResult::ValRef(val) => return Ok((val, scope)),
         ImportResult::Code(path, code) => (path, code),
     };
@@ -96,6 +113,7 @@ fn import(ctx: &Rc<ImportCtx>, name: &BString, mut scope: Scope) -> FuncResult {
     scope = init_with_importer(scope, childctx);
 
     let mut reader = parse::Reader::new(code.as_bytes(), BString::from_os_str(abspath.as_os_str()));
+    reader.set_max_depth(scope.context().max_parse_depth());
 
     let mut retval = ValRef::None;
     loop {
@@ -105,17 +123,23 @@ fn import(ctx: &Rc<ImportCtx>, name: &BString, mut scope: Scope) -> FuncResult {
                 None => break,
             },
             Err(err) => {
-                return Err(StackTrace::from_string(format!(
-                    "{}: Parse error: {}:{}: {}",
-                    name, err.line, err.col, err.msg
-                )))
+                let hint = match &err.hint {
+                    Some(hint) => format!(" ({})", hint),
+                    None => String::new(),
+                };
+                let mut trace = StackTrace::from_string(format!(
+                    "{}: Parse error: {}:{}: {}{}",
+                    name, err.line, err.col, err.msg, hint
+                ));
+                trace.span = Some(err.location());
+                return Err(trace.into());
             }
         };
 
         drop(retval);
-        match eval(&expr, scope) {
+        match eval(&resolve::resolve(&expr), scope) {
             Ok(res) => (retval, scope) = res,
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into_error().into()),
         }
     }
 
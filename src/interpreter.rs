@@ -0,0 +1,526 @@
+use super::ast;
+use super::bignum::{BigInt, Rational};
+use super::bstring::BString;
+use super::convert::IntoFunc;
+use super::diagnostic;
+use super::eval::{
+    self, Abort, Backend, Context, Scope, StackTrace, StackTraceEntry, Unwind, ValRef,
+};
+use super::importlib::{self, DefaultImporter, Import, ImportCtx};
+use super::parse;
+use super::resolve;
+use super::symbol::Symbol;
+use super::{dotlib, iolib, stdlib};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ptr;
+use std::rc::Rc;
+
+// A copy of a value which can be sent between threads, like the value of an error.
+// Values which can't be copied, like functions and native objects,
+// and lists and dicts which contain themselves, are kept as their printed form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Number(f64),
+    Int(i64),
+    BigInt(BigInt),
+    Rational(Rational),
+    String(BString),
+    List(Vec<Value>),
+    Dict(HashMap<BString, Value>),
+    Other(String),
+}
+
+impl Value {
+    fn from_valref(val: &ValRef, seen: &mut Vec<*const ()>) -> Self {
+        let ptr = match val {
+            ValRef::List(lst) => Rc::as_ptr(lst) as *const (),
+            ValRef::Dict(dict) => Rc::as_ptr(dict) as *const (),
+            _ => ptr::null(),
+        };
+        if !ptr.is_null() && seen.contains(&ptr) {
+            return Value::Other(match val {
+                ValRef::List(..) => "[...]".to_string(),
+                _ => "{...}".to_string(),
+            });
+        }
+
+        seen.push(ptr);
+        let res = match val {
+            ValRef::None => Value::None,
+            ValRef::Bool(b) => Value::Bool(*b),
+            ValRef::Number(num) => Value::Number(*num),
+            ValRef::Int(num) => Value::Int(*num),
+            ValRef::BigInt(num) => Value::BigInt(num.as_ref().clone()),
+            ValRef::Rational(num) => Value::Rational(num.as_ref().clone()),
+            ValRef::String(s) => Value::String(s.as_ref().clone()),
+            ValRef::List(lst) => Value::List(
+                lst.borrow()
+                    .iter()
+                    .map(|val| Value::from_valref(val, seen))
+                    .collect(),
+            ),
+            ValRef::Dict(dict) => Value::Dict(
+                dict.borrow()
+                    .iter()
+                    .map(|(key, val)| (key.clone(), Value::from_valref(val, seen)))
+                    .collect(),
+            ),
+            val => Value::Other(val.to_string()),
+        };
+        seen.pop();
+        res
+    }
+}
+
+impl From<&ValRef> for Value {
+    fn from(val: &ValRef) -> Self {
+        Value::from_valref(val, &mut Vec::new())
+    }
+}
+
+// A runtime error, like a 'StackTrace', but with the error's value copied,
+// and rendered as text, so that errors can be sent between threads.
+#[derive(Debug)]
+pub struct Trace {
+    pub message: String,
+    pub value: Box<Value>,
+    pub span: Option<ast::Location>,
+    pub trace: Vec<StackTraceEntry>,
+    pub abort: Option<Abort>,
+}
+
+impl From<StackTrace> for Trace {
+    fn from(trace: StackTrace) -> Self {
+        Self {
+            message: trace.message_string(),
+            value: Box::new(Value::from(&trace.message)),
+            span: trace.span,
+            trace: trace.trace,
+            abort: trace.abort,
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        eval::write_trace(f, &self.message, self.span.as_ref(), &self.trace)
+    }
+}
+
+// Runtime errors come from the interpreter or from native functions,
+// script errors are raised by scripts with 'error'.
+// Io errors have a path if they came from reading a script file.
+// Errors don't refer to any values, so they're Send and Sync.
+#[derive(Debug)]
+pub enum Error {
+    Parse(parse::ParseError),
+    Runtime(Trace),
+    Script(Trace),
+    Io(Option<BString>, io::Error),
+}
+
+impl Error {
+    pub fn trace(&self) -> Option<&Trace> {
+        match self {
+            Error::Runtime(trace) | Error::Script(trace) => Some(trace),
+            _ => None,
+        }
+    }
+
+    // The value passed to 'error', for errors raised by scripts
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Error::Script(trace) => Some(trace.value.as_ref()),
+            _ => None,
+        }
+    }
+
+    // The status passed to 'exit', for scripts which exited
+    pub fn exit_status(&self) -> Option<i32> {
+        match self.trace()?.abort {
+            Some(Abort::Exit(status)) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "Parse error: {}", err),
+            Error::Runtime(trace) | Error::Script(trace) => write!(f, "Error: {}", trace),
+            Error::Io(Some(path), err) => write!(f, "{}: {}", path, err),
+            Error::Io(None, err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<parse::ParseError> for Error {
+    fn from(err: parse::ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<StackTrace> for Error {
+    fn from(trace: StackTrace) -> Self {
+        if trace.raised {
+            Error::Script(trace.into())
+        } else {
+            Error::Runtime(trace.into())
+        }
+    }
+}
+
+impl From<Unwind> for Error {
+    fn from(err: Unwind) -> Self {
+        err.into_error().into()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(None, err)
+    }
+}
+
+pub struct Builder {
+    stdlib: bool,
+    stdio: Option<stdlib::StdIo>,
+    iolib: bool,
+    importlib: bool,
+    dotlib: bool,
+    cwd: BString,
+    importer: Option<Rc<RefCell<dyn Import>>>,
+    context: Option<Rc<Context>>,
+    backend: Option<Backend>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            stdlib: true,
+            stdio: None,
+            iolib: true,
+            importlib: true,
+            dotlib: false,
+            cwd: BString::from_str("."),
+            importer: None,
+            context: None,
+            backend: None,
+        }
+    }
+
+    pub fn stdlib(mut self, enable: bool) -> Self {
+        self.stdlib = enable;
+        self
+    }
+
+    // Use the given ports as stdin, stdout and stderr, instead of the process's
+    pub fn stdio(mut self, stdio: stdlib::StdIo) -> Self {
+        self.stdio = Some(stdio);
+        self
+    }
+
+    pub fn iolib(mut self, enable: bool) -> Self {
+        self.iolib = enable;
+        self
+    }
+
+    pub fn importlib(mut self, enable: bool) -> Self {
+        self.importlib = enable;
+        self
+    }
+
+    pub fn dotlib(mut self, enable: bool) -> Self {
+        self.dotlib = enable;
+        self
+    }
+
+    // The directory which imports from 'eval_str' are relative to
+    pub fn cwd(mut self, cwd: BString) -> Self {
+        self.cwd = cwd;
+        self
+    }
+
+    pub fn importer(mut self, importer: Rc<RefCell<dyn Import>>) -> Self {
+        self.importer = Some(importer);
+        self
+    }
+
+    pub fn context(mut self, context: Rc<Context>) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    // Defaults to the context's backend, which is the tree-walking evaluator
+    // unless the context was set up otherwise
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
+        let mut root = match self.context {
+            Some(ctx) => Scope::with_context(ctx),
+            None => Scope::new(),
+        };
+
+        if let Some(backend) = self.backend {
+            root.context().set_backend(backend);
+        }
+
+        if self.stdlib {
+            root = match self.stdio {
+                Some(stdio) => stdlib::init_with_stdio(root, stdio),
+                None => stdlib::init(root),
+            };
+        }
+
+        if self.iolib {
+            root = iolib::init(root);
+        }
+
+        let importer = match self.importer {
+            Some(importer) => importer,
+            None => Rc::new(RefCell::new(DefaultImporter::new())),
+        };
+
+        if self.importlib {
+            root = importlib::init_with_importer(
+                root,
+                Rc::new(ImportCtx::new(importer.clone(), self.cwd)),
+            );
+        }
+
+        if self.dotlib {
+            root = dotlib::init(root);
+        }
+
+        Interpreter {
+            globals: root.subscope(),
+            importer,
+            importlib: self.importlib,
+            sources: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// An interpreter owns a scope of global variables, which persists across evaluations.
+// It also keeps the code it has evaluated, by file name, so that errors can be
+// rendered with the code they came from.
+pub struct Interpreter {
+    globals: Scope,
+    importer: Rc<RefCell<dyn Import>>,
+    importlib: bool,
+    sources: HashMap<BString, Vec<u8>>,
+}
+
+impl Interpreter {
+    // Create an interpreter with the stdlib, iolib and importlib loaded
+    pub fn new() -> Self {
+        Builder::new().build()
+    }
+
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.globals
+    }
+
+    pub fn context(&self) -> &Rc<Context> {
+        self.globals.context()
+    }
+
+    pub fn importer(&self) -> &Rc<RefCell<dyn Import>> {
+        &self.importer
+    }
+
+    pub fn get(&self, name: &str) -> Option<ValRef> {
+        // A name which has never been interned can't be the name of a variable
+        let name = Symbol::get(&BString::from_str(name))?;
+        self.globals.lookup(name)
+    }
+
+    pub fn set(&mut self, name: &str, val: ValRef) {
+        self.globals = self.globals.clone().put(name, val);
+    }
+
+    // See 'Scope::register_fn'.
+    pub fn register_fn<Args, F: IntoFunc<Args>>(&mut self, name: &str, func: F) {
+        self.globals = self.globals.clone().register_fn(name, func);
+    }
+
+    pub fn call_global(&mut self, name: &str, args: Vec<ValRef>) -> Result<ValRef, Error> {
+        self.begin_eval();
+        let func = match self.get(name) {
+            Some(func) => func,
+            None => {
+                return Err(
+                    StackTrace::from_string(format!("Variable '{}' doesn't exist", name)).into(),
+                )
+            }
+        };
+
+        let (val, scope) = eval::call(&func, args, self.globals.clone())?;
+        self.globals = scope;
+        Ok(val)
+    }
+
+    pub fn eval_str(&mut self, code: &str) -> Result<ValRef, Error> {
+        self.eval_bytes(code.as_bytes(), BString::from_str("<string>"))
+    }
+
+    // Evaluate every expression in the code, returning the value of the last one
+    pub fn eval_bytes(&mut self, code: &[u8], filename: BString) -> Result<ValRef, Error> {
+        self.begin_eval();
+        self.sources.insert(filename.clone(), code.to_vec());
+        let (retval, scope) = eval_code(code, filename, self.globals.clone())?;
+        self.globals = scope;
+        Ok(retval)
+    }
+
+    // Evaluate a file. Imports in the file are relative to the file's directory.
+    // Variables defined by the file become global variables.
+    pub fn eval_file(&mut self, path: &BString) -> Result<ValRef, Error> {
+        self.begin_eval();
+        let code = match fs::read(path.to_path()) {
+            Ok(code) => code,
+            Err(err) => return Err(Error::Io(Some(path.clone()), err)),
+        };
+
+        if !self.importlib {
+            return self.eval_bytes(&code, path.clone());
+        }
+
+        let mut dirpath = path.to_path();
+        dirpath.pop();
+        let importctx = Rc::new(ImportCtx::new(
+            self.importer.clone(),
+            BString::from_os_str(dirpath.as_os_str()),
+        ));
+        let filescope = importlib::init_with_importer(self.globals.subscope(), importctx);
+        self.sources.insert(path.clone(), code.clone());
+
+        let (retval, filescope) = eval_code(&code, path.clone(), filescope)?;
+
+        let import = Symbol::from_str("import");
+        if let Some(map) = &filescope.m.map {
+            for (key, val) in map.borrow().iter() {
+                if *key != import {
+                    self.globals = self.globals.clone().insert(*key, val.clone());
+                }
+            }
+        }
+
+        Ok(retval)
+    }
+
+    // Evaluate an expression which has already been parsed,
+    // like one from a 'parse::StreamReader'
+    pub fn eval_expr(&mut self, expr: &ast::Expression) -> Result<ValRef, Error> {
+        self.begin_eval();
+        let (retval, scope) = eval::eval(&resolve::resolve(expr), self.globals.clone())?;
+        self.globals = scope;
+        Ok(retval)
+    }
+
+    // An interrupt which came while nothing was being evaluated,
+    // like a Ctrl-C at an idle REPL prompt, shouldn't abort the next evaluation
+    fn begin_eval(&self) {
+        self.context().interrupt_handle().reset();
+    }
+
+    // Add code to the end of a file's source, for code which is evaluated
+    // as it's read. Errors in the code can then be rendered with the code.
+    pub fn append_source(&mut self, file: &BString, code: &[u8]) {
+        match self.sources.get_mut(file) {
+            Some(source) => source.extend_from_slice(code),
+            None => {
+                self.sources.insert(file.clone(), code.to_vec());
+            }
+        }
+    }
+
+    // The global variables which have been defined by code or with 'set',
+    // not including the ones from the libraries, sorted by name
+    pub fn variables(&self) -> Vec<(Symbol, ValRef)> {
+        let mut vars = match &self.globals.m.map {
+            Some(map) => map
+                .borrow()
+                .iter()
+                .map(|(key, val)| (*key, val.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        vars.sort_by(|a, b| a.0.as_bstring().as_bytes().cmp(b.0.as_bstring().as_bytes()));
+        vars
+    }
+
+    // The code of a file which has been evaluated or imported
+    pub fn source(&self, file: &BString) -> Option<Vec<u8>> {
+        match self.sources.get(file) {
+            Some(code) => Some(code.clone()),
+            None => self.importer.borrow().source(file),
+        }
+    }
+
+    // Render an error with the line of code it came from, see the 'diagnostic' module.
+    // With 'color', the output contains ANSI color codes.
+    pub fn render_error(&self, err: &Error, color: bool) -> String {
+        match err {
+            Error::Parse(err) => {
+                let source = self.source(&err.file);
+                diagnostic::render_parse_error(err, source.as_deref(), color)
+            }
+            Error::Runtime(trace) | Error::Script(trace) => {
+                let span = trace.span.as_ref();
+                let source = span.and_then(|span| self.source(&span.file));
+                diagnostic::render_trace(
+                    &trace.message,
+                    span,
+                    &trace.trace,
+                    source.as_deref(),
+                    color,
+                )
+            }
+            Error::Io(..) => format!("{}\n", err),
+        }
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn eval_code(code: &[u8], filename: BString, mut scope: Scope) -> Result<(ValRef, Scope), Error> {
+    let mut reader = parse::Reader::new(code, filename);
+    reader.set_max_depth(scope.context().max_parse_depth());
+
+    let mut retval = ValRef::None;
+    while let Some(expr) = parse::parse(&mut reader)? {
+        drop(retval);
+        (retval, scope) = eval::eval(&resolve::resolve(&expr), scope)?;
+    }
+
+    Ok((retval, scope))
+}
@@ -1,5 +1,5 @@
 use super::bstring::BString;
-use super::eval::{FuncResult, PortVal, Scope, StackTrace, ValRef};
+use super::eval::{self, FuncResult, PortVal, Scope, StackTrace, ValRef};
 use std::cell::RefCell;
 use std::fs;
 use std::io;
@@ -45,26 +45,24 @@ impl PortVal for TextFile {
 }
 
 pub fn lib_open(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.len() != 1 {
-        return Err(StackTrace::from_str("'open' requires 1 argument"));
+        return Err(StackTrace::from_str("'open' requires 1 argument").into());
     }
 
     let path = match &args[0] {
         ValRef::String(s) => s,
         _ => {
-            return Err(StackTrace::from_str(
-                "'open' requires the first argument to be a string",
-            ))
+            return Err(
+                StackTrace::from_str("'open' requires the first argument to be a string").into(),
+            )
         }
     };
 
     let f = match fs::File::open(path.to_path()) {
         Ok(f) => f,
         Err(err) => {
-            return Err(StackTrace::from_string(format!(
-                "'open': {}: {}",
-                path, err
-            )))
+            return Err(StackTrace::from_string(format!("'open': {}: {}", path, err)).into())
         }
     };
 
@@ -72,26 +70,24 @@ pub fn lib_open(args: Vec<ValRef>, scope: Scope) -> FuncResult {
 }
 
 pub fn lib_create(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.len() != 1 {
-        return Err(StackTrace::from_str("'create' requires 1 argument"));
+        return Err(StackTrace::from_str("'create' requires 1 argument").into());
     }
 
     let path = match &args[0] {
         ValRef::String(s) => s,
         _ => {
-            return Err(StackTrace::from_str(
-                "'create' requires the first argument to be a string",
-            ))
+            return Err(
+                StackTrace::from_str("'create' requires the first argument to be a string").into(),
+            )
         }
     };
 
     let f = match fs::File::create(path.to_path()) {
         Ok(f) => f,
         Err(err) => {
-            return Err(StackTrace::from_string(format!(
-                "'create': {}: {}",
-                path, err
-            )))
+            return Err(StackTrace::from_string(format!("'create': {}: {}", path, err)).into())
         }
     };
 
@@ -137,16 +133,15 @@ impl PortVal for ChildProc {
 }
 
 pub fn lib_exec(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
-        return Err(StackTrace::from_str("'exec' requires at least 1 argument"));
+        return Err(StackTrace::from_str("'exec' requires at least 1 argument").into());
     }
 
     let name = match &args[0] {
         ValRef::String(s) => s,
         _ => {
-            return Err(StackTrace::from_str(
-                "'exec' requires its arguments to be strings",
-            ))
+            return Err(StackTrace::from_str("'exec' requires its arguments to be strings").into())
         }
     };
 
@@ -156,15 +151,15 @@ pub fn lib_exec(args: Vec<ValRef>, scope: Scope) -> FuncResult {
         match item {
             ValRef::String(s) => cmd.arg(s.to_os_str()),
             _ => {
-                return Err(StackTrace::from_str(
-                    "'exec' requires its arguments to be strings",
-                ))
+                return Err(
+                    StackTrace::from_str("'exec' requires its arguments to be strings").into(),
+                )
             }
         };
     }
 
     match cmd.spawn() {
-        Err(err) => Err(StackTrace::from_string(format!("exec: {}", err))),
+        Err(err) => Err(StackTrace::from_string(format!("exec: {}", err)).into()),
         Ok(child) => Ok((
             ValRef::Port(Rc::new(RefCell::new(ChildProc { c: child }))),
             scope,
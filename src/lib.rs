@@ -1,8 +1,17 @@
 pub mod ast;
+pub mod bignum;
 pub mod bstring;
+pub mod bytecode;
+pub mod convert;
+pub mod diagnostic;
 pub mod dotlib;
 pub mod eval;
 pub mod importlib;
+pub mod interpreter;
 pub mod iolib;
+pub mod number;
 pub mod parse;
+pub mod resolve;
 pub mod stdlib;
+pub mod symbol;
+pub mod vm;
@@ -0,0 +1,240 @@
+use super::bignum::{BigInt, Rational};
+use super::eval::{self, StackTrace, ValRef};
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+// The numeric tower. Arithmetic on ints which overflows is done with big ints,
+// integers are promoted to rationals when they're combined with rationals,
+// and anything combined with a float is a float. Exact results are always
+// stored in the smallest type which can represent them, so a big int is never
+// in the range of an int, and a rational is never an integer.
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Int,
+    BigInt,
+    Rational,
+    Float,
+}
+
+// Values which aren't numbers are treated as floats, with the value of 'to_num'
+fn level(val: &ValRef) -> Level {
+    match val {
+        ValRef::Int(..) => Level::Int,
+        ValRef::BigInt(..) => Level::BigInt,
+        ValRef::Rational(..) => Level::Rational,
+        _ => Level::Float,
+    }
+}
+
+pub fn is_exact(val: &ValRef) -> bool {
+    level(val) != Level::Float
+}
+
+fn to_bigint(val: &ValRef) -> BigInt {
+    match val {
+        ValRef::Int(num) => BigInt::from_i64(*num),
+        ValRef::BigInt(num) => num.as_ref().clone(),
+        _ => unreachable!(),
+    }
+}
+
+// The exact value of a number, or None for floats which are infinite or NaN
+pub fn to_rational(val: &ValRef) -> Option<Rational> {
+    match val {
+        ValRef::Int(..) | ValRef::BigInt(..) => Some(Rational::from_int(to_bigint(val))),
+        ValRef::Rational(num) => Some(num.as_ref().clone()),
+        _ => Rational::from_f64(val.to_num()),
+    }
+}
+
+pub fn from_bigint(num: BigInt) -> ValRef {
+    match num.to_i64() {
+        Some(num) => ValRef::Int(num),
+        None => ValRef::BigInt(Rc::new(num)),
+    }
+}
+
+pub fn from_rational(num: Rational) -> ValRef {
+    if num.is_integer() {
+        from_bigint(num.numer().clone())
+    } else {
+        ValRef::Rational(Rc::new(num))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Rem,
+}
+
+fn division_by_zero() -> StackTrace {
+    StackTrace::from_str("Division by zero")
+}
+
+// Apply an operation to two numbers
+pub fn binary(op: Op, a: &ValRef, b: &ValRef) -> Result<ValRef, StackTrace> {
+    let mut lvl = level(a).max(level(b));
+    if let (Level::Int, ValRef::Int(x), ValRef::Int(y)) = (lvl, a, b) {
+        let res = match op {
+            Op::Add => x.checked_add(*y),
+            Op::Sub => x.checked_sub(*y),
+            Op::Mul => x.checked_mul(*y),
+            Op::Rem if *y == 0 => return Err(division_by_zero()),
+            Op::Rem => x.checked_rem(*y),
+        };
+
+        match res {
+            Some(num) => return Ok(ValRef::Int(num)),
+            None => lvl = Level::BigInt,
+        }
+    }
+
+    match lvl {
+        Level::Int | Level::BigInt => {
+            let (x, y) = (to_bigint(a), to_bigint(b));
+            let res = match op {
+                Op::Add => x.add(&y),
+                Op::Sub => x.sub(&y),
+                Op::Mul => x.mul(&y),
+                Op::Rem => x.divrem(&y).ok_or_else(division_by_zero)?.1,
+            };
+            Ok(from_bigint(res))
+        }
+        Level::Rational => {
+            let (x, y) = (to_rational(a).unwrap(), to_rational(b).unwrap());
+            let res = match op {
+                Op::Add => x.add(&y),
+                Op::Sub => x.sub(&y),
+                Op::Mul => x.mul(&y),
+                Op::Rem => x.rem(&y).ok_or_else(division_by_zero)?,
+            };
+            Ok(from_rational(res))
+        }
+        Level::Float => {
+            let (x, y) = (a.to_num(), b.to_num());
+            let res = match op {
+                Op::Add => x + y,
+                Op::Sub => x - y,
+                Op::Mul => x * y,
+                Op::Rem => x % y,
+            };
+            Ok(ValRef::Number(res))
+        }
+    }
+}
+
+// Divide the first number by the others. Division is exact if one of the numbers
+// is a rational and none of them are floats; otherwise, the result is a float,
+// even if all the numbers are integers.
+pub fn divide(args: &[ValRef]) -> Result<ValRef, StackTrace> {
+    let exact = args.iter().any(|arg| level(arg) == Level::Rational) && args.iter().all(is_exact);
+    divide_as(args, exact)
+}
+
+// Like 'divide', but division is exact if none of the numbers are floats,
+// so dividing integers which don't divide evenly gives a rational.
+pub fn divide_exact(args: &[ValRef]) -> Result<ValRef, StackTrace> {
+    divide_as(args, args.iter().all(is_exact))
+}
+
+fn divide_as(args: &[ValRef], exact: bool) -> Result<ValRef, StackTrace> {
+    if exact {
+        let mut acc = to_rational(&args[0]).unwrap();
+        for arg in &args[1..] {
+            acc = acc.div(&to_rational(arg).unwrap()).ok_or_else(division_by_zero)?;
+        }
+        return Ok(from_rational(acc));
+    }
+
+    let mut acc = args[0].to_num();
+    for arg in &args[1..] {
+        acc /= arg.to_num();
+    }
+    Ok(ValRef::Number(acc))
+}
+
+pub fn negate(val: &ValRef) -> ValRef {
+    match val {
+        ValRef::Int(num) => match num.checked_neg() {
+            Some(num) => ValRef::Int(num),
+            None => from_bigint(BigInt::from_i64(*num).neg()),
+        },
+        ValRef::BigInt(num) => from_bigint(num.neg()),
+        ValRef::Rational(num) => ValRef::Rational(Rc::new(num.neg())),
+        _ => ValRef::Number(-val.to_num()),
+    }
+}
+
+// Compare two numbers exactly, even when comparing exact numbers with floats.
+// Returns None if either number is NaN.
+pub fn compare(a: &ValRef, b: &ValRef) -> Option<Ordering> {
+    match (a, b) {
+        (ValRef::Int(x), ValRef::Int(y)) => return Some(x.cmp(y)),
+        (ValRef::Int(x), ValRef::Number(y)) => return compare_int_float(*x, *y),
+        (ValRef::Number(x), ValRef::Int(y)) => {
+            return compare_int_float(*y, *x).map(Ordering::reverse)
+        }
+        _ => (),
+    }
+
+    match (is_exact(a), is_exact(b)) {
+        (false, false) => a.to_num().partial_cmp(&b.to_num()),
+        (true, true) => match level(a).max(level(b)) {
+            Level::Rational => Some(to_rational(a)?.cmp(&to_rational(b)?)),
+            _ => Some(to_bigint(a).cmp(&to_bigint(b))),
+        },
+        (true, false) => compare_exact_float(a, b.to_num()),
+        (false, true) => compare_exact_float(b, a.to_num()).map(Ordering::reverse),
+    }
+}
+
+fn compare_exact_float(a: &ValRef, b: f64) -> Option<Ordering> {
+    if b.is_nan() {
+        None
+    } else if b == f64::INFINITY {
+        Some(Ordering::Less)
+    } else if b == f64::NEG_INFINITY {
+        Some(Ordering::Greater)
+    } else {
+        Some(to_rational(a)?.cmp(&Rational::from_f64(b)?))
+    }
+}
+
+fn compare_int_float(a: i64, b: f64) -> Option<Ordering> {
+    let ord = (a as f64).partial_cmp(&b)?;
+    if ord != Ordering::Equal {
+        return Some(ord);
+    }
+
+    // 'a' may have been rounded to 'b'. If 'b' isn't in the range of i64,
+    // it's 2^63, which is greater than any i64.
+    match eval::int_from_float(b) {
+        Some(b) => Some(a.cmp(&b)),
+        None => Some(Ordering::Less),
+    }
+}
+
+// Round a number towards zero. Returns None for infinity and NaN.
+pub fn trunc(val: &ValRef) -> Option<ValRef> {
+    match val {
+        ValRef::Int(..) | ValRef::BigInt(..) => Some(val.clone()),
+        ValRef::Rational(num) => Some(from_bigint(num.trunc())),
+        _ => Some(from_rational(Rational::from_f64(val.to_num().trunc())?)),
+    }
+}
+
+// Write a float as the shortest literal which parses back to the same float.
+// Floats always have a decimal point or an exponent, so that they don't look like ints.
+pub fn fmt_float(f: &mut fmt::Formatter, num: f64) -> fmt::Result {
+    if num.is_nan() {
+        write!(f, "nan")
+    } else {
+        write!(f, "{:?}", num)
+    }
+}
@@ -1,33 +1,103 @@
 use super::ast;
+use super::bignum::{BigInt, Rational};
 use super::bstring::BString;
+use super::symbol::Symbol;
 
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
 use std::rc::Rc;
+use std::sync::Arc;
 
+// The default maximum nesting depth of expressions.
+pub const DEFAULT_MAX_DEPTH: usize = 500;
+
+// The default maximum length of an incomplete expression in a StreamReader.
+pub const DEFAULT_MAX_BUFFER: usize = 16 * 1024 * 1024;
+
+// 'hint' is extra information about what might have caused the error,
+// like where an unclosed bracket was opened.
+#[derive(Debug)]
 pub struct ParseError {
+    pub file: Arc<BString>,
     pub line: u32,
     pub col: u32,
     pub msg: String,
+    pub hint: Option<String>,
 }
 
+impl ParseError {
+    fn with_hint(mut self, hint: String) -> Self {
+        self.hint = Some(hint);
+        self
+    }
+
+    // The location of the error, as a span of one character
+    pub fn location(&self) -> ast::Location {
+        ast::Location {
+            line: self.line,
+            column: self.col,
+            end_line: self.line,
+            end_column: self.col + 1,
+            file: self.file.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.col, self.msg)?;
+        if let Some(hint) = &self.hint {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ParseError {}
+
+// In recovering mode (see 'parse_all'), errors inside of a form are collected
+// in 'errors' instead of being returned. 'closers' are the closing brackets
+// of the lists which are being parsed, innermost last.
 pub struct Reader<'a> {
-    filename: Rc<BString>,
+    filename: Arc<BString>,
     line: u32,
     col: u32,
     string: &'a [u8],
     idx: usize,
+    depth: usize,
+    max_depth: usize,
+    recovering: bool,
+    errors: Vec<ParseError>,
+    closers: Vec<u8>,
 }
 
 impl<'a> Reader<'a> {
     pub fn new(string: &'a [u8], filename: BString) -> Self {
+        Self::at(string, Arc::new(filename), 1, 1)
+    }
+
+    // A reader for input which starts at 'line' and 'col' of the file
+    fn at(string: &'a [u8], filename: Arc<BString>, line: u32, col: u32) -> Self {
         Self {
-            filename: Rc::new(filename),
-            line: 1,
-            col: 1,
+            filename,
+            line,
+            col,
             string,
             idx: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            recovering: false,
+            errors: Vec::new(),
+            closers: Vec::new(),
         }
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     fn peek(&self) -> u8 {
         if self.idx < self.string.len() {
             self.string[self.idx]
@@ -36,7 +106,7 @@ impl<'a> Reader<'a> {
         }
     }
 
-    fn eof(&self) -> bool {
+    pub fn eof(&self) -> bool {
         self.idx == self.string.len()
     }
 
@@ -56,17 +126,333 @@ impl<'a> Reader<'a> {
 
     fn err(&self, msg: String) -> ParseError {
         ParseError {
+            file: self.filename.clone(),
             line: self.line,
             col: self.col,
             msg,
+            hint: None,
         }
     }
 
+    // Collect the error in recovering mode, otherwise return it
+    fn report(&mut self, err: ParseError) -> Result<(), ParseError> {
+        if self.recovering {
+            self.errors.push(err);
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    // The current position, as an empty span
     fn loc(&self) -> ast::Location {
         ast::Location {
             file: self.filename.clone(),
             line: self.line,
             column: self.col,
+            end_line: self.line,
+            end_column: self.col,
+        }
+    }
+
+    // The span from 'start' to the current position
+    fn span(&self, start: &ast::Location) -> ast::Location {
+        ast::Location {
+            file: start.file.clone(),
+            line: start.line,
+            column: start.column,
+            end_line: self.line,
+            end_column: self.col,
+        }
+    }
+}
+
+// What a StreamReader found in the input it has so far
+pub enum Next {
+    Expr(ast::Expression),
+    // The input ends before an expression is complete. Feed it more input,
+    // or close it if there's no more.
+    NeedInput,
+    // The stream is closed, and all of its input has been parsed
+    End,
+}
+
+// A reader which gets its input in chunks, either pushed with 'feed'
+// or pulled from an io::Read, and parses it one expression at a time.
+//
+// An expression at the end of the input is only complete once it's followed
+// by something which can't continue it: "12" could become "123", and "foo"
+// could become "foo.bar", even on the next line. In interactive mode, a newline
+// after the expression completes it instead, so that input from a REPL is
+// evaluated line by line. Running out of input in the middle of an expression
+// isn't an error, until the stream is closed.
+//
+// New input is scanned once to keep track of open brackets, strings and comments,
+// and the buffered input is only parsed when that finds a point where an expression
+// could be complete. An expression which is still incomplete after 'max_buffer'
+// bytes is an error, and is discarded. After a syntax error, the input up to
+// the end of the line with the error is discarded.
+pub struct StreamReader {
+    filename: Arc<BString>,
+    line: u32,
+    col: u32,
+    buf: Vec<u8>,
+    scan: Scan,
+    // Whether to discard input until the end of the line, after a syntax error
+    skip_line: bool,
+    source: Option<Box<dyn io::Read>>,
+    closed: bool,
+    interactive: bool,
+    max_depth: usize,
+    max_buffer: usize,
+}
+
+// How far the buffered input has been scanned, and the state at that point
+#[derive(Default)]
+struct Scan {
+    offset: usize,
+    depth: usize,
+    string: bool,
+    escape: bool,
+    comment: bool,
+    // Whether the last byte ended a token, so the next one starts a new one
+    boundary: bool,
+    // Whether an expression could have been completed since the input was last parsed.
+    // That's when a new token starts at the top level, since an expression is only
+    // complete once something which can't continue it follows, or at the end of a line.
+    pending: bool,
+}
+
+impl Scan {
+    fn scan(&mut self, input: &[u8]) {
+        for ch in input[self.offset..].iter().copied() {
+            if self.comment {
+                self.comment = ch != b'\n';
+                self.boundary = !self.comment;
+                self.pending |= !self.comment && self.depth == 0;
+            } else if self.string {
+                if self.escape {
+                    self.escape = false;
+                } else if ch == b'\\' {
+                    self.escape = true;
+                } else if ch == b'"' {
+                    self.string = false;
+                    self.boundary = true;
+                }
+            } else if ch == b';' {
+                self.comment = true;
+            } else {
+                let starts_token = ch == b'\n' || (!is_space(ch) && self.boundary);
+                self.pending |= starts_token && self.depth == 0;
+                self.string = ch == b'"';
+                self.boundary = is_separator(ch);
+                match ch {
+                    b'(' | b'{' | b'[' => self.depth += 1,
+                    b')' | b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                    _ => (),
+                }
+            }
+        }
+
+        self.offset = input.len();
+    }
+}
+
+impl StreamReader {
+    pub fn new(filename: BString) -> Self {
+        Self {
+            filename: Arc::new(filename),
+            line: 1,
+            col: 1,
+            buf: Vec::new(),
+            scan: Scan::default(),
+            skip_line: false,
+            source: None,
+            closed: false,
+            interactive: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_buffer: DEFAULT_MAX_BUFFER,
+        }
+    }
+
+    // A stream which reads more input from 'source' whenever it needs it,
+    // and is closed when 'source' reaches EOF. 'next_expr' never returns NeedInput.
+    pub fn from_read(source: Box<dyn io::Read>, filename: BString) -> Self {
+        let mut stream = Self::new(filename);
+        stream.source = Some(source);
+        stream
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn set_max_buffer(&mut self, max_buffer: usize) {
+        self.max_buffer = max_buffer;
+    }
+
+    // In interactive mode, an expression which ends a line is complete,
+    // so a property access can't continue it on the next line
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // Signal that no more input is coming
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Throw away the input which hasn't been parsed yet
+    pub fn discard(&mut self) {
+        self.advance(self.buf.len());
+        self.scan = Scan::default();
+    }
+
+    // Whether the buffered input contains part of an expression,
+    // like an unclosed list. A REPL would show a continuation prompt.
+    pub fn has_partial(&self) -> bool {
+        let mut r = Reader::at(&self.buf, self.filename.clone(), self.line, self.col);
+        skip_space(&mut r);
+        !r.eof()
+    }
+
+    pub fn next_expr(&mut self) -> Result<Next, ParseError> {
+        loop {
+            match self.parse_buffered()? {
+                Next::NeedInput if self.buf.len() > self.max_buffer => {
+                    let err = ParseError {
+                        file: self.filename.clone(),
+                        line: self.line,
+                        col: self.col,
+                        msg: format!("Expression is longer than {} bytes", self.max_buffer),
+                        hint: None,
+                    };
+                    self.discard();
+                    return Err(err);
+                }
+                Next::NeedInput if self.source.is_some() => self.read_more()?,
+                next => return Ok(next),
+            }
+        }
+    }
+
+    fn read_more(&mut self) -> Result<(), ParseError> {
+        let mut chunk = [0u8; 4096];
+        let source = self.source.as_mut().unwrap();
+        loop {
+            match source.read(&mut chunk) {
+                Ok(0) => {
+                    self.source = None;
+                    self.closed = true;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    self.source = None;
+                    self.closed = true;
+                    return Err(ParseError {
+                        file: self.filename.clone(),
+                        line: self.line,
+                        col: self.col,
+                        msg: format!("Read error: {}", err),
+                        hint: None,
+                    });
+                }
+            }
+        }
+    }
+
+    fn parse_buffered(&mut self) -> Result<Next, ParseError> {
+        if self.skip_line {
+            match self.buf.iter().position(|ch| *ch == b'\n') {
+                Some(newline) => {
+                    self.advance(newline + 1);
+                    self.skip_line = false;
+                }
+                None => self.advance(self.buf.len()),
+            }
+        }
+
+        self.scan.scan(&self.buf);
+        if !self.scan.pending && !self.closed {
+            return Ok(Next::NeedInput);
+        }
+
+        let mut r = Reader::at(&self.buf, self.filename.clone(), self.line, self.col);
+        r.set_max_depth(self.max_depth);
+
+        let res = parse(&mut r);
+        let (idx, eof) = (r.idx, r.eof());
+        match res {
+            Ok(Some(expr)) if !eof || self.closed => {
+                self.advance(idx);
+                Ok(Next::Expr(expr))
+            }
+            Ok(Some(expr)) if self.interactive && r.line > expr.loc().end_line => {
+                // The rest of the input is whitespace or comments, which may
+                // not be complete yet, so only consume whole lines
+                if let Some(newline) = self.buf[..idx].iter().rposition(|ch| *ch == b'\n') {
+                    self.advance(newline + 1);
+                }
+                Ok(Next::Expr(expr))
+            }
+            Ok(None) if self.closed => {
+                self.advance(idx);
+                Ok(Next::End)
+            }
+            Ok(None) => {
+                // The input is whitespace or comments. Only consume whole lines,
+                // since the last line may be part of a comment which isn't complete yet.
+                if let Some(newline) = self.buf[..idx].iter().rposition(|ch| *ch == b'\n') {
+                    self.advance(newline + 1);
+                }
+                self.scan.pending = false;
+                Ok(Next::NeedInput)
+            }
+            Ok(Some(_)) => {
+                self.scan.pending = false;
+                Ok(Next::NeedInput)
+            }
+            Err(_) if eof && !self.closed => {
+                self.scan.pending = false;
+                Ok(Next::NeedInput)
+            }
+            Err(err) => {
+                // The discarded input may have had unclosed brackets or strings
+                self.advance(idx);
+                self.scan = Scan {
+                    pending: true,
+                    ..Scan::default()
+                };
+                self.skip_line = true;
+                Err(err)
+            }
+        }
+    }
+
+    // Remove the first 'n' bytes of input, which have been parsed.
+    // They're complete expressions or whole lines, so the scan state stays the same.
+    fn advance(&mut self, n: usize) {
+        self.scan.offset = self.scan.offset.saturating_sub(n);
+        for ch in self.buf.drain(..n) {
+            if ch == b'\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
         }
     }
 }
@@ -86,7 +472,14 @@ fn is_separator(ch: u8) -> bool {
         || ch == b'.'
 }
 
+// Skips comments too, and a shebang line ("#!/usr/bin/env osyris") at the start of a file
 fn skip_space(r: &mut Reader<'_>) {
+    if r.line == 1 && r.col == 1 && r.string[r.idx..].starts_with(b"#!") {
+        while !r.eof() && r.peek() != b'\n' {
+            r.consume();
+        }
+    }
+
     while !r.eof() {
         let ch = r.peek();
         if is_space(ch) {
@@ -129,6 +522,7 @@ fn read_name(r: &mut Reader<'_>) -> Result<BString, ParseError> {
 }
 
 fn parse_string(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
     r.consume(); // '"'
 
     let mut buf: Vec<u8> = Vec::new();
@@ -136,16 +530,17 @@ fn parse_string(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
         let ch = r.peek();
         if ch == b'"' {
             r.consume();
-            let _ = match std::str::from_utf8(&buf) {
-                Ok(..) => (),
-                Err(err) => return Err(r.err(format!("Invalid UTF-8: {}", err))),
-            };
+            if let Err(err) = std::str::from_utf8(&buf) {
+                r.report(r.err(format!("Invalid UTF-8: {}", err)))?;
+                buf = String::from_utf8_lossy(&buf).into_owned().into_bytes();
+            }
 
-            return Ok(ast::Expression::String(BString::from_vec(buf)));
+            let s = Rc::new(BString::from_vec(buf));
+            return Ok(ast::Expression::String(s, r.span(&start)));
         } else if ch == b'\\' {
             r.consume();
             if r.eof() {
-                return Err(r.err("Unexpected EOF".to_string()));
+                break;
             }
 
             let ch = match r.peek() {
@@ -155,7 +550,11 @@ fn parse_string(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
                 b'0' => b'\0',
                 b'"' => b'"',
                 b'\\' => b'\\',
-                ch => return Err(r.err(format!("Invalid escape sequence: \\{}", ch as char))),
+                ch => {
+                    r.report(r.err(format!("Invalid escape sequence: \\{}", ch as char)))?;
+                    r.consume();
+                    continue;
+                }
             };
 
             buf.push(ch);
@@ -166,16 +565,17 @@ fn parse_string(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
         }
     }
 
-    Err(r.err("Unexpected EOF".to_string()))
+    let hint = format!("unclosed string opened at {}:{}", start.line, start.column);
+    Err(r.err("Unexpected EOF".to_string()).with_hint(hint))
 }
 
 fn read_digit(ch: u8, base: u8) -> Result<u8, ()> {
     let num;
-    if (b'0'..=b'9').contains(&ch) {
+    if ch.is_ascii_digit() {
         num = ch - b'0';
-    } else if (b'a'..=b'z').contains(&ch) {
+    } else if ch.is_ascii_lowercase() {
         num = ch - b'a' + 10;
-    } else if (b'A'..=b'Z').contains(&ch) {
+    } else if ch.is_ascii_uppercase() {
         num = ch - b'A' + 10;
     } else {
         return Err(());
@@ -188,151 +588,513 @@ fn read_digit(ch: u8, base: u8) -> Result<u8, ()> {
     Ok(num)
 }
 
-fn read_int(r: &mut Reader<'_>, base: u8) -> (u64, u64) {
-    let mut int: u64 = 0;
-    let mut div: u64 = 1;
+// Read the digits of an integer, returning the value of each digit
+fn read_digits(r: &mut Reader<'_>, base: u8) -> Vec<u8> {
+    let mut digits = Vec::new();
     while !r.eof() {
-        let ch = r.peek();
-        let digit = match read_digit(ch, base) {
-            Ok(d) => d,
+        match read_digit(r.peek(), base) {
+            Ok(digit) => digits.push(digit),
             Err(..) => break,
-        };
-        div *= base as u64;
-        int *= base as u64;
-        int += digit as u64;
+        }
         r.consume();
     }
 
-    (int, div)
+    digits
 }
 
-pub fn read_number(r: &mut Reader<'_>) -> Result<f64, ParseError> {
-    let mut base = 10u8;
-    let (mut integral, _) = read_int(r, 10);
-    let mut decimal = 0.0;
-    if r.eof() {
-        return Ok(integral as f64);
+fn to_u64(digits: &[u8], base: u8) -> Option<u64> {
+    digits.iter().try_fold(0u64, |acc, digit| {
+        acc.checked_mul(base as u64)?.checked_add(*digit as u64)
+    })
+}
+
+fn to_bigint(negative: bool, digits: &[u8], base: u8) -> BigInt {
+    BigInt::from_digits(negative, digits, base as u32)
+}
+
+// An integer literal, which is an Int if it fits in an i64
+fn int_expr(num: BigInt, loc: ast::Location) -> ast::Expression {
+    match num.to_i64() {
+        Some(num) => ast::Expression::Int(num, loc),
+        None => ast::Expression::BigInt(Rc::new(num), loc),
+    }
+}
+
+// Read the exponent of a float literal, after the 'e'. Exponents are
+// always decimal, and very big exponents are saturated, since they result
+// in infinity or zero anyway.
+fn read_exponent(r: &mut Reader<'_>) -> Result<i64, ParseError> {
+    let mut negative = false;
+    if !r.eof() && (r.peek() == b'-' || r.peek() == b'+') {
+        negative = r.peek() == b'-';
+        r.consume();
+    }
+
+    let digits = read_digits(r, 10);
+    if digits.is_empty() {
+        return Err(r.err("Invalid number literal".to_string()));
+    }
+
+    let exp = digits.iter().fold(0i64, |acc, digit| {
+        (acc * 10 + *digit as i64).min(MAX_EXPONENT)
+    });
+    Ok(if negative { -exp } else { exp })
+}
+
+const MAX_EXPONENT: i64 = 1_000_000_000;
+
+// The float nearest to 'int.frac * base^exp', or None if it's too big
+// to be represented as a float
+fn to_float(int: &[u8], frac: &[u8], exp: i64, base: u8) -> Option<f64> {
+    let digits = [int, frac].concat();
+    let digits = match digits.iter().position(|digit| *digit != 0) {
+        Some(start) => &digits[start..],
+        None => return Some(0.0),
+    };
+
+    // The number is between base^(len - 1 + exp) and base^(len + exp). Check
+    // that it's roughly in the range of a float before computing it exactly.
+    let exp = exp - frac.len() as i64;
+    let log2 = (base as f64).log2();
+    if (digits.len() as i64 - 1 + exp) as f64 * log2 > 1025.0 {
+        return None;
+    } else if (digits.len() as i64 + exp) as f64 * log2 < -1080.0 {
+        return Some(0.0);
+    }
+
+    let mant = to_bigint(false, digits, base);
+    let scale = BigInt::from_u64(base as u64).pow(exp.unsigned_abs() as u32);
+    let num = if exp >= 0 {
+        mant.mul(&scale).to_f64()
+    } else {
+        mant.div_to_f64(&scale)
+    };
+
+    if num.is_infinite() {
+        None
+    } else {
+        Some(num)
     }
+}
+
+// Read a number literal. Literals with a decimal point or an exponent
+// are floats ('Number'), literals with a slash are exact fractions ('Rational'),
+// and other literals are integers ('Int', or 'BigInt' if they don't fit in 64 bits).
+// The exponent ('e' or 'E') is a power of the literal's base, so it can't be used
+// in bases where 'e' is a digit.
+// 'negative' is whether the literal was preceded by a '-'.
+pub fn read_number(r: &mut Reader<'_>, negative: bool) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
+    read_number_at(r, negative, start)
+}
+
+// Like 'read_number', but the literal's location starts at 'start'
+fn read_number_at(
+    r: &mut Reader<'_>,
+    negative: bool,
+    start: ast::Location,
+) -> Result<ast::Expression, ParseError> {
+    let mut base = 10u8;
+    let mut digits = read_digits(r, 10);
 
-    if r.peek() == b'#' {
+    if !r.eof() && r.peek() == b'#' {
         r.consume();
-        if integral > 36 {
-            return Err(r.err(format!("Number literal: Max base is 36, got {}", integral)));
+        match to_u64(&digits, 10) {
+            Some(b) if b <= 36 => base = b as u8,
+            _ => {
+                let b = to_bigint(false, &digits, 10);
+                return Err(r.err(format!("Number literal: Max base is 36, got {}", b)));
+            }
         }
 
-        base = integral as u8;
-        integral = read_int(r, base).0;
+        digits = read_digits(r, base);
     }
 
-    if r.peek() == b'.' {
+    let mut fraction = None;
+    let mut denominator = None;
+    if !r.eof() && r.peek() == b'.' {
+        r.consume();
+        fraction = Some(read_digits(r, base));
+    } else if !r.eof() && r.peek() == b'/' {
         r.consume();
-        let (i, div) = read_int(r, base);
-        decimal = (i as f64) / (div as f64);
+        denominator = Some(read_digits(r, base));
+    }
+
+    let mut exponent = None;
+    if denominator.is_none() && base < 15 && !r.eof() && (r.peek() == b'e' || r.peek() == b'E') {
+        r.consume();
+        exponent = Some(read_exponent(r)?);
     }
 
     if !r.eof() && !is_separator(r.peek()) {
         return Err(r.err("Invalid number literal".to_string()));
     }
 
-    Ok(integral as f64 + decimal)
+    if fraction.is_some() || exponent.is_some() {
+        let fraction = fraction.unwrap_or_default();
+        let num = match to_float(&digits, &fraction, exponent.unwrap_or(0), base) {
+            Some(num) => num,
+            None => return Err(r.err("Number literal: Too big for a float".to_string())),
+        };
+        let num = if negative { -num } else { num };
+        return Ok(ast::Expression::Number(num, r.span(&start)));
+    }
+
+    if let Some(denominator) = denominator {
+        if denominator.is_empty() {
+            return Err(r.err("Invalid number literal".to_string()));
+        }
+
+        let num = to_bigint(negative, &digits, base);
+        let den = to_bigint(false, &denominator, base);
+        return match Rational::new(num, den) {
+            Some(num) if num.is_integer() => Ok(int_expr(num.numer().clone(), r.span(&start))),
+            Some(num) => Ok(ast::Expression::Rational(Rc::new(num), r.span(&start))),
+            None => Err(r.err("Number literal: Division by zero".to_string())),
+        };
+    }
+
+    let int = to_u64(&digits, base).and_then(|int| {
+        if negative {
+            0i64.checked_sub_unsigned(int)
+        } else {
+            i64::try_from(int).ok()
+        }
+    });
+
+    match int {
+        Some(int) => Ok(ast::Expression::Int(int, r.span(&start))),
+        None => Ok(int_expr(to_bigint(negative, &digits, base), r.span(&start))),
+    }
 }
 
 fn parse_number(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
-    Ok(ast::Expression::Number(read_number(r)?))
+    read_number(r, false)
 }
 
-fn parse_list(r: &mut Reader<'_>, closer: u8) -> Result<Vec<ast::Expression>, ParseError> {
-    r.consume(); // Opener
+fn is_closer(ch: u8) -> bool {
+    ch == b')' || ch == b']' || ch == b'}'
+}
 
-    let mut exprs: Vec<ast::Expression> = Vec::new();
+fn opener_of(closer: u8) -> u8 {
+    match closer {
+        b')' => b'(',
+        b']' => b'[',
+        _ => b'{',
+    }
+}
+
+// Skip ahead to the closing bracket of the list which is being parsed,
+// skipping over nested lists, strings and comments
+fn skip_to_closer(r: &mut Reader<'_>) {
+    let mut depth = 0;
+    while !r.eof() {
+        match r.peek() {
+            b'(' | b'[' | b'{' => depth += 1,
+            ch if is_closer(ch) && depth == 0 => return,
+            ch if is_closer(ch) => depth -= 1,
+            b'"' => {
+                r.consume();
+                while !r.eof() && r.peek() != b'"' {
+                    if r.peek() == b'\\' {
+                        r.consume();
+                    }
+                    r.consume();
+                }
+            }
+            b';' => {
+                while !r.eof() && r.peek() != b'\n' {
+                    r.consume();
+                }
+            }
+            _ => (),
+        }
+
+        r.consume();
+    }
+}
+
+fn unclosed_hint(opener: char, open: &ast::Location) -> String {
+    format!(
+        "unclosed '{}' opened at {}:{}",
+        opener, open.line, open.column
+    )
+}
+
+// Parse the next element of the list which was opened at 'open'.
+// Returns None at the end of the list, after consuming the closing bracket.
+// In recovering mode, errors are collected and the rest of the list is skipped.
+fn parse_element(
+    r: &mut Reader<'_>,
+    open: &ast::Location,
+) -> Result<Option<ast::Expression>, ParseError> {
+    let closer = *r.closers.last().unwrap();
+    let opener = opener_of(closer) as char;
     loop {
         skip_space(r);
 
-        if r.peek() == closer {
-            r.consume();
-            break;
+        if r.eof() {
+            let hint = unclosed_hint(opener, open);
+            return Err(r.err("Unexpected EOF".to_string()).with_hint(hint));
         }
 
-        let expr = match parse(r)? {
-            Some(expr) => expr,
-            None => return Err(r.err("Unexpected EOF".to_string())),
-        };
+        let ch = r.peek();
+        if ch == closer {
+            r.consume();
+            return Ok(None);
+        } else if r.recovering && ch == b'(' && r.col == 1 {
+            // Most likely the start of the next top-level form,
+            // so this list was never closed
+            let hint = unclosed_hint(opener, open);
+            r.report(
+                r.err(format!("Expected '{}'", closer as char))
+                    .with_hint(hint),
+            )?;
+            return Ok(None);
+        } else if is_closer(ch) {
+            let hint = format!(
+                "expected '{}' to close '{}' opened at {}:{}",
+                closer as char, opener, open.line, open.column
+            );
+            r.report(
+                r.err(format!("Unexpected '{}'", ch as char))
+                    .with_hint(hint),
+            )?;
+
+            // The closing bracket of an enclosing list closes this list too,
+            // other closing brackets are skipped
+            if r.closers.contains(&ch) {
+                return Ok(None);
+            }
+            r.consume();
+            continue;
+        }
 
-        exprs.push(expr);
+        match parse(r) {
+            Ok(expr) => return Ok(expr),
+            Err(err) if r.recovering && !r.eof() => {
+                r.errors.push(err);
+                skip_to_closer(r);
+            }
+            Err(err) => return Err(err),
+        }
     }
+}
 
-    Ok(exprs)
+fn parse_list(r: &mut Reader<'_>, closer: u8) -> Result<Vec<ast::Expression>, ParseError> {
+    let open = r.loc();
+    r.consume(); // Opener
+
+    r.closers.push(closer);
+    let mut exprs: Vec<ast::Expression> = Vec::new();
+    let res = loop {
+        match parse_element(r, &open) {
+            Ok(Some(expr)) => exprs.push(expr),
+            Ok(None) => break Ok(exprs),
+            Err(err) => break Err(err),
+        }
+    };
+    r.closers.pop();
+
+    res
 }
 
 fn parse_infix(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
     r.consume(); // '['
 
-    let mut lhs = match parse(r)? {
-        Some(expr) => expr,
-        None => return Err(r.err("Unexpected EOF".into())),
-    };
+    r.closers.push(b']');
+    let res = parse_infix_operands(r, &start);
+    r.closers.pop();
 
-    loop {
-        skip_space(r);
+    res
+}
 
-        if r.peek() == b']' {
-            r.consume();
-            break;
+fn parse_infix_operands(
+    r: &mut Reader<'_>,
+    start: &ast::Location,
+) -> Result<ast::Expression, ParseError> {
+    let mut lhs = match parse_element(r, start)? {
+        Some(expr) => expr,
+        None => {
+            r.report(r.err("Empty infix expression".to_string()))?;
+            return Ok(ast::Expression::Call(Vec::new(), r.span(start)));
         }
+    };
 
-        let infix = match parse(r)? {
+    while let Some(infix) = parse_element(r, start)? {
+        let rhs = match parse_element(r, start)? {
             Some(expr) => expr,
-            None => return Err(r.err("Unexpected EOF".to_string())),
+            None => {
+                r.report(r.err(format!("Expected an operand after '{}'", infix)))?;
+                break;
+            }
         };
 
-        let rhs = match parse(r)? {
-            Some(expr) => expr,
-            None => return Err(r.err("Unexpected EOF".to_string())),
-        };
+        // The outermost call's span includes the closing bracket
+        skip_space(r);
+        let done = r.peek() == b']';
+        if done {
+            r.consume();
+        }
 
-        lhs = ast::Expression::Call(vec![infix, lhs, rhs], r.loc());
+        lhs = ast::Expression::Call(vec![infix, lhs, rhs], r.span(start));
+        if done {
+            break;
+        }
     }
 
     Ok(lhs)
 }
 
 fn parse_quote(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
     r.consume(); // '\''
     if r.peek() == b'(' {
-        Ok(ast::Expression::Block(Rc::new(parse_list(r, b')')?)))
+        let exprs = parse_list(r, b')')?;
+        Ok(ast::Expression::Block(Rc::new(exprs), r.span(&start)))
+    } else if r.string[r.idx..].starts_with(b"...") {
+        // A rest parameter, like '...args
+        r.consume();
+        r.consume();
+        r.consume();
+        let name = read_name(r)?;
+        let name = BString::from_vec([b"...", name.as_bytes()].concat());
+        Ok(ast::Expression::String(Rc::new(name), r.span(&start)))
     } else {
-        Ok(ast::Expression::String(read_name(r)?))
+        let name = read_name(r)?;
+        Ok(ast::Expression::String(Rc::new(name), r.span(&start)))
     }
 }
 
+fn parse_keyword(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
+    r.consume(); // ':'
+    let name = read_name(r)?;
+    Ok(ast::Expression::Keyword(
+        Symbol::intern(&name),
+        r.span(&start),
+    ))
+}
+
 fn parse_dash(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
+    let start = r.loc();
     r.consume(); // '-'
     let ch = r.peek();
-    if (b'0'..=b'9').contains(&ch) {
-        Ok(ast::Expression::Number(-read_number(r)?))
+    if ch.is_ascii_digit() {
+        read_number_at(r, true, start)
     } else if is_separator(ch) {
-        Ok(ast::Expression::Lookup(BString::from_str("-")))
+        Ok(ast::Expression::Lookup(
+            Symbol::from_str("-"),
+            r.span(&start),
+        ))
     } else {
         let s = read_name(r)?;
-        Ok(ast::Expression::Lookup(BString::from_vec(
-            [b"-", s.as_bytes()].concat(),
-        )))
+        if s.as_bytes() == b"inf" {
+            return Ok(ast::Expression::Number(f64::NEG_INFINITY, r.span(&start)));
+        }
+
+        Ok(ast::Expression::Lookup(
+            Symbol::intern(&BString::from_vec([b"-", s.as_bytes()].concat())),
+            r.span(&start),
+        ))
     }
 }
 
 fn parse_braced(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
-    Ok(ast::Expression::Block(Rc::new(parse_list(r, b'}')?)))
+    let start = r.loc();
+    let exprs = parse_list(r, b'}')?;
+    Ok(ast::Expression::Block(Rc::new(exprs), r.span(&start)))
 }
 
 fn parse_call(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
-    Ok(ast::Expression::Call(parse_list(r, b')')?, r.loc()))
+    let start = r.loc();
+    let exprs = parse_list(r, b')')?;
+    Ok(ast::Expression::Call(exprs, r.span(&start)))
 }
 
 fn parse_lookup(r: &mut Reader<'_>) -> Result<ast::Expression, ParseError> {
-    Ok(ast::Expression::Lookup(read_name(r)?))
+    let start = r.loc();
+    let name = read_name(r)?;
+    let loc = r.span(&start);
+    match name.as_bytes() {
+        b"inf" => Ok(ast::Expression::Number(f64::INFINITY, loc)),
+        b"nan" => Ok(ast::Expression::Number(f64::NAN, loc)),
+        _ => Ok(ast::Expression::Lookup(Symbol::intern(&name), loc)),
+    }
 }
 
 pub fn parse(r: &mut Reader<'_>) -> Result<Option<ast::Expression>, ParseError> {
+    if r.depth >= r.max_depth {
+        return Err(r.err("Maximum nesting depth exceeded".to_string()));
+    }
+
+    r.depth += 1;
+    let res = parse_expr(r);
+    r.depth -= 1;
+    res
+}
+
+// Parse all the expressions in the reader, collecting errors instead of
+// stopping at the first one. Returns the expressions which could be parsed,
+// so that tools can work with files which have errors.
+//
+// An error inside of a list skips ahead to the list's closing bracket.
+// A line which starts with a '(' is assumed to be the next top-level form,
+// so it closes any lists which are still open. A list which is still open
+// at the end of the file is dropped, and parsing resumes at the next such line.
+pub fn parse_all(r: &mut Reader<'_>) -> (Vec<ast::Expression>, Vec<ParseError>) {
+    r.recovering = true;
+
+    let mut exprs = Vec::new();
+    loop {
+        skip_space(r);
+        let (idx, line, col) = (r.idx, r.line, r.col);
+        let num_errors = r.errors.len();
+
+        let err = match parse(r) {
+            Ok(Some(expr)) => {
+                exprs.push(expr);
+                continue;
+            }
+            Ok(None) => break,
+            Err(err) => err,
+        };
+
+        if !r.eof() {
+            // Skip the rest of the token which caused the error
+            r.errors.push(err);
+            if is_separator(r.peek()) {
+                r.consume();
+            }
+            while !r.eof() && !is_separator(r.peek()) {
+                r.consume();
+            }
+            continue;
+        }
+
+        // Go back to the start of the form, and skip to the next line which starts
+        // with a '('. Errors from after that point will be found again.
+        (r.idx, r.line, r.col) = (idx, line, col);
+        r.consume();
+        while !r.eof() && (r.col != 1 || r.peek() != b'(') {
+            r.consume();
+        }
+
+        let (line, col) = (r.line, r.col);
+        let mut errors = r.errors.split_off(num_errors);
+        errors.retain(|err| r.eof() || (err.line, err.col) < (line, col));
+        r.errors.append(&mut errors);
+        r.errors.push(err);
+    }
+
+    r.recovering = false;
+    let mut errors = mem::take(&mut r.errors);
+    errors.sort_by_key(|err| (err.line, err.col));
+    (exprs, errors)
+}
+
+fn parse_expr(r: &mut Reader<'_>) -> Result<Option<ast::Expression>, ParseError> {
     skip_space(r);
 
     if r.eof() {
@@ -342,12 +1104,14 @@ pub fn parse(r: &mut Reader<'_>) -> Result<Option<ast::Expression>, ParseError>
     let ch = r.peek();
     let mut base = if ch == b'"' {
         parse_string(r)?
-    } else if (b'0'..=b'9').contains(&ch) {
+    } else if ch.is_ascii_digit() {
         parse_number(r)?
     } else if ch == b'-' {
         parse_dash(r)?
     } else if ch == b'\'' {
         parse_quote(r)?
+    } else if ch == b':' {
+        parse_keyword(r)?
     } else if ch == b'(' {
         parse_call(r)?
     } else if ch == b'{' {
@@ -369,19 +1133,20 @@ pub fn parse(r: &mut Reader<'_>) -> Result<Option<ast::Expression>, ParseError>
             r.consume();
             skip_space(r);
             let ch = r.peek();
-            if (b'0'..=b'9').contains(&ch) {
-                let num = parse_number(r)?;
-                base = ast::Expression::Call(vec![base, num], r.loc());
+            let arg = if ch.is_ascii_digit() {
+                parse_number(r)?
             } else if ch == b'[' {
-                let arg = parse_infix(r)?;
-                base = ast::Expression::Call(vec![base, arg], r.loc());
+                parse_infix(r)?
             } else if ch == b'(' {
-                let arg = parse_call(r)?;
-                base = ast::Expression::Call(vec![base, arg], r.loc());
+                parse_call(r)?
             } else {
+                let start = r.loc();
                 let name = read_name(r)?;
-                base = ast::Expression::Call(vec![base, ast::Expression::String(name)], r.loc());
-            }
+                ast::Expression::String(Rc::new(name), r.span(&start))
+            };
+
+            let loc = r.span(base.loc());
+            base = ast::Expression::Property(Box::new(base), Box::new(arg), loc);
         } else {
             break;
         }
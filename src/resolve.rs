@@ -0,0 +1,134 @@
+use super::ast::{Expression, Location};
+use super::bstring::BString;
+use super::symbol::Symbol;
+
+use std::rc::Rc;
+
+// Lexical addressing: lookups of lambda parameters (and 'self' and 'caller-scope')
+// are resolved to the slot they occupy in the lambda's call frame,
+// so that they don't have to be looked up by name at runtime.
+//
+// Lambdas are recognized by their syntax, '(lambda 'a 'b {...})' or
+// '(func 'name 'a 'b {...})', where parameters can also be '...rest' strings or
+// '{name default...}' blocks. Default blocks are resolved in the lambda's frame.
+// Since 'lambda' and 'func' might have been redefined,
+// and since blocks run in whatever scope they're called from, the resolved
+// slots are only hints; 'Scope::lookup_local' checks that they're still valid,
+// and falls back to looking the name up otherwise.
+
+enum Env {
+    // The call frame of a lambda, with the names of its slots
+    Frame(Vec<Symbol>),
+    Block,
+}
+
+// The names of the slots in a lambda's call frame
+pub fn frame_names(params: &[Symbol]) -> Vec<Symbol> {
+    let mut names = Vec::with_capacity(params.len() + 2);
+    names.extend_from_slice(params);
+    names.push(Symbol::from_str("self"));
+    names.push(Symbol::from_str("caller-scope"));
+    names
+}
+
+// If the call is a lambda or func form, get its parameters
+fn lambda_params(exprs: &[Expression]) -> Option<Vec<Symbol>> {
+    let (head, rest) = exprs.split_first()?;
+    let skip = match head {
+        Expression::Lookup(name, _) if *name == Symbol::from_str("lambda") => 0,
+        Expression::Lookup(name, _) if *name == Symbol::from_str("func") => 1,
+        _ => return None,
+    };
+
+    let (body, params) = rest.split_last()?;
+    if !matches!(body, Expression::Block(..)) || params.len() < skip {
+        return None;
+    }
+
+    let mut names = Vec::with_capacity(params.len() - skip);
+    let mut rest = None;
+    for param in &params[skip..] {
+        match param {
+            Expression::String(name, _) => match name.as_bytes().strip_prefix(b"...") {
+                Some(name) => rest = Some(Symbol::intern(&BString::from_bytes(name))),
+                None => names.push(Symbol::intern(name)),
+            },
+            Expression::Block(exprs, _) => match exprs.first() {
+                Some(Expression::Lookup(name, _)) | Some(Expression::Local(name, ..)) => {
+                    names.push(*name)
+                }
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+
+    names.extend(rest);
+    Some(names)
+}
+
+fn resolve_lookup(name: Symbol, loc: &Location, envs: &[Env]) -> Expression {
+    let mut frames = 0;
+    for env in envs.iter().rev() {
+        if let Env::Frame(names) = env {
+            if let Some(slot) = names.iter().rposition(|n| *n == name) {
+                return Expression::Local(name, frames, slot as u32, loc.clone());
+            }
+
+            frames += 1;
+        }
+    }
+
+    Expression::Lookup(name, loc.clone())
+}
+
+fn resolve_block(
+    exprs: &[Expression],
+    loc: &Location,
+    env: Env,
+    envs: &mut Vec<Env>,
+) -> Expression {
+    envs.push(env);
+    let exprs = exprs.iter().map(|e| resolve_expr(e, envs)).collect();
+    envs.pop();
+    Expression::Block(Rc::new(exprs), loc.clone())
+}
+
+fn resolve_expr(expr: &Expression, envs: &mut Vec<Env>) -> Expression {
+    match expr {
+        Expression::String(s, loc) => Expression::String(s.clone(), loc.clone()),
+        Expression::Number(num, loc) => Expression::Number(*num, loc.clone()),
+        Expression::Int(num, loc) => Expression::Int(*num, loc.clone()),
+        Expression::BigInt(num, loc) => Expression::BigInt(num.clone(), loc.clone()),
+        Expression::Rational(num, loc) => Expression::Rational(num.clone(), loc.clone()),
+        Expression::Lookup(name, loc) | Expression::Local(name, _, _, loc) => {
+            resolve_lookup(*name, loc, envs)
+        }
+        Expression::Keyword(name, loc) => Expression::Keyword(*name, loc.clone()),
+        Expression::Call(exprs, loc) => {
+            let params = lambda_params(exprs);
+            let mut resolved = Vec::with_capacity(exprs.len());
+            for e in exprs {
+                match (e, &params) {
+                    (Expression::Block(body, loc), Some(params)) => {
+                        let env = Env::Frame(frame_names(params));
+                        resolved.push(resolve_block(body, loc, env, envs));
+                    }
+                    _ => resolved.push(resolve_expr(e, envs)),
+                }
+            }
+
+            Expression::Call(resolved, loc.clone())
+        }
+        Expression::Block(exprs, loc) => resolve_block(exprs, loc, Env::Block, envs),
+        Expression::Property(val, key, loc) => Expression::Property(
+            Box::new(resolve_expr(val, envs)),
+            Box::new(resolve_expr(key, envs)),
+            loc.clone(),
+        ),
+    }
+}
+
+pub fn resolve(expr: &Expression) -> Expression {
+    resolve_expr(expr, &mut Vec::new())
+}
@@ -1,9 +1,16 @@
+use super::ast;
+use super::bignum::Rational;
 use super::bstring::BString;
-use super::eval::{self, FuncArgs, FuncResult, PortVal, Scope, StackTrace, ValRef};
+use super::eval::{
+    self, Abort, DictVal, FuncArgs, FuncResult, LoopStep, PortVal, Scope, Signal, StackTrace,
+    Unwind, ValRef,
+};
+use super::number;
 use super::parse;
+use super::symbol::Symbol;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp::Ordering;
 use std::io;
 use std::iter;
 use std::mem;
@@ -11,52 +18,60 @@ use std::rc::Rc;
 use std::vec;
 
 /*
-@(print (arg:any)*) -> none
+@(print (arg:any)* (:sep sep:any)? (:end end:any)?) -> none
 
-Print the arguments to 'stdout', separated by a space.
+Print the arguments to 'stdout', separated by 'sep', which defaults to a space,
+and followed by 'end', which defaults to a newline.
+For example, (print "a" "b" :sep ", " :end "") prints "a, b".
 */
 fn lib_print(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    let args = args.drain(0..);
+    let mut args = args.drain(0..);
+    args.check_named(&["sep", "end"])?;
+
+    let space = match args.named("sep") {
+        Some(sep) => sep,
+        None => ValRef::String(Rc::new(BString::from_str(" "))),
+    };
+    let end = match args.named("end") {
+        Some(end) => end,
+        None => ValRef::String(Rc::new(BString::from_str("\n"))),
+    };
 
-    let stdout = match scope.lookup(&BString::from_str("stdout")) {
+    let stdout = match scope.lookup(Symbol::from_str("stdout")) {
         Some(stdout) => match stdout {
             ValRef::Port(port) => port,
-            _ => {
-                return Err(StackTrace::from_str(
-                    "'print' expects 'stdout' to be a port",
-                ))
-            }
+            _ => return Err(StackTrace::from_str("'print' expects 'stdout' to be a port").into()),
         },
         None => {
-            return Err(StackTrace::from_str(
-                "'print' expects a variable 'stdout' to be defined",
-            ))
+            return Err(
+                StackTrace::from_str("'print' expects a variable 'stdout' to be defined").into(),
+            )
         }
     };
 
     let mut out = stdout.borrow_mut();
 
-    let space = ValRef::String(Rc::new(BString::from_str(" ")));
     let mut first = true;
-    for arg in args {
+    while args.has_next() {
+        let arg = args.next_val()?;
         if !first {
             match out.write(&space) {
                 Ok(_) => (),
-                Err(err) => return Err(StackTrace::from_string(err)),
+                Err(err) => return Err(StackTrace::from_string(err).into()),
             };
         }
 
         match out.write(&arg) {
             Ok(_) => (),
-            Err(err) => return Err(StackTrace::from_string(err)),
+            Err(err) => return Err(StackTrace::from_string(err).into()),
         };
 
         first = false;
     }
 
-    match out.write(&ValRef::String(Rc::new(BString::from_str("\n")))) {
+    match out.write(&end) {
         Ok(_) => (),
-        Err(err) => return Err(StackTrace::from_string(err)),
+        Err(err) => return Err(StackTrace::from_string(err).into()),
     }
 
     Ok((ValRef::None, scope))
@@ -82,46 +97,72 @@ fn lib_not(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
 /*
 @(mod a:number b:number) -> number
 
-Returns 'a' modulo 'b'.
+Returns 'a' modulo 'b'. The result has the same sign as 'a'.
+If both numbers are exact, the result is exact.
 
 Examples:
 (mod 11 3) -> 2
 [12 mod 3] -> 0
 (mod 9 2) -> 1
 (mod 8 2) -> 0
+(mod -7 2) -> -1
+(mod 7.5 2) -> 1.5
+(mod 7/2 1) -> 1/2
 */
 fn lib_mod(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
 
-    let a = args.next_val()?.get_number()?;
-    let b = args.next_val()?.get_number()?;
+    let a = args.next_val()?;
+    let b = args.next_val()?;
     args.done()?;
-    Ok((ValRef::Number(a % b), scope))
+
+    if !a.is_number() || !b.is_number() {
+        return Err(StackTrace::from_str("Expected number").into());
+    }
+
+    let res = number::binary(number::Op::Rem, &a, &b)?;
+    scope.context().alloc_number(&res)?;
+    Ok((res, scope))
+}
+
+// Combine the arguments from left to right, promoting them as necessary;
+// see the 'number' module.
+fn arithmetic(args: Vec<ValRef>, op: number::Op, scope: &Scope) -> Result<ValRef, StackTrace> {
+    let mut args = args.into_iter();
+    let mut acc = match args.next() {
+        Some(val) if val.is_number() => val,
+        Some(val) => ValRef::Number(val.to_num()),
+        None => ValRef::Int(0),
+    };
+
+    for item in args {
+        acc = number::binary(op, &acc, &item)?;
+        scope.context().alloc_number(&acc)?;
+    }
+
+    Ok(acc)
 }
 
 /*
 @(+ (val:number)*) -> number
 
 Returns all the numbers added together.
+If all the numbers are exact (integers or rationals), the result is exact.
+Integers never overflow, they're promoted to big integers.
 
 Examples:
 (+ 10 20) -> 30
 (+ 33) -> 33
 [10 + 30] -> 40
 (+ 1 2 3 4 5) -> 15
+(+ 1 2.5) -> 3.5
+(+ 1/3 1/6) -> 1/2
+(+ 9223372036854775807 1) -> 9223372036854775808
 (+) -> 0
 */
 fn lib_add(args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    if args.is_empty() {
-        return Ok((ValRef::Number(0.0), scope));
-    }
-
-    let mut num = args[0].to_num();
-    for item in args.into_iter().skip(1) {
-        num += item.to_num();
-    }
-
-    Ok((ValRef::Number(num), scope))
+    eval::check_named(&args, &[])?;
+    Ok((arithmetic(args, number::Op::Add, &scope)?, scope))
 }
 
 /*
@@ -129,52 +170,46 @@ fn lib_add(args: Vec<ValRef>, scope: Scope) -> FuncResult {
 
 Returns all subsequent numbers subtracted from the first number.
 If there's only one argument, return the negative of that number.
+If all the numbers are exact (integers or rationals), the result is exact.
 
 Examples:
 (- 10) -> -10
 (- 10 3) -> 7
 [10 - 4] -> 6
 (- 10 2 3) -> 5
+(- 10 0.5) -> 9.5
+(- 1 1/3) -> 2/3
 (-) -> 0
 */
 fn lib_sub(args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    if args.is_empty() {
-        return Ok((ValRef::Number(0.0), scope));
-    } else if args.len() == 1 {
-        return Ok((ValRef::Number(-args[0].to_num()), scope));
-    }
-
-    let mut num = args[0].to_num();
-    for item in args.into_iter().skip(1) {
-        num -= item.to_num();
+    eval::check_named(&args, &[])?;
+    if args.len() == 1 {
+        let res = number::negate(&args[0]);
+        scope.context().alloc_number(&res)?;
+        return Ok((res, scope));
     }
 
-    Ok((ValRef::Number(num), scope))
+    Ok((arithmetic(args, number::Op::Sub, &scope)?, scope))
 }
 
 /*
 @(* (val:number)*) -> number
 
 Returns all numbers multiplied by each other.
+If all the numbers are exact (integers or rationals), the result is exact.
 
 Examples:
 (* 10) -> 10
 [10 * 5] -> 50
 (* 10 3) -> 30
 (* 10 2 3) -> 60
+(* 10 0.5) -> 5
+(* 2/3 3) -> 2
 (*) -> 0
 */
 fn lib_mul(args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    if args.is_empty() {
-        return Ok((ValRef::Number(0.0), scope));
-    }
-
-    let mut num = args[0].to_num();
-    for item in args.into_iter().skip(1) {
-        num *= item.to_num();
-    }
-
-    Ok((ValRef::Number(num), scope))
+    eval::check_named(&args, &[])?;
+    Ok((arithmetic(args, number::Op::Mul, &scope)?, scope))
 }
 
 /*
@@ -182,27 +217,56 @@ fn lib_mul(args: Vec<ValRef>, scope: Scope) -> FuncResult {
 
 Returns all subsequent numbers divided from the first one.
 If there's only one argument, return the reciprocal of that number.
+Dividing integers produces a float. Division is only exact
+if one of the numbers is a rational and none of them are floats.
+Use 'exact/' to divide integers exactly.
 
 Examples:
 (/ 10) -> 0.1
 (/ 10 2) -> 5
 (/ 30 3 2) -> 5
 [200 / 10] -> 20
+(/ 7 2) -> 3.5
+(/ 1/2 3) -> 1/6
+(/ 2/3) -> 3/2
 (/) -> 0
 */
 fn lib_div(args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    if args.is_empty() {
-        return Ok((ValRef::Number(0.0), scope));
-    } else if args.len() == 1 {
-        return Ok((ValRef::Number(1.0 / args[0].to_num()), scope));
-    }
+    eval::check_named(&args, &[])?;
+    let res = match args.len() {
+        0 => return Ok((ValRef::Number(0.0), scope)),
+        1 => number::divide(&[ValRef::Int(1), args[0].clone()])?,
+        _ => number::divide(&args)?,
+    };
 
-    let mut num = args[0].to_num();
-    for item in args.into_iter().skip(1) {
-        num /= item.to_num();
-    }
+    scope.context().alloc_number(&res)?;
+    Ok((res, scope))
+}
+
+/*
+@(exact/ (val:number)*) -> number
+
+Like '/', but if all the numbers are exact (integers or rationals),
+the result is exact: an integer if it divides evenly, and a rational otherwise.
+
+Examples:
+(exact/ 7 2) -> 7/2
+[7 exact/ 2] -> 7/2
+(exact/ 10 2) -> 5
+(exact/ 10) -> 1/10
+(exact/ 7.0 2) -> 3.5
+(exact/) -> 0
+*/
+fn lib_exact_div(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
+    let res = match args.len() {
+        0 => return Ok((ValRef::Int(0), scope)),
+        1 => number::divide_exact(&[ValRef::Int(1), args[0].clone()])?,
+        _ => number::divide_exact(&args)?,
+    };
 
-    Ok((ValRef::Number(num), scope))
+    scope.context().alloc_number(&res)?;
+    Ok((res, scope))
 }
 
 /*
@@ -226,6 +290,7 @@ Examples:
 (== (list 1 2 3) (list 1 2 4)) -> false
 */
 fn lib_equals(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.len() <= 1 {
         return Ok((ValRef::Bool(true), scope));
     }
@@ -253,6 +318,7 @@ Examples:
 (!=) -> false
 */
 fn lib_nequals(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     match lib_equals(args, scope) {
         Ok((ValRef::Bool(true), s)) => Ok((ValRef::Bool(false), s)),
         Ok((ValRef::Bool(false), s)) => Ok((ValRef::Bool(true), s)),
@@ -275,12 +341,14 @@ Examples:
 (<=) -> true
 */
 fn lib_lteq(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
         return Ok((ValRef::Bool(true), scope));
     }
 
     for idx in 0..args.len() - 1 {
-        if args[idx].to_num() > args[idx + 1].to_num() {
+        let ord = ValRef::compare_numbers(&args[idx], &args[idx + 1]);
+        if matches!(ord, Some(Ordering::Greater)) {
             return Ok((ValRef::Bool(false), scope));
         }
     }
@@ -303,12 +371,14 @@ Examples:
 (<) -> true
 */
 fn lib_lt(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
         return Ok((ValRef::Bool(true), scope));
     }
 
     for idx in 0..args.len() - 1 {
-        if args[idx].to_num() >= args[idx + 1].to_num() {
+        let ord = ValRef::compare_numbers(&args[idx], &args[idx + 1]);
+        if matches!(ord, Some(Ordering::Greater | Ordering::Equal)) {
             return Ok((ValRef::Bool(false), scope));
         }
     }
@@ -331,12 +401,14 @@ Examples:
 (>=) -> true
 */
 fn lib_gteq(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
         return Ok((ValRef::Bool(true), scope));
     }
 
     for idx in 0..args.len() - 1 {
-        if args[idx].to_num() < args[idx + 1].to_num() {
+        let ord = ValRef::compare_numbers(&args[idx], &args[idx + 1]);
+        if matches!(ord, Some(Ordering::Less)) {
             return Ok((ValRef::Bool(false), scope));
         }
     }
@@ -359,12 +431,14 @@ Examples:
 (>) -> true
 */
 fn lib_gt(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
         return Ok((ValRef::Bool(true), scope));
     }
 
     for idx in 0..args.len() - 1 {
-        if args[idx].to_num() <= args[idx + 1].to_num() {
+        let ord = ValRef::compare_numbers(&args[idx], &args[idx + 1]);
+        if matches!(ord, Some(Ordering::Less | Ordering::Equal)) {
             return Ok((ValRef::Bool(false), scope));
         }
     }
@@ -386,6 +460,7 @@ Examples:
 (||) -> false
 */
 fn lib_or(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     for item in args {
         if item.to_bool() {
             return Ok((ValRef::Bool(true), scope));
@@ -410,6 +485,7 @@ Examples:
 (&&) -> true
 */
 fn lib_and(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     for item in args {
         if !item.to_bool() {
             return Ok((ValRef::Bool(false), scope));
@@ -432,6 +508,7 @@ Examples:
 (??) -> none
 */
 fn lib_first(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     for arg in args.drain(0..) {
         match arg {
             ValRef::None => (),
@@ -446,6 +523,8 @@ fn lib_first(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
 @(def (name:string value:any)*) -> none
 
 Defines the given values in the current scope.
+A lambda which was just created, and isn't named yet, is named after
+the variable, so errors from it can refer to it by name.
 
 Examples:
 (def 'x 10) -> none
@@ -456,20 +535,78 @@ x -> 10
 */
 fn lib_def(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     while args.has_next() {
         let key = args.next_val()?.get_string()?;
-        let val = args.next_val()?;
-        scope = scope.insert(key.as_ref().clone(), val);
+        let mut val = args.next_val()?;
+        let key = scope.context().intern(&key)?;
+
+        // A new lambda is named after the variable, for errors
+        if let ValRef::Lambda(l) = &mut val {
+            if let Some(l) = Rc::get_mut(l) {
+                l.name.get_or_insert(key);
+            }
+        }
+
+        scope = scope.insert(key, val);
     }
 
     Ok((ValRef::None, scope))
 }
 
+// Create a lambda from the parameters and body given to 'lambda' or 'func'
+fn make_lambda(args: vec::Drain<ValRef>, scope: &Scope) -> Result<eval::LambdaVal, StackTrace> {
+    let mut args: Vec<ValRef> = args.collect();
+    let body = match args.pop() {
+        Some(ValRef::Block(body)) => body,
+        _ => return Err(StackTrace::from_str("Expected block")),
+    };
+
+    let mut params = eval::Params::default();
+    for arg in args {
+        if params.rest.is_some() {
+            return Err(StackTrace::from_str("The rest parameter must be the last parameter"));
+        }
+
+        match arg {
+            ValRef::String(s) => {
+                if let Some(name) = s.as_bytes().strip_prefix(b"...") {
+                    params.rest = Some(scope.context().intern(&BString::from_bytes(name))?);
+                    continue;
+                }
+
+                // Optional parameters can only be followed by other optional parameters
+                if let Some(Some(..)) = params.defaults.last() {
+                    return Err(StackTrace::from_string(format!(
+                        "Parameter '{}' needs a default, since it follows an optional parameter",
+                        s
+                    )));
+                }
+
+                params.names.push(scope.context().intern(&s)?);
+                params.defaults.push(None);
+            }
+            ValRef::Block(b) => match b.first() {
+                Some(ast::Expression::Lookup(name, _))
+                | Some(ast::Expression::Local(name, ..)) => {
+                    params.names.push(*name);
+                    params.defaults.push(Some(b.clone()));
+                }
+                _ => return Err(StackTrace::from_str("Expected parameter name in block")),
+            },
+            _ => return Err(StackTrace::from_str("Expected string or block")),
+        }
+    }
+
+    Ok(eval::LambdaVal::with_params(params, body, scope.clone()))
+}
+
 /*
-@(func name:string (arg:string)* body:block) -> none
+@(func name:string (param:string|block)* body:block) -> none
 
 Defines a lambda with the given name and parameters in the current scope.
+Parameters are the same as for 'lambda'.
 
 Examples:
 (func 'square 'x {
@@ -486,37 +623,14 @@ Examples:
 */
 fn lib_func(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     let name = args.next_val()?.get_string()?;
+    let name = scope.context().intern(&name)?;
+    let mut lambda = make_lambda(args, &scope)?;
+    lambda.name = Some(name);
 
-    let mut argnames: Vec<BString> = Vec::new();
-    let mut block = None;
-    for arg in args.by_ref() {
-        match arg {
-            ValRef::String(s) => argnames.push(s.as_ref().clone()),
-            ValRef::Block(b) => {
-                block = Some(b);
-                break;
-            }
-            _ => {
-                return Err(StackTrace::from_str("Expected string or block"));
-            }
-        }
-    }
-
-    args.done()?;
-    let block = match block {
-        Some(block) => block,
-        None => return Err(StackTrace::from_str("Expected block")),
-    };
-
-    let val = ValRef::Lambda(Rc::new(eval::LambdaVal {
-        args: argnames,
-        body: block,
-        scope: scope.clone(),
-    }));
-    scope = scope.insert(name.as_ref().clone(), val);
-
+    scope = scope.insert(name, ValRef::Lambda(Rc::new(lambda)));
     Ok((ValRef::None, scope))
 }
 
@@ -533,19 +647,23 @@ x -> 50
 */
 fn lib_set(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     while args.has_next() {
         let key = args.next_val()?.get_string()?;
         let val = args.next_val()?;
 
-        if !scope.has_shallow(key.as_ref()) {
-            return Err(StackTrace::from_string(format!(
-                "Variable '{}' doesn't exist",
-                key
-            )));
-        }
+        // A name which has never been interned can't be the name of a variable
+        let sym = match Symbol::get(&key) {
+            Some(sym) if scope.has_shallow(sym) => sym,
+            _ => {
+                return Err(
+                    StackTrace::from_string(format!("Variable '{}' doesn't exist", key)).into(),
+                )
+            }
+        };
 
-        scope = scope.insert(key.as_ref().clone(), val);
+        scope = scope.insert(sym, val);
     }
 
     Ok((ValRef::None, scope))
@@ -575,21 +693,23 @@ x -> 15
 */
 fn lib_mutate(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     if args.len() < 2 {
-        return Err(StackTrace::from_str("Not enough arguments"));
+        return Err(StackTrace::from_str("Not enough arguments").into());
     }
 
     let name = args[0].clone().get_string()?;
-    let val = match scope.lookup_shallow(name.as_ref()) {
-        Some(val) => val,
+    let lookup = |sym| Some((sym, scope.lookup_shallow(sym)?));
+    let (sym, val) = match Symbol::get(&name).and_then(lookup) {
+        Some(found) => found,
         None => {
             return Err(StackTrace::from_string(format!(
                 "Variable '{}' doesn't exist in this scope",
                 name
-            )))
+            ))
+            .into())
         }
     };
 
-    scope.maybe_inplace_erase(name.as_ref());
+    scope.maybe_inplace_erase(sym);
 
     // Replace the name and the mutator function with the value to be
     // passed as the first argument, so that we can re-use the args array
@@ -598,7 +718,7 @@ fn lib_mutate(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
 
     let new_val;
     (new_val, scope) = eval::call(&func, args, scope)?;
-    scope = scope.insert(name.as_ref().clone(), new_val.clone());
+    scope = scope.insert(sym, new_val.clone());
     Ok((new_val, scope))
 }
 
@@ -627,9 +747,9 @@ fn lib_if(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     args.done()?;
 
     if cond {
-        eval::call(&if_body, vec![], scope)
+        eval::tail_call(if_body, vec![], scope)
     } else if let Some(else_body) = else_body {
-        eval::call(&else_body, vec![], scope)
+        eval::tail_call(else_body, vec![], scope)
     } else {
         Ok((ValRef::None, scope))
     }
@@ -657,18 +777,19 @@ Examples:
 */
 fn lib_case(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     while args.has_next() {
         let block = args.next_val()?.get_block()?;
 
-        if block.len() < 1 {
-            return Err(StackTrace::from_str("Blocks must have at least 1 element"));
+        if block.is_empty() {
+            return Err(StackTrace::from_str("Blocks must have at least 1 element").into());
         }
 
         let val;
-        (val, _) = eval::eval(&block[0], scope.clone())?;
+        (val, _) = eval::eval_block_range(&block, 0..1, false, scope.clone())?;
         if val.to_bool() {
-            let (res, _) = eval::eval_multiple(&block[1..], scope.clone())?;
+            let (res, _) = eval::eval_block_range(&block, 1..block.len(), true, scope.clone())?;
             return Ok((res, scope));
         }
     }
@@ -676,6 +797,189 @@ fn lib_case(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     Ok((ValRef::None, scope))
 }
 
+/*
+@(match (cond:func body:func)* (default:func)?) -> any
+
+Call each condition function in turn. When one returns something truthy,
+call the body function which follows it and return its return value.
+If no condition is truthy, call the default function if it exists.
+
+Examples:
+(def 'x 55)
+(match {[x > 10]} {"x is greater than 10"}
+       {[x < 10]} {"x is smaller than 10"}
+       {"x is 10"}) -> "x is greater than 10"
+
+(match {false} {10} {20}) -> 20
+(match {false} {10}) -> none
+(match) -> none
+*/
+fn lib_match(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    args.check_named(&[])?;
+
+    while args.has_next() {
+        let cond = args.next_val()?;
+        let body = match args.next() {
+            Some(body) => body,
+            None => return eval::tail_call(cond, vec![], scope),
+        };
+
+        let val;
+        (val, _) = eval::call(&cond, vec![], scope.clone())?;
+        if val.to_bool() {
+            return eval::tail_call(body, vec![], scope);
+        }
+    }
+
+    Ok((ValRef::None, scope))
+}
+
+// Blocks are evaluated directly in the given scope rather than in a subscope,
+// so that a loop body can 'set' variables which are defined outside of the loop.
+fn call_inline(func: &ValRef, scope: Scope) -> FuncResult {
+    match func {
+        ValRef::Block(b) => eval::eval_block(b, scope),
+        _ => eval::call(func, vec![], scope),
+    }
+}
+
+/*
+@(while cond:func (body:func)?) -> any
+
+Call the condition function. If it returns something truthy, call the body
+if it exists, then loop. If it returns something falsy, return the last thing
+the body function returned, or none.
+
+Blocks are run in the current scope, so the body can modify variables
+which were defined outside of the loop, and variables which the body
+defines with 'def' are still defined after the loop.
+
+Examples:
+(def 'index 0)
+(def 'sum 1)
+(while {[index < 4]} {
+    (set 'sum [sum * 2])
+    (set 'index [index + 1])
+    sum
+}) -> 16
+sum -> 16
+index -> 4
+
+(while {false}) -> none
+
+(def 'n 0)
+(while {true} {
+    (set 'n [n + 1])
+    (if [n == 3] {(break "done")})
+}) -> "done"
+n -> 3
+*/
+fn lib_while(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+
+    let cond = args.next_val()?;
+    let body = args.next();
+    args.done()?;
+
+    let mut retval = ValRef::None;
+    loop {
+        let val;
+        (val, scope) = call_inline(&cond, scope)?;
+        if !val.to_bool() {
+            return Ok((retval, scope));
+        }
+
+        if let Some(body) = &body {
+            drop(retval);
+            match eval::loop_step(call_inline(body, scope))? {
+                LoopStep::Next(val, s) => (retval, scope) = (val, s),
+                LoopStep::Break(val, s) => return Ok((val, s)),
+            }
+        }
+    }
+}
+
+/*
+@(break value:any?) -> error
+
+Stop the innermost loop, like 'while' or 'list-for', which then returns
+the value, or none. The loop is found lexically: a 'break' in the body
+of a lambda can only stop a loop in that body, or the 'list-for', 'list-map'
+or 'list-reduce' which the lambda is the callback of.
+Using 'break' outside of a loop is an error.
+
+Examples:
+(def 'l (list 3 8 -1 4))
+(list-for l (lambda 'x {
+    (if [x < 0] {(break x)})
+})) -> -1
+
+(while {true} {(break)}) -> none
+*/
+fn lib_break(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let val = args.next().unwrap_or(ValRef::None);
+    args.done()?;
+
+    Err(Unwind::signal(Signal::Break(val, scope)))
+}
+
+/*
+@(continue value:any?) -> error
+
+End the current iteration of the innermost loop, with the value, or none,
+as the result of the iteration. For 'list-map', the value is the new element,
+and for 'list-reduce', it's the new accumulator.
+Like with 'break', the loop is found lexically.
+Using 'continue' outside of a loop is an error.
+
+Examples:
+(list-map (list 1 2 3 4) (lambda 'x {
+    (if [x > 2] {(continue 0)})
+    [x * 10]
+})) -> (list 10 20 0 0)
+
+(list-reduce (list 1 2 3 4) 0 (lambda 'x 'sum {
+    (if [x == 2] {(continue sum)})
+    [sum + x]
+})) -> 8
+*/
+fn lib_continue(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let val = args.next().unwrap_or(ValRef::None);
+    args.done()?;
+
+    Err(Unwind::signal(Signal::Continue(val, scope)))
+}
+
+/*
+@(return value:any?) -> error
+
+Return from the innermost lambda, with the value, or none.
+Loops which are running in the lambda are stopped.
+Using 'return' outside of a lambda is an error.
+
+Examples:
+(def 'index-of (lambda 'l 'x {
+    (def 'idx 0)
+    (while {[idx < (list-len l)]} {
+        (if [(l idx) == x] {(return idx)})
+        (set 'idx [idx + 1])
+    })
+    none
+}))
+(index-of (list 5 6 7) 6) -> 1
+(index-of (list 5 6 7) 8) -> none
+*/
+fn lib_return(mut args: Vec<ValRef>, _: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let val = args.next().unwrap_or(ValRef::None);
+    args.done()?;
+
+    Err(Unwind::signal(Signal::Return(val)))
+}
+
 /*
 @(do (args:any)*) -> any
 
@@ -691,6 +995,7 @@ Examples:
 (do (def 'x 10) [x + 5]) -> 15
 */
 fn lib_do(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if let Some(val) = args.pop() {
         Ok((val, scope))
     } else {
@@ -698,6 +1003,89 @@ fn lib_do(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     }
 }
 
+/*
+@(bind values:list (name:string)* body:block) -> any
+
+Run the body with each value of the list bound to the corresponding name.
+Names without a corresponding value are bound to 'none'.
+
+Examples:
+(bind (list 10 20) 'a 'b {
+    [a + b]
+}) -> 30
+
+(bind (list 1 2 3) 'first {
+    first
+}) -> 1
+
+(bind (list "hello") 'x 'y {
+    y
+}) -> none
+*/
+fn lib_bind(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let body = match args.pop() {
+        Some(body) => body.get_block()?,
+        None => return Err(StackTrace::from_str("Not enough parameters").into()),
+    };
+
+    let mut args = args.drain(0..);
+    args.check_named(&[])?;
+    let lst = args.next_val()?.get_list()?;
+
+    let mut subscope = scope.subscope();
+    for (idx, name) in args.enumerate() {
+        let name = name.get_string()?;
+        let val = match lst.borrow().get(idx) {
+            Some(val) => val.clone(),
+            None => ValRef::None,
+        };
+
+        subscope = subscope.insert(scope.context().intern(&name)?, val);
+    }
+
+    match eval::eval_block(&body, subscope) {
+        Ok((res, _)) => Ok((res, scope)),
+        Err(err) => Err(err.resume_in(&scope)),
+    }
+}
+
+/*
+@(with (name:string value:any)* body:block) -> any
+
+Run the body with the given values bound to the given names.
+The names are only visible to the body.
+
+Examples:
+(with 'num [[100 * 3] + [10 * 2]] {
+    [num + 5]
+}) -> 325
+
+(with 'x 10 'y 20 {
+    [x + y]
+}) -> 30
+*/
+fn lib_with(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let body = match args.pop() {
+        Some(body) => body.get_block()?,
+        None => return Err(StackTrace::from_str("Not enough parameters").into()),
+    };
+
+    let mut args = args.drain(0..);
+    args.check_named(&[])?;
+
+    let mut subscope = scope.subscope();
+    while args.has_next() {
+        let key = args.next_val()?.get_string()?;
+        let val = args.next_val()?;
+        subscope = subscope.insert(scope.context().intern(&key)?, val);
+    }
+
+    match eval::eval_block(&body, subscope) {
+        Ok((res, _)) => Ok((res, scope)),
+        Err(err) => Err(err.resume_in(&scope)),
+    }
+}
+
 /*
 @(read port:port size:number?) -> any
 
@@ -709,16 +1097,22 @@ fn lib_read(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let port = args.next_val()?.get_port()?;
 
     let res = if args.has_next() {
-        let size = args.next_val()?.get_number()?;
+        let size = get_index(args.next_val()?)?;
         args.done()?;
-        port.borrow_mut().read_chunk(size as usize)
+        port.borrow_mut().read_chunk(size)
     } else {
         port.borrow_mut().read()
     };
 
     match res {
-        Ok(val) => Ok((val, scope)),
-        Err(err) => Err(StackTrace::from_string(err)),
+        Ok(val) => {
+            if let ValRef::String(s) = &val {
+                scope.context().alloc(s, eval::string_size(s.as_bytes().len()))?;
+            }
+
+            Ok((val, scope))
+        }
+        Err(err) => Err(StackTrace::from_string(err).into()),
     }
 }
 
@@ -737,7 +1131,7 @@ fn lib_write(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let res = port.borrow_mut().write(&val);
     match res {
         Ok(_) => Ok((ValRef::None, scope)),
-        Err(err) => Err(StackTrace::from_string(err)),
+        Err(err) => Err(StackTrace::from_string(err).into()),
     }
 }
 
@@ -753,16 +1147,17 @@ fn lib_seek(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
 
     let port = args.next_val()?.get_port()?;
-    let num = args.next_val()?.get_number()?;
+    let num = args.next_val()?.get_int()?;
     let pos = if args.has_next() {
         match args.next_val()?.get_string()?.as_bytes() {
             b"set" => io::SeekFrom::Start(num as u64),
-            b"end" => io::SeekFrom::End(num as i64),
-            b"current" => io::SeekFrom::Current(num as i64),
+            b"end" => io::SeekFrom::End(num),
+            b"current" => io::SeekFrom::Current(num),
             _ => {
                 return Err(StackTrace::from_str(
                     "'seek' requires the seek offset to be 'set', 'end' or 'current'",
-                ))
+                )
+                .into())
             }
         }
     } else {
@@ -774,7 +1169,7 @@ fn lib_seek(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let res = port.borrow_mut().seek(pos);
     match res {
         Ok(_) => Ok((ValRef::None, scope)),
-        Err(err) => Err(StackTrace::from_string(err)),
+        Err(err) => Err(StackTrace::from_string(err).into()),
     }
 }
 
@@ -787,11 +1182,12 @@ Create an error. An error contains a value:
 * If 'error' is called with multiple arguments, they are concatenated together
   and the value is the resulting string.
 */
-fn lib_error(args: Vec<ValRef>, _: Scope) -> FuncResult {
+fn lib_error(args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
-        Err(StackTrace::from_val(ValRef::None))
+        Err(StackTrace::raised(ValRef::None).into())
     } else if args.len() == 1 {
-        Err(StackTrace::from_val(args[0].clone()))
+        Err(StackTrace::raised(args[0].clone()).into())
     } else {
         let mut vec = Vec::new();
 
@@ -806,9 +1202,32 @@ fn lib_error(args: Vec<ValRef>, _: Scope) -> FuncResult {
             }
         }
 
-        Err(StackTrace::from_val(ValRef::String(Rc::new(
-            BString::from_vec(vec),
-        ))))
+        let size = eval::string_size(vec.len());
+        let s = Rc::new(BString::from_vec(vec));
+        scope.context().alloc(&s, size)?;
+        Err(StackTrace::raised(ValRef::String(s)).into())
+    }
+}
+
+/*
+@(exit status:number?) -> error
+
+Stop evaluating the script, with an exit status which defaults to 0.
+When the script is run by the osyris binary, the process exits with that status.
+Exiting aborts evaluation, so it can't be caught with 'try'.
+*/
+fn lib_exit(mut args: Vec<ValRef>, _: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let status = if args.has_next() {
+        args.next_val()?.get_int()?
+    } else {
+        0
+    };
+    args.done()?;
+
+    match i32::try_from(status) {
+        Ok(status) => Err(StackTrace::from_abort(Abort::Exit(status)).into()),
+        Err(..) => Err(StackTrace::from_str("Exit status is out of range").into()),
     }
 }
 
@@ -816,6 +1235,7 @@ fn lib_error(args: Vec<ValRef>, _: Scope) -> FuncResult {
 @(try body:func catch:func) -> any
 
 Call 'body'. If it returns an error, call 'catch' with that error's value as an argument.
+Errors which abort evaluation, such as running out of fuel, can't be caught.
 
 Examples:
 (try {
@@ -834,7 +1254,10 @@ fn lib_try(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
 
     match eval::call(&try_body, Vec::new(), scope.subscope()) {
         Ok(res) => Ok(res),
-        Err(err) => eval::call(&catch_body, vec![err.message], scope),
+        Err(err) if err.is_catchable() => {
+            eval::call(&catch_body, vec![err.into_error().message], scope)
+        }
+        Err(err) => Err(err.resume_in(&scope)),
     }
 }
 
@@ -860,6 +1283,36 @@ fn lib_bool(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     }
 }
 
+// Parse a number literal from a string
+fn parse_number(s: &BString) -> Result<ValRef, StackTrace> {
+    let filename = BString::from_str("string");
+    let (negative, bytes) = match s.as_bytes() {
+        [b'-', rest @ ..] => (true, rest),
+        bytes => (false, bytes),
+    };
+
+    match bytes {
+        b"inf" if negative => return Ok(ValRef::Number(f64::NEG_INFINITY)),
+        b"inf" => return Ok(ValRef::Number(f64::INFINITY)),
+        b"nan" => return Ok(ValRef::Number(f64::NAN)),
+        _ => (),
+    }
+
+    let mut r = parse::Reader::new(bytes, filename);
+    let num = match parse::read_number(&mut r, negative) {
+        Ok(num) => num,
+        Err(err) => return Err(StackTrace::from_string(err.msg)),
+    };
+
+    match num {
+        ast::Expression::Int(num, _) if r.eof() => Ok(ValRef::Int(num)),
+        ast::Expression::BigInt(num, _) if r.eof() => Ok(ValRef::BigInt(num)),
+        ast::Expression::Rational(num, _) if r.eof() => Ok(ValRef::Rational(num)),
+        ast::Expression::Number(num, _) if r.eof() => Ok(ValRef::Number(num)),
+        _ => Err(StackTrace::from_str("Invalid number literal")),
+    }
+}
+
 /*
 @(number value:any) -> number
 
@@ -867,9 +1320,13 @@ Convert the argument to a number.
 
 Examples:
 (number 10) -> 10
-(number false) -> 0
-(number true) -> 1
+(number false) -> 0.0
+(number true) -> 1.0
 (number "20") -> 20
+(number "-2.5") -> -2.5
+(number "1/3") -> 1/3
+(number "1.5e3") -> 1500.0
+(number "-inf") -> -inf
 */
 fn lib_number(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
@@ -877,19 +1334,126 @@ fn lib_number(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     args.done()?;
 
     match arg {
-        ValRef::Number(..) => Ok((arg, scope)),
+        _ if arg.is_number() => Ok((arg, scope)),
         ValRef::String(s) => {
-            let filename = BString::from_str("string");
-            let mut r = parse::Reader::new(s.as_bytes(), filename);
-            match parse::read_number(&mut r) {
-                Ok(num) => Ok((ValRef::Number(num), scope)),
-                Err(err) => Err(StackTrace::from_string(err.msg)),
-            }
+            let num = parse_number(&s)?;
+            scope.context().alloc_number(&num)?;
+            Ok((num, scope))
+        }
+        _ => Ok((ValRef::Number(arg.to_num()), scope)),
+    }
+}
+
+/*
+@(int value:any) -> int
+
+Convert the argument to an integer.
+Floats and rationals are rounded towards zero, and strings are parsed.
+
+Examples:
+(int 10) -> 10
+(int 2.7) -> 2
+(int -2.7) -> -2
+(int 7/2) -> 3
+(int "16#ff") -> 255
+(int 100000000000000000000.0) -> 100000000000000000000
+(int true) -> 1
+*/
+fn lib_int(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let arg = args.next_val()?;
+    args.done()?;
+
+    let num = match arg {
+        ValRef::String(s) => parse_number(&s)?,
+        _ => arg,
+    };
+
+    match number::trunc(&num) {
+        Some(num) => {
+            scope.context().alloc_number(&num)?;
+            Ok((num, scope))
         }
+        None => Err(StackTrace::from_string(format!(
+            "Number {} can't be converted to an integer",
+            num
+        ))
+        .into()),
+    }
+}
+
+/*
+@(float value:any) -> number
+
+Convert the argument to a float.
+Numbers which can't be represented exactly are rounded.
+
+Examples:
+(float 10) -> 10.0
+(float 2.5) -> 2.5
+(float 1/4) -> 0.25
+(float "3") -> 3.0
+(float false) -> 0.0
+*/
+fn lib_float(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+    let arg = args.next_val()?;
+    args.done()?;
+
+    match arg {
+        ValRef::Number(..) => Ok((arg, scope)),
+        ValRef::String(s) => Ok((ValRef::Number(parse_number(&s)?.to_num()), scope)),
         _ => Ok((ValRef::Number(arg.to_num()), scope)),
     }
 }
 
+/*
+@(rational num:number den:number?) -> number
+
+Convert a number to an exact number, or create the exact fraction 'num'/'den'.
+Floats are converted to the exact value they represent, and strings are parsed.
+
+Examples:
+(rational 0.5) -> 1/2
+(rational 1 3) -> 1/3
+(rational 6 4) -> 3/2
+(rational 1/2 2) -> 1/4
+(rational "2/6") -> 1/3
+*/
+fn lib_rational(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    fn get_rational(val: ValRef) -> Result<Rational, StackTrace> {
+        let num = match val {
+            ValRef::String(s) => parse_number(&s)?,
+            num if num.is_number() => num,
+            _ => return Err(StackTrace::from_str("Expected number")),
+        };
+
+        match number::to_rational(&num) {
+            Some(num) => Ok(num),
+            None => Err(StackTrace::from_string(format!(
+                "Number {} can't be converted to a rational",
+                num
+            ))),
+        }
+    }
+
+    let mut args = args.drain(0..);
+
+    let mut num = get_rational(args.next_val()?)?;
+    if args.has_next() {
+        let den = get_rational(args.next_val()?)?;
+        num = match num.div(&den) {
+            Some(num) => num,
+            None => return Err(StackTrace::from_str("Division by zero").into()),
+        };
+    }
+    args.done()?;
+
+    let num = number::from_rational(num);
+    scope.context().alloc_number(&num)?;
+    Ok((num, scope))
+}
+
 /*
 @(string (value:any)*) -> string
 
@@ -904,6 +1468,7 @@ Examples:
 (string [3 + 5] " things") -> "8 things"
 */
 fn lib_string(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    eval::check_named(&args, &[])?;
     if args.is_empty() {
         return Ok((ValRef::String(Rc::new(BString::from_str(""))), scope));
     }
@@ -922,7 +1487,11 @@ fn lib_string(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
         }
     }
 
-    Ok((ValRef::String(Rc::new(BString::from_vec(buf))), scope))
+    let size = eval::string_size(buf.len());
+    let s = Rc::new(BString::from_vec(buf));
+    scope.context().alloc(&s, size)?;
+
+    Ok((ValRef::String(s), scope))
 }
 
 /*
@@ -949,11 +1518,25 @@ fn lib_lazy(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
 }
 
 /*
-@(lambda (param:string)* body:block) -> lambda
+@(lambda (param:string|block)* body:block) -> lambda
 
 Create a lambda, which is like a block, but which creates
 its own scope when called and which has named arguments.
 
+A parameter can be a name, a block with a name followed by a default value,
+or a name which starts with '...', which gets the remaining arguments
+as a list. Defaults are evaluated when the lambda is called without
+the argument, and can refer to the parameters before them.
+A parameter with a default can only be followed by others with defaults
+and the '...' parameter.
+
+Arguments can also be passed by name, with keyword arguments like ':y 20'.
+
+In the body, 'self' is the lambda itself and 'caller-scope' is the scope
+it was called from, even when it's called as the last expression of another
+function. That keeps the caller's frame alive, so a long chain of such calls
+uses memory for each call.
+
 Examples:
 (def 'add (lambda 'x 'y {
     [x + y]
@@ -961,39 +1544,68 @@ Examples:
 (add 10 20) -> 30
 (add 5 7) -> 12
 [9 add 10] -> 19
+(add :y 2 :x 1) -> 3
+
+(def 'greet (lambda 'name {greeting "Hello"} {
+    (string greeting ", " name)
+}))
+(greet "Bob") -> "Hello, Bob"
+(greet "Bob" "Hi") -> "Hi, Bob"
+
+(def 'count (lambda 'first '...rest {
+    [1 + (list-len rest)]
+}))
+(count 10 20 30) -> 3
 */
 fn lib_lambda(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    let mut args = args.drain(0..);
+    eval::check_named(&args, &[])?;
+    let lambda = make_lambda(args.drain(0..), &scope)?;
+    Ok((ValRef::Lambda(Rc::new(lambda)), scope))
+}
 
-    let mut argnames: Vec<BString> = Vec::new();
-    let mut block = None;
-    for arg in args.by_ref() {
-        match arg {
-            ValRef::String(s) => argnames.push(s.as_ref().clone()),
-            ValRef::Block(b) => {
-                block = Some(b);
-                break;
-            }
-            _ => {
-                return Err(StackTrace::from_str("Expected string or block"));
-            }
-        }
-    }
+/*
+@(strict l:lambda) -> lambda
 
-    args.done()?;
-    let block = match block {
-        Some(block) => block,
-        None => return Err(StackTrace::from_str("Expected block")),
+Returns a copy of the lambda which raises an error when it's called
+with too few or too many arguments. Otherwise, parameters without an
+argument are left undefined, and extra arguments are ignored.
+
+Examples:
+(func 'add 'a 'b {[a + b]})
+(def 'strict-add (strict add))
+(strict-add 1 2) -> 3
+(try {(strict-add 1 2 3)} (lambda 'err {err})) -> "Lambda 'add' expects 2 arguments, but got 3"
+
+(def 'sub (strict (lambda 'a 'b {[a - b]})))
+(try {(sub 1)} (lambda 'err {err})) -> "Lambda 'sub' expects 2 arguments, but got 1"
+*/
+fn lib_strict(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
+    let mut args = args.drain(0..);
+
+    let lambda = match args.next_val()? {
+        ValRef::Lambda(l) => l,
+        _ => return Err(StackTrace::from_str("Expected lambda").into()),
     };
+    args.done()?;
 
-    Ok((
-        ValRef::Lambda(Rc::new(eval::LambdaVal {
-            args: argnames,
-            body: block.clone(),
-            scope: scope.clone(),
-        })),
-        scope,
-    ))
+    let mut lambda = lambda.as_ref().clone();
+    lambda.strict = true;
+    Ok((ValRef::Lambda(Rc::new(lambda)), scope))
+}
+
+// Get a list which can be modified in place, copying it if it's shared
+fn unshare_list(
+    lst: Rc<RefCell<Vec<ValRef>>>,
+    scope: &Scope,
+) -> Result<Rc<RefCell<Vec<ValRef>>>, StackTrace> {
+    if Rc::strong_count(&lst) == 1 {
+        Ok(lst)
+    } else {
+        let size = eval::list_size(lst.borrow().len());
+        let copy = Rc::new((*lst).clone());
+        scope.context().alloc(&copy, size)?;
+        Ok(copy)
+    }
 }
 
 /*
@@ -1012,14 +1624,18 @@ Examples:
 (l 1) -> 20
 (l 2) -> none
 
-; This is an alternate function call syntax
+; The dot syntax gets properties, which are the elements of lists
 l.0 -> 10
 l.1 -> 20
 l.[0 + 1] -> 20
 l.(+ 0 1) -> 20
 */
 fn lib_list(args: Vec<ValRef>, scope: Scope) -> FuncResult {
-    Ok((ValRef::List(Rc::new(RefCell::new(args))), scope))
+    eval::check_named(&args, &[])?;
+    let size = eval::list_size(args.len());
+    let lst = Rc::new(RefCell::new(args));
+    scope.context().alloc(&lst, size)?;
+    Ok((ValRef::List(lst), scope))
 }
 
 /*
@@ -1036,14 +1652,12 @@ l -> (list 10 30 40)
 */
 fn lib_list_push(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     let lst = args.next_val()?.get_list()?;
 
-    let lst = if Rc::strong_count(&lst) == 1 {
-        lst
-    } else {
-        Rc::new((*lst).clone())
-    };
+    let lst = unshare_list(lst, &scope)?;
+    scope.context().alloc(&lst, args.len() * mem::size_of::<ValRef>())?;
 
     let mut lstmut = lst.borrow_mut();
     for val in args {
@@ -1071,11 +1685,7 @@ fn lib_list_pop(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let lst = args.next_val()?.get_list()?;
     args.done()?;
 
-    let lst = if Rc::strong_count(&lst) == 1 {
-        lst
-    } else {
-        Rc::new((*lst).clone())
-    };
+    let lst = unshare_list(lst, &scope)?;
 
     lst.borrow_mut().pop();
     Ok((ValRef::List(lst), scope))
@@ -1097,19 +1707,17 @@ l -> (list 10 1 99 100 2 3)
 */
 fn lib_list_insert(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     let lst = args.next_val()?.get_list()?;
-    let idx = args.next_val()?.get_number()? as usize;
+    let idx = get_index(args.next_val()?)?;
 
     if idx >= lst.borrow().len() {
-        return Err(StackTrace::from_str("Index out of bounds"));
+        return Err(StackTrace::from_str("Index out of bounds").into());
     }
 
-    let lst = if Rc::strong_count(&lst) == 1 {
-        lst
-    } else {
-        Rc::new((*lst).clone())
-    };
+    let lst = unshare_list(lst, &scope)?;
+    scope.context().alloc(&lst, args.len() * mem::size_of::<ValRef>())?;
 
     lst.borrow_mut().splice(idx..idx, args);
     Ok((ValRef::List(lst), scope))
@@ -1136,23 +1744,20 @@ l -> (list 1 4)
 */
 fn lib_list_remove(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
     let lst = args.next_val()?.get_list()?;
-    let idx = args.next_val()?.get_number()? as usize;
+    let idx = get_index(args.next_val()?)?;
     let end = match args.next() {
         None => idx + 1,
-        Some(x) => x.get_number()? as usize,
+        Some(x) => get_index(x)?,
     };
 
-    if idx >= lst.borrow().len() {
-        return Err(StackTrace::from_str("Index out of bounds"));
+    if idx >= lst.borrow().len() || end < idx || end > lst.borrow().len() {
+        return Err(StackTrace::from_str("Index out of bounds").into());
     }
 
-    let lst = if Rc::strong_count(&lst) == 1 {
-        lst
-    } else {
-        Rc::new((*lst).clone())
-    };
+    let lst = unshare_list(lst, &scope)?;
 
     lst.borrow_mut().splice(idx..end, iter::empty());
     Ok((ValRef::List(lst), scope))
@@ -1180,26 +1785,31 @@ fn lib_list_map(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
         let mut lstmut = lst.borrow_mut();
         for idx in 0..lstmut.len() {
             let val = mem::replace(&mut lstmut[idx], ValRef::None);
-            let vec = vec![val, ValRef::Number(idx as f64)];
-            let res;
-            (res, scope) = eval::call(&func, vec, scope)?;
-            lstmut[idx] = res;
+            let vec = vec![val, ValRef::Int(idx as i64)];
+            match eval::loop_step(eval::call_loop_body(&func, vec, scope))? {
+                LoopStep::Next(res, s) => (lstmut[idx], scope) = (res, s),
+                LoopStep::Break(val, s) => return Ok((val, s)),
+            }
         }
 
         drop(lstmut);
         Ok((ValRef::List(lst), scope))
     } else {
         let lst = lst.borrow();
-        let mut lstmut: Vec<ValRef> = Vec::new();
-        lstmut.reserve(lst.len());
+        let res = Rc::new(RefCell::new(Vec::with_capacity(lst.len())));
+        scope.context().alloc(&res, eval::list_size(lst.len()))?;
         for idx in 0..lst.len() {
-            let vec = vec![lst[idx].clone(), ValRef::Number(idx as f64)];
-            let res;
-            (res, scope) = eval::call(&func, vec, scope)?;
-            lstmut.push(res);
+            let vec = vec![lst[idx].clone(), ValRef::Int(idx as i64)];
+            match eval::loop_step(eval::call_loop_body(&func, vec, scope))? {
+                LoopStep::Next(val, s) => {
+                    res.borrow_mut().push(val);
+                    scope = s;
+                }
+                LoopStep::Break(val, s) => return Ok((val, s)),
+            }
         }
 
-        Ok((ValRef::List(Rc::new(RefCell::new(lstmut))), scope))
+        Ok((ValRef::List(res), scope))
     }
 }
 
@@ -1247,7 +1857,11 @@ fn lib_list_for(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     let mut retval = ValRef::None;
     for idx in 0..lst.borrow().len() {
         drop(retval);
-        (retval, scope) = eval::call(&func, vec![lst.borrow()[idx].clone()], scope)?;
+        let res = eval::call_loop_body(&func, vec![lst.borrow()[idx].clone()], scope);
+        match eval::loop_step(res)? {
+            LoopStep::Next(val, s) => (retval, scope) = (val, s),
+            LoopStep::Break(val, s) => return Ok((val, s)),
+        }
     }
 
     Ok((retval, scope))
@@ -1274,7 +1888,11 @@ fn lib_list_reduce(mut args: Vec<ValRef>, mut scope: Scope) -> FuncResult {
     args.done()?;
 
     for idx in 0..lst.borrow().len() {
-        (acc, scope) = eval::call(&func, vec![lst.borrow()[idx].clone(), acc], scope)?;
+        let res = eval::call_loop_body(&func, vec![lst.borrow()[idx].clone(), acc], scope);
+        match eval::loop_step(res)? {
+            LoopStep::Next(val, s) => (acc, scope) = (val, s),
+            LoopStep::Break(val, s) => return Ok((val, s)),
+        }
     }
 
     Ok((acc, scope))
@@ -1294,7 +1912,30 @@ fn lib_list_len(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let lst = args.next_val()?.get_list()?;
     args.done()?;
     let lst = lst.borrow();
-    Ok((ValRef::Number(lst.len() as f64), scope))
+    Ok((ValRef::Int(lst.len() as i64), scope))
+}
+
+// Get a non-negative integer, such as an index into a list
+fn get_index(val: ValRef) -> Result<usize, StackTrace> {
+    match usize::try_from(val.get_int()?) {
+        Ok(idx) => Ok(idx),
+        Err(..) => Err(StackTrace::from_str("Index out of bounds")),
+    }
+}
+
+// Get a dict which can be modified in place, copying it if it's shared
+fn unshare_dict(
+    dict: Rc<RefCell<DictVal>>,
+    scope: &Scope,
+) -> Result<Rc<RefCell<DictVal>>, StackTrace> {
+    if Rc::strong_count(&dict) == 1 {
+        Ok(dict)
+    } else {
+        let size = eval::dict_size(&dict.borrow());
+        let copy = Rc::new((*dict).clone());
+        scope.context().alloc(&copy, size)?;
+        Ok(copy)
+    }
 }
 
 /*
@@ -1315,21 +1956,26 @@ Examples:
 (d 'y) -> 20
 (d 'z) -> none
 
-; This is an alternate function call syntax
+; The dot syntax gets properties, which are the values of dicts
 d.x -> 10
 d.y -> 20
 */
 fn lib_dict(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
+    args.check_named(&[])?;
 
-    let mut dict: HashMap<BString, ValRef> = HashMap::new();
+    let mut dict = DictVal::default();
     while args.has_next() {
         let key = args.next_val()?.get_string()?;
         let val = args.next_val()?;
         dict.insert(key.as_ref().clone(), val.clone());
     }
 
-    Ok((ValRef::Dict(Rc::new(RefCell::new(dict))), scope))
+    let size = eval::dict_size(&dict);
+    let dict = Rc::new(RefCell::new(dict));
+    scope.context().alloc(&dict, size)?;
+
+    Ok((ValRef::Dict(dict), scope))
 }
 
 /*
@@ -1337,6 +1983,9 @@ fn lib_dict(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
 
 Returns a new dict with the new keys and values.
 
+If given a native object instead of a dict, the properties are set
+on the object itself, and the object is returned.
+
 Examples:
 (def 'd (dict 'x 10 'y 20))
 d -> (dict 'x 10 'y 20)
@@ -1345,19 +1994,34 @@ d -> (dict 'x 30 'y 20)
 */
 fn lib_dict_set(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let mut args = args.drain(0..);
-    let dict = args.next_val()?.get_dict()?;
+    args.check_named(&[])?;
+    let dict = args.next_val()?;
+
+    if let ValRef::Native(obj) = &dict {
+        while args.has_next() {
+            let key = args.next_val()?.get_string()?;
+            let val = args.next_val()?;
+            if let Err(err) = obj.set(key.as_ref(), val) {
+                return Err(StackTrace::from_string(err).into());
+            }
+        }
 
-    let dict = if Rc::strong_count(&dict) == 1 {
-        dict
-    } else {
-        Rc::new((*dict).clone())
-    };
+        return Ok((dict, scope));
+    }
+
+    let dict = dict.get_dict()?;
+
+    let dict = unshare_dict(dict, &scope)?;
 
     let mut dictmut = dict.borrow_mut();
     while args.has_next() {
         let key = args.next_val()?.get_string()?;
         let val = args.next_val()?;
 
+        if !dictmut.contains_key(key.as_ref()) {
+            scope.context().alloc(&dict, eval::dict_entry_size(&key))?;
+        }
+
         dictmut.insert(key.as_ref().clone(), val.clone());
     }
 
@@ -1395,7 +2059,7 @@ d.x -> 7
 */
 fn lib_dict_mutate(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     if args.len() < 3 {
-        return Err(StackTrace::from_str("Not enough arguments"));
+        return Err(StackTrace::from_str("Not enough arguments").into());
     }
 
     let mut it = args.drain(0..2);
@@ -1403,19 +2067,14 @@ fn lib_dict_mutate(mut args: Vec<ValRef>, scope: Scope) -> FuncResult {
     let name = it.next_val()?.get_string()?;
     drop(it);
 
-    let dict = if Rc::strong_count(&dict) == 1 {
-        dict
-    } else {
-        Rc::new((*dict).clone())
-    };
+    let dict = unshare_dict(dict, &scope)?;
 
     let val = match dict.borrow_mut().remove(name.as_ref()) {
         Some(val) => val,
         None => {
-            return Err(StackTrace::from_string(format!(
-                "Variable '{}' doesn't exist",
-                name
-            )))
+            return Err(
+                StackTrace::from_string(format!("Variable '{}' doesn't exist", name)).into(),
+            )
         }
     };
 
@@ -1450,6 +2109,7 @@ pub fn init_with_stdio(mut s: Scope, stdio: StdIo) -> Scope {
     s = s.put_func("-", Rc::new(lib_sub));
     s = s.put_func("*", Rc::new(lib_mul));
     s = s.put_func("/", Rc::new(lib_div));
+    s = s.put_func("exact/", Rc::new(lib_exact_div));
     s = s.put_func("==", Rc::new(lib_equals));
     s = s.put_func("!=", Rc::new(lib_nequals));
     s = s.put_func("<=", Rc::new(lib_lteq));
@@ -1467,7 +2127,14 @@ pub fn init_with_stdio(mut s: Scope, stdio: StdIo) -> Scope {
 
     s = s.put_func("if", Rc::new(lib_if));
     s = s.put_func("case", Rc::new(lib_case));
+    s = s.put_func("match", Rc::new(lib_match));
+    s = s.put_func("while", Rc::new(lib_while));
+    s = s.put_func("break", Rc::new(lib_break));
+    s = s.put_func("continue", Rc::new(lib_continue));
+    s = s.put_func("return", Rc::new(lib_return));
     s = s.put_func("do", Rc::new(lib_do));
+    s = s.put_func("bind", Rc::new(lib_bind));
+    s = s.put_func("with", Rc::new(lib_with));
 
     s = s.put_func("read", Rc::new(lib_read));
     s = s.put_func("write", Rc::new(lib_write));
@@ -1475,12 +2142,17 @@ pub fn init_with_stdio(mut s: Scope, stdio: StdIo) -> Scope {
 
     s = s.put_func("error", Rc::new(lib_error));
     s = s.put_func("try", Rc::new(lib_try));
+    s = s.put_func("exit", Rc::new(lib_exit));
 
     s = s.put_func("number", Rc::new(lib_number));
+    s = s.put_func("int", Rc::new(lib_int));
+    s = s.put_func("float", Rc::new(lib_float));
+    s = s.put_func("rational", Rc::new(lib_rational));
     s = s.put_func("bool", Rc::new(lib_bool));
     s = s.put_func("string", Rc::new(lib_string));
 
     s = s.put_func("lambda", Rc::new(lib_lambda));
+    s = s.put_func("strict", Rc::new(lib_strict));
 
     s = s.put_func("lazy", Rc::new(lib_lazy));
 
@@ -0,0 +1,111 @@
+use super::bstring::BString;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+
+// An interned name. Symbols are cheap to copy, compare and hash,
+// and are used as the keys of scopes.
+//
+// The symbol table is per thread, and names are never freed once interned.
+// Symbols shouldn't be sent between threads. Names are interned by the parser,
+// and by 'Context::intern' when a script defines a variable; names which are
+// only looked up use 'Symbol::get', so that they aren't interned.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+struct SymbolTable {
+    ids: HashMap<&'static BString, Symbol>,
+    names: Vec<&'static BString>,
+}
+
+thread_local! {
+    static TABLE: RefCell<SymbolTable> = RefCell::new(SymbolTable {
+        ids: HashMap::new(),
+        names: Vec::new(),
+    });
+}
+
+impl Symbol {
+    pub fn intern(name: &BString) -> Self {
+        TABLE.with(|table| {
+            let mut table = table.borrow_mut();
+            if let Some(sym) = table.ids.get(name) {
+                return *sym;
+            }
+
+            let name: &'static BString = Box::leak(Box::new(name.clone()));
+            let sym = Symbol(table.names.len() as u32);
+            table.names.push(name);
+            table.ids.insert(name, sym);
+            sym
+        })
+    }
+
+    // Get the symbol for a name without interning it. A name which has never
+    // been interned can't be the key of any scope or dict.
+    pub fn get(name: &BString) -> Option<Self> {
+        TABLE.with(|table| table.borrow().ids.get(name).copied())
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(name: &str) -> Self {
+        Self::intern(&BString::from_str(name))
+    }
+
+    pub fn as_bstring(self) -> &'static BString {
+        TABLE.with(|table| table.borrow().names[self.0 as usize])
+    }
+
+    pub fn id(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<&BString> for Symbol {
+    fn from(name: &BString) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Self::from_str(name)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_bstring())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_bstring())
+    }
+}
+
+// Symbols are sequential numbers, so they don't need a strong hash function.
+// They're multiplied by a large odd constant to spread them over the whole range.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
@@ -0,0 +1,695 @@
+use super::ast;
+use super::bytecode::{self, Chunk, Op};
+use super::eval::{
+    self, Context, FuncResult, Owner, Scope, StackTrace, TailCallVal, Unwind, ValRef,
+};
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem;
+use std::ops::Range;
+use std::rc::{Rc, Weak};
+
+// The VM runs bytecode with a stack of call frames of its own. A call to a lambda
+// or a block pushes a frame for its body instead of recursing, and a tail call
+// replaces the frame of the body it's in. Arguments are evaluated onto a value stack
+// which all of the frames share, and a lambda's arguments are moved from there
+// into the slots of its call frame, which the compiler resolves its parameters to.
+// Natives are called like in the tree-walking evaluator, so lambdas and blocks
+// which natives call, like the body of a 'while', are run by another 'run'.
+
+// Compiled code for blocks, keyed by the address of the block's AST.
+// The Weak reference keeps the allocation alive, so the address can't be reused
+// by another block while the entry exists. Entries for blocks which have been
+// freed are pruned whenever the cache has doubled in size.
+type Block = Vec<ast::Expression>;
+
+pub struct CodeCache {
+    map: HashMap<*const Block, (Weak<Block>, Rc<Chunk>), BuildHasherDefault<AddrHasher>>,
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 64;
+
+// The cache is looked up for every call to a block, and addresses don't need
+// a strong hash function. The low bits of an address are always the same,
+// so the high bits of the product are folded into them.
+#[derive(Default)]
+struct AddrHasher(u64);
+
+impl Hasher for AddrHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        let hash = (n as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        self.0 = hash ^ (hash >> 32);
+    }
+}
+
+impl CodeCache {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::default(),
+            prune_at: MIN_PRUNE_AT,
+        }
+    }
+
+    pub fn get(&mut self, block: &Rc<Block>) -> Rc<Chunk> {
+        if let Some((_, chunk)) = self.map.get(&Rc::as_ptr(block)) {
+            return chunk.clone();
+        }
+
+        if self.map.len() >= self.prune_at {
+            self.map.retain(|_, (weak, _)| weak.strong_count() > 0);
+            self.prune_at = (self.map.len() * 2).max(MIN_PRUNE_AT);
+        }
+
+        let chunk = Rc::new(bytecode::compile(&block[..]));
+        let entry = (Rc::downgrade(block), chunk.clone());
+        self.map.insert(Rc::as_ptr(block), entry);
+        chunk
+    }
+}
+
+impl Default for CodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Push the call sites which were being evaluated at 'pc' to the stack trace
+fn with_sites(chunk: &Chunk, mut err: Unwind, pc: usize) -> Unwind {
+    for site in chunk.sites_at(pc) {
+        err = err.push(site.location.clone(), site.name.clone());
+    }
+
+    err
+}
+
+// An error from the op at 'pc', which records the location of the op's expression
+fn error_at(chunk: &Chunk, err: impl Into<Unwind>, pc: usize) -> Unwind {
+    with_sites(chunk, err.into().at(&chunk.locations[pc]), pc)
+}
+
+// The call expression which made a tail call, for errors. Tail calls made by
+// the VM refer to the site in their chunk, rather than copying its name.
+enum TailSite {
+    Op(Rc<Chunk>, usize),
+    Val(Box<(ast::Location, String)>),
+}
+
+impl TailSite {
+    fn push(&self, err: Unwind) -> Unwind {
+        let (location, name) = match self {
+            TailSite::Op(chunk, idx) => (&chunk.sites[*idx].location, &chunk.sites[*idx].name),
+            TailSite::Val(site) => (&site.0, &site.1),
+        };
+
+        err.at(location).push(location.clone(), name.clone())
+    }
+}
+
+// A call expression which is being run, like by 'eval::call_chain'. The first
+// step calls the function of the call expression, and each tail call is another
+// step. A step which runs the body of a lambda or block has a frame, which
+// carries the call until the step is done.
+struct Call {
+    owner: Owner,
+    // The depth of the call expression, which every step is run at
+    depth: usize,
+    // The scope a step after the first was called in,
+    // and the site of the tail call which made it
+    step_scope: Option<Scope>,
+    site: Option<TailSite>,
+    resolves: usize,
+    last_scope: Option<Scope>,
+}
+
+impl Call {
+    fn new(owner: Owner, depth: usize) -> Self {
+        Self {
+            owner,
+            depth,
+            step_scope: None,
+            site: None,
+            resolves: 0,
+            last_scope: None,
+        }
+    }
+
+    // Count a step which returned, see 'eval::call_chain'
+    fn step_done(&mut self) {
+        if let Some(scope) = self.step_scope.take() {
+            if self.site.is_some() {
+                self.resolves += 1;
+            }
+            self.last_scope = Some(scope);
+        }
+    }
+
+    // Resolve the value the last step returned
+    fn finish(self, mut val: ValRef) -> Result<ValRef, Unwind> {
+        if let Some(mut scope) = self.last_scope {
+            for _ in 0..self.resolves {
+                if !matches!(val, ValRef::Lazy(..) | ValRef::ProtectedLazy(..)) {
+                    break;
+                }
+
+                (val, scope) = eval::resolve(val, scope)?;
+            }
+        }
+
+        Ok(val)
+    }
+
+    // Handle an error from the current step. 'scope' is the caller's.
+    fn unwind(self, err: Unwind, scope: &Scope) -> Result<ValRef, Unwind> {
+        if self.step_scope.is_none() && self.owner == Owner::Caller {
+            return Err(err);
+        }
+
+        match (eval::unwind_step(err, self.owner, scope), self.site) {
+            (Ok(val), _) => Ok(val),
+            (Err(err), Some(site)) => Err(site.push(err)),
+            (Err(err), None) => Err(err),
+        }
+    }
+}
+
+// The code 'run' was called with, or the body of a lambda or block.
+// Ops are run from 'pc' to 'end', the end of the top-level expression 'idx'.
+struct Frame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    end: usize,
+    idx: usize,
+    stop: usize,
+    // Whether a call in the last expression is a tail call,
+    // and whether the current expression is that call
+    tail: bool,
+    is_tail: bool,
+    // The depth the frame's expressions are counted from
+    base: usize,
+    // The height of the value stack below the frame
+    sp: usize,
+    // The call the frame is a step of, unless it's the frame 'run' was called with
+    call: Option<Call>,
+}
+
+impl Frame {
+    fn new(chunk: Rc<Chunk>, range: Range<usize>, tail: bool, base: usize, sp: usize) -> Self {
+        let mut frame = Self {
+            chunk,
+            pc: 0,
+            end: 0,
+            idx: range.start,
+            stop: range.end,
+            tail,
+            is_tail: false,
+            base,
+            sp,
+            call: None,
+        };
+        frame.start();
+        frame
+    }
+
+    // Start running the top-level expression 'idx'
+    fn start(&mut self) {
+        if self.idx >= self.stop {
+            return;
+        }
+
+        self.pc = self.chunk.starts[self.idx];
+        self.end = self.chunk.starts[self.idx + 1];
+
+        // Like in the tree-walking evaluator, a call in tail position
+        // doesn't consume fuel for the call expression itself
+        self.is_tail =
+            self.tail && self.idx == self.stop - 1 && matches!(self.chunk.ops[self.pc], Op::Step);
+        if self.is_tail {
+            self.pc += 1;
+        }
+    }
+
+    // The depth of the op at 'pc'. The tree-walking evaluator doesn't enter
+    // a call in tail position, so its arguments are one level less deep.
+    fn depth(&self, pc: usize) -> usize {
+        self.base + self.chunk.levels[pc] as usize - self.is_tail as usize
+    }
+}
+
+// Why the ops of the current frame stopped running
+enum Event {
+    // The frame is done, with the value of its last expression
+    Done(ValRef, Scope),
+    // A call to a lambda or block, with its arguments on top of the stack
+    Call(ValRef, usize, Scope),
+    // A call which a native returned as a tail call, and the depth of its call expression
+    Chain(Box<TailCallVal>, usize, Scope),
+    // The tail call of a body, with its arguments above the frame on the stack
+    TailCall(ValRef, usize, Scope),
+    // An error, which has the location and the call sites of the op it came from
+    Error(Unwind),
+}
+
+// The arguments of a step
+enum Args {
+    Stack(usize),
+    Vec(Vec<ValRef>),
+}
+
+struct Vm {
+    ctx: Rc<Context>,
+    stack: Vec<ValRef>,
+    // The frames below the current one, with their scopes
+    frames: Vec<(Frame, Scope)>,
+}
+
+// Evaluate the top-level expressions of the chunk which are in 'range',
+// returning the value of the last one. With 'tail', a call in the last
+// expression is returned as a ValRef::TailCall, like 'eval::eval_multiple_tail'.
+//
+// The depth of each op's expression is counted from the depth at the start,
+// like the tree-walking evaluator, which enters every nested expression,
+// so recursion hits the depth limit at the same point with both backends.
+pub fn run(chunk: Rc<Chunk>, range: Range<usize>, tail: bool, scope: Scope) -> FuncResult {
+    let ctx = scope.context().clone();
+    let base = ctx.depth();
+    let frame = Frame::new(chunk, range, tail, base, 0);
+    let mut vm = Vm {
+        ctx: ctx.clone(),
+        stack: Vec::new(),
+        frames: Vec::new(),
+    };
+    let res = vm.execute(frame, scope);
+    ctx.set_depth(base);
+    res
+}
+
+impl Vm {
+    fn execute(&mut self, mut f: Frame, mut scope: Scope) -> FuncResult {
+        loop {
+            let res = match self.run_ops(&mut f, scope) {
+                Event::Done(val, scope) => match f.call.take() {
+                    Some(call) => self.ret(&mut f, call, val),
+                    None => return Ok((val, scope)),
+                },
+                Event::Call(func, argc, scope) => self.call(&mut f, func, argc, scope),
+                Event::Chain(tc, depth, scope) => {
+                    let tc = *tc;
+                    let site = tc.site.map(|site| TailSite::Val(Box::new(site)));
+                    let args = Args::Vec(tc.args);
+                    let call = Call::new(Owner::Caller, depth);
+                    self.step(&mut f, call, tc.func, args, tc.scope, site, scope)
+                }
+                Event::TailCall(func, site, tc_scope) => {
+                    let (done, scope) = self.leave(&mut f);
+                    let argc = self.stack.len() - done.sp;
+                    let site = TailSite::Op(done.chunk, site);
+                    let mut call = done.call.unwrap();
+                    call.step_done();
+                    let args = Args::Stack(argc);
+                    self.step(&mut f, call, func, args, tc_scope, Some(site), scope)
+                }
+                Event::Error(err) => Err(err),
+            };
+
+            scope = match res {
+                Ok(scope) => scope,
+                Err(err) => self.unwind(&mut f, err)?,
+            };
+        }
+    }
+
+    // Run the ops of the current frame, until it's done or it makes a call which
+    // needs a frame. Calls to natives are run here.
+    fn run_ops(&mut self, f: &mut Frame, mut scope: Scope) -> Event {
+        let ctx = self.ctx.clone();
+        loop {
+            if f.pc == f.end {
+                f.idx += 1;
+                if f.idx >= f.stop {
+                    let val = match self.stack.len() > f.sp {
+                        true => self.stack.pop().unwrap(),
+                        false => ValRef::None,
+                    };
+                    return Event::Done(val, scope);
+                }
+
+                // The value of the previous expression is dropped before
+                // the next expression is evaluated
+                self.stack.truncate(f.sp);
+                f.start();
+            }
+
+            let pc = f.pc;
+            let op = f.chunk.ops[pc];
+            if !matches!(op, Op::Call(..) | Op::EmptyCall) {
+                if let Err(err) = ctx.enter_at(f.depth(pc)) {
+                    return Event::Error(error_at(&f.chunk, err, pc));
+                }
+            }
+
+            match op {
+                Op::Step => (),
+                Op::String(idx) => {
+                    let s = f.chunk.strings[idx as usize].clone();
+                    self.stack.push(ValRef::String(s));
+                }
+                Op::Number(num) => self.stack.push(ValRef::Number(num)),
+                Op::Int(num) => self.stack.push(ValRef::Int(num)),
+                Op::BigInt(idx) => {
+                    let num = f.chunk.bigints[idx as usize].clone();
+                    self.stack.push(ValRef::BigInt(num));
+                }
+                Op::Rational(idx) => {
+                    let num = f.chunk.rationals[idx as usize].clone();
+                    self.stack.push(ValRef::Rational(num));
+                }
+                Op::Lookup(idx) | Op::Local(idx, ..) => {
+                    let name = f.chunk.names[idx as usize];
+                    let val = match op {
+                        Op::Local(_, frames, slot) => scope.lookup_local(name, frames, slot),
+                        _ => scope.lookup(name),
+                    };
+
+                    let mut val = match val {
+                        Some(val) => val,
+                        None => {
+                            let err = StackTrace::from_string(format!(
+                                "Variable '{}' doesn't exist",
+                                name
+                            ));
+                            return Event::Error(error_at(&f.chunk, err, pc));
+                        }
+                    };
+
+                    if matches!(val, ValRef::Lazy(..) | ValRef::ProtectedLazy(..)) {
+                        (val, scope) = match eval::resolve(val, scope) {
+                            Ok(res) => res,
+                            Err(err) => return Event::Error(error_at(&f.chunk, err, pc)),
+                        };
+                    }
+
+                    self.stack.push(val);
+                }
+                Op::Keyword(idx) => self
+                    .stack
+                    .push(ValRef::Keyword(f.chunk.names[idx as usize])),
+                Op::BadKeyword(idx) => {
+                    let err = eval::keyword_error(f.chunk.names[idx as usize]);
+                    return Event::Error(error_at(&f.chunk, err, pc));
+                }
+                Op::Block(idx) => {
+                    let block = f.chunk.blocks[idx as usize].clone();
+                    self.stack.push(ValRef::Block(block));
+                }
+                Op::Call(argc, site) => {
+                    let func = self.stack.pop().unwrap();
+                    let argc = argc as usize;
+
+                    if f.is_tail && pc == f.end - 1 {
+                        if f.call.is_some() {
+                            return Event::TailCall(func, site as usize, scope);
+                        }
+
+                        let args = self.stack.split_off(self.stack.len() - argc);
+                        let site = &f.chunk.sites[site as usize];
+                        let tc = TailCallVal {
+                            func,
+                            args,
+                            scope: scope.clone(),
+                            site: Some((site.location.clone(), site.name.clone())),
+                        };
+                        return Event::Done(ValRef::TailCall(Box::new(tc)), scope);
+                    }
+
+                    let depth = f.depth(pc);
+                    ctx.set_depth(depth);
+                    if matches!(func, ValRef::Lambda(..) | ValRef::Block(..)) {
+                        return Event::Call(func, argc, scope);
+                    }
+
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    let val;
+                    (val, scope) = match eval::call_step(&func, args, scope, false) {
+                        Ok(res) => res,
+                        Err(err) => return Event::Error(error_at(&f.chunk, err, pc)),
+                    };
+
+                    if let ValRef::TailCall(tc) = val {
+                        return Event::Chain(tc, depth, scope);
+                    }
+
+                    scope = match self.complete(f, val, scope) {
+                        Ok(scope) => scope,
+                        Err(err) => return Event::Error(err),
+                    };
+                    continue;
+                }
+                Op::Property => {
+                    let val = self.stack.pop().unwrap();
+                    let key = self.stack.pop().unwrap();
+                    let mut val = match eval::get_property(&val, &key) {
+                        Ok(val) => val,
+                        Err(err) => return Event::Error(error_at(&f.chunk, err, pc)),
+                    };
+
+                    if matches!(val, ValRef::Lazy(..) | ValRef::ProtectedLazy(..)) {
+                        (val, scope) = match eval::resolve(val, scope) {
+                            Ok(res) => res,
+                            Err(err) => return Event::Error(error_at(&f.chunk, err, pc)),
+                        };
+                    }
+
+                    self.stack.push(val);
+                }
+                Op::EmptyCall => {
+                    let err = StackTrace::from_str("Call list has no elements");
+                    return Event::Error(error_at(&f.chunk, err, pc));
+                }
+            }
+
+            f.pc += 1;
+        }
+    }
+
+    // Push the value of the call at the current frame's op, once the call is done.
+    // Resolving the value isn't part of the call expression,
+    // so an error from that doesn't have the call's site.
+    fn complete(
+        &mut self,
+        f: &mut Frame,
+        mut val: ValRef,
+        mut scope: Scope,
+    ) -> Result<Scope, Unwind> {
+        if matches!(val, ValRef::Lazy(..) | ValRef::ProtectedLazy(..)) {
+            self.ctx.set_depth(f.depth(f.pc));
+            (val, scope) = match eval::resolve(val, scope) {
+                Ok(res) => res,
+                Err(err) => {
+                    let err = err.at(&f.chunk.locations[f.pc]);
+                    return Err(with_sites(&f.chunk, err, f.pc + 1));
+                }
+            };
+        }
+
+        self.stack.push(val);
+        f.pc += 1;
+        Ok(scope)
+    }
+
+    // Make a frame for the body of a step the current one. The caller
+    // makes the body's scope, and 'scope' is kept as the caller's.
+    fn enter(&mut self, f: &mut Frame, body: &Rc<Block>, call: Call, scope: Scope) {
+        let chunk = self.ctx.compiled(body);
+        let range = 0..chunk.len();
+        let mut frame = Frame::new(chunk, range, true, call.depth, self.stack.len());
+        frame.call = Some(call);
+        let caller = mem::replace(f, frame);
+        self.frames.push((caller, scope));
+    }
+
+    // Go back to the caller's frame, returning the frame which was left and the caller's scope
+    fn leave(&mut self, f: &mut Frame) -> (Frame, Scope) {
+        let (caller, scope) = self.frames.pop().unwrap();
+        (mem::replace(f, caller), scope)
+    }
+
+    // The first step of a call to a lambda or block
+    fn call(
+        &mut self,
+        f: &mut Frame,
+        func: ValRef,
+        argc: usize,
+        scope: Scope,
+    ) -> Result<Scope, Unwind> {
+        let depth = f.depth(f.pc);
+        let start = self.stack.len() - argc;
+        match &func {
+            ValRef::Lambda(l) => {
+                let args = self.stack.drain(start..);
+                match eval::lambda_frame(l, &func, args, &scope, false) {
+                    Ok(subscope) => {
+                        self.enter(f, &l.body, Call::new(Owner::Lambda, depth), scope);
+                        Ok(subscope)
+                    }
+                    Err(err) => {
+                        let call = Call::new(Owner::Lambda, depth);
+                        self.fail(f, call, err, scope)
+                    }
+                }
+            }
+            ValRef::Block(b) => {
+                self.stack.truncate(start);
+                let subscope = scope.subscope();
+                self.enter(f, b, Call::new(Owner::Caller, depth), scope);
+                Ok(subscope)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Run the steps of a call after the first, starting with the tail call of 'func'
+    // in 'tc_scope'. The caller's frame is the current one, and 'scope' is its scope.
+    #[allow(clippy::too_many_arguments)]
+    fn step(
+        &mut self,
+        f: &mut Frame,
+        mut call: Call,
+        mut func: ValRef,
+        mut args: Args,
+        mut tc_scope: Scope,
+        mut site: Option<TailSite>,
+        scope: Scope,
+    ) -> Result<Scope, Unwind> {
+        loop {
+            // A lambda in tail position is lexically in the body of the lambda
+            // which called it, so signals from it can't reach the loop either
+            if matches!(func, ValRef::Lambda(..)) {
+                call.owner = Owner::Lambda;
+            }
+            self.ctx.set_depth(call.depth);
+            call.site = site;
+
+            match &func {
+                ValRef::Lambda(l) => {
+                    let res = match args {
+                        Args::Stack(argc) => {
+                            let start = self.stack.len() - argc;
+                            let args = self.stack.drain(start..);
+                            eval::lambda_frame(l, &func, args, &tc_scope, true)
+                        }
+                        Args::Vec(args) => {
+                            eval::lambda_frame(l, &func, args.into_iter(), &tc_scope, true)
+                        }
+                    };
+
+                    call.step_scope = Some(tc_scope);
+                    return match res {
+                        Ok(subscope) => {
+                            self.enter(f, &l.body, call, scope);
+                            Ok(subscope)
+                        }
+                        Err(err) => self.fail(f, call, err, scope),
+                    };
+                }
+                ValRef::Block(b) => {
+                    if let Args::Stack(argc) = args {
+                        self.stack.truncate(self.stack.len() - argc);
+                    }
+
+                    let subscope = tc_scope.subscope();
+                    call.step_scope = Some(tc_scope);
+                    self.enter(f, b, call, scope);
+                    return Ok(subscope);
+                }
+                _ => (),
+            }
+
+            let argv = match args {
+                Args::Stack(argc) => self.stack.split_off(self.stack.len() - argc),
+                Args::Vec(argv) => argv,
+            };
+
+            let res = eval::call_step(&func, argv, tc_scope.clone(), true);
+            call.step_scope = Some(tc_scope);
+            let val = match res {
+                Ok((val, _)) => val,
+                Err(err) => return self.fail(f, call, err, scope),
+            };
+
+            call.step_done();
+            let tc = match val {
+                ValRef::TailCall(tc) => *tc,
+                val => {
+                    return match call.finish(val) {
+                        Ok(val) => self.complete(f, val, scope),
+                        Err(err) => Err(error_at(&f.chunk, err, f.pc)),
+                    };
+                }
+            };
+
+            func = tc.func;
+            args = Args::Vec(tc.args);
+            tc_scope = tc.scope;
+            site = tc.site.map(|site| TailSite::Val(Box::new(site)));
+        }
+    }
+
+    // The body of a step is done with a value, so the call is done
+    fn ret(&mut self, f: &mut Frame, mut call: Call, val: ValRef) -> Result<Scope, Unwind> {
+        let (done, scope) = self.leave(f);
+        self.stack.truncate(done.sp);
+        call.step_done();
+        self.ctx.set_depth(call.depth);
+        match call.finish(val) {
+            Ok(val) => self.complete(f, val, scope),
+            Err(err) => Err(error_at(&f.chunk, err, f.pc)),
+        }
+    }
+
+    // A step of the call at the current frame's op failed
+    fn fail(
+        &mut self,
+        f: &mut Frame,
+        call: Call,
+        err: Unwind,
+        scope: Scope,
+    ) -> Result<Scope, Unwind> {
+        match call.unwind(err, &scope) {
+            Ok(val) => self.complete(f, val, scope),
+            Err(err) => Err(error_at(&f.chunk, err, f.pc)),
+        }
+    }
+
+    // Leave frames until a call handles the error from the current frame, which has
+    // the frame's call sites. Returns the scope of the frame which goes on.
+    fn unwind(&mut self, f: &mut Frame, mut err: Unwind) -> Result<Scope, Unwind> {
+        loop {
+            let call = match f.call.take() {
+                Some(call) => call,
+                None => return Err(err),
+            };
+
+            let (done, scope) = self.leave(f);
+            self.stack.truncate(done.sp);
+
+            // Like in 'eval::call_step', loops go on in the scope the body was called in
+            err = err.resume_in(call.step_scope.as_ref().unwrap_or(&scope));
+            match self.fail(f, call, err, scope) {
+                Ok(scope) => return Ok(scope),
+                Err(next) => err = next,
+            }
+        }
+    }
+}
@@ -0,0 +1,955 @@
+use osyris::bstring::BString;
+use osyris::convert::{FromValRef, IntoValRef};
+use osyris::eval::{
+    Abort, Backend, FuncArgs, FuncResult, NativeObject, Opaque, Scope, StackTrace, ValRef,
+};
+use osyris::interpreter::{Error, Interpreter, Value};
+use osyris::parse::{self, Next, StreamReader};
+use osyris::stdlib::{ReadPort, StdIo, WritePort};
+use osyris::symbol::Symbol;
+
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+fn abort_of(res: Result<ValRef, Error>) -> Option<Abort> {
+    match res {
+        Err(err) => err.trace().and_then(|trace| trace.abort),
+        Ok(val) => panic!("Expected an error, got {}", val),
+    }
+}
+
+#[test]
+fn fuel_stops_infinite_loops() {
+    let mut interp = Interpreter::new();
+    interp.context().set_fuel(Some(1000));
+
+    let res = interp.eval_str("(while {true} {})");
+    assert_eq!(abort_of(res), Some(Abort::OutOfFuel));
+    assert_eq!(interp.context().fuel(), Some(0));
+    assert_eq!(interp.context().fuel_used(), 1000);
+}
+
+#[test]
+fn fuel_abort_cant_be_caught() {
+    let mut interp = Interpreter::new();
+    interp.context().set_fuel(Some(1000));
+
+    let res = interp.eval_str("(try {(while {true} {})} (lambda 'err {10}))");
+    assert_eq!(abort_of(res), Some(Abort::OutOfFuel));
+}
+
+#[test]
+fn fuel_is_counted() {
+    let mut interp = Interpreter::new();
+    interp.context().set_fuel(Some(1000));
+
+    let val = interp.eval_str("[1 + 2]").unwrap();
+    assert_eq!(val.to_num(), 3.0);
+
+    let used = interp.context().fuel_used();
+    assert!(used > 0);
+    assert_eq!(interp.context().fuel(), Some(1000 - used));
+
+    // Setting the fuel resets the count, and unlimited fuel is still counted
+    interp.context().set_fuel(None);
+    interp.eval_str("[1 + 2]").unwrap();
+    assert_eq!(interp.context().fuel(), None);
+    assert_eq!(interp.context().fuel_used(), used);
+}
+
+#[test]
+fn interrupt_from_another_thread() {
+    let mut interp = Interpreter::new();
+    let handle = interp.context().interrupt_handle();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+
+    let res = interp.eval_str("(try {(while {true} {})} (lambda 'err {10}))");
+    thread.join().unwrap();
+    assert_eq!(abort_of(res), Some(Abort::Interrupted));
+
+    // The interrupt is consumed, so evaluation can go on
+    assert!(!interp.context().interrupt_handle().is_interrupted());
+    assert_eq!(interp.eval_str("[1 + 2]").unwrap().to_num(), 3.0);
+}
+
+#[test]
+fn interrupt_while_idle_is_discarded() {
+    let mut interp = Interpreter::new();
+    interp.context().interrupt_handle().interrupt();
+    assert_eq!(interp.eval_str("[1 + 2]").unwrap().to_num(), 3.0);
+
+    interp.eval_str("(def 'f (lambda {[1 + 2]}))").unwrap();
+    interp.context().interrupt_handle().interrupt();
+    assert_eq!(interp.call_global("f", vec![]).unwrap().to_num(), 3.0);
+}
+
+#[test]
+fn deadline_stops_infinite_loops() {
+    let mut interp = Interpreter::new();
+    interp.context().set_timeout(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let res = interp.eval_str("(try {(while {true} {})} (lambda 'err {10}))");
+    assert_eq!(abort_of(res), Some(Abort::DeadlineExceeded));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // The deadline stays until it's cleared
+    assert_eq!(
+        abort_of(interp.eval_str("(while {true} {})")),
+        Some(Abort::DeadlineExceeded)
+    );
+    interp.context().set_deadline(None);
+    assert_eq!(interp.eval_str("[1 + 2]").unwrap().to_num(), 3.0);
+}
+
+#[test]
+fn memory_limit_counts_live_values() {
+    let mut interp = Interpreter::new();
+    interp.context().set_max_memory(Some(100_000));
+
+    // Values which are freed give their memory back,
+    // and literals don't count at all
+    interp
+        .eval_str(
+            "(def 'i 0)
+            (while {[i < 10000]} {
+                (def 's \"x\")
+                (def 'l (list i i i))
+                (def 'str (string \"number \" i))
+                (set 'i [i + 1])
+            })",
+        )
+        .unwrap();
+    assert!(interp.context().memory_used() < 1000);
+
+    // Values which stay alive keep their memory
+    interp
+        .eval_str(
+            "(def 'l (list))
+            (def 'i 0)
+            (while {[i < 1000]} {
+                (mutate 'l list-push (string \"item\"))
+                (set 'i [i + 1])
+            })",
+        )
+        .unwrap();
+    assert!(interp.context().memory_used() > 10_000);
+
+    interp.eval_str("(set 'l none)").unwrap();
+    assert!(interp.context().memory_used() < 1000);
+
+    // The values from an evaluation which failed are freed
+    let res = interp.eval_str(
+        "(def 'l (list))
+        (while {true} {(mutate 'l list-push (string \"item\"))})",
+    );
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+    assert!(interp.context().memory_used() < 1000);
+}
+
+#[test]
+fn memory_limit_cant_be_caught() {
+    let mut interp = Interpreter::new();
+    interp.context().set_max_memory(Some(10_000));
+
+    let res = interp.eval_str(
+        "(try {
+            (def 'l (list))
+            (while {true} {(mutate 'l list-push 1)})
+        } (lambda 'err {10}))",
+    );
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+}
+
+#[test]
+fn memory_limit_counts_tail_call_frames() {
+    let mut interp = Interpreter::new();
+    interp.context().set_max_memory(Some(100_000));
+
+    // Each frame is kept alive by the next one's 'caller-scope'
+    interp
+        .eval_str("(def 'f (lambda 'n {(if [n == 0] {n} {(self [n - 1])})}))")
+        .unwrap();
+    let res = interp.eval_str("(f 100000)");
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+
+    // The frames are freed once the chain of calls is done
+    interp.eval_str("(f 100)").unwrap();
+    interp.eval_str("(f 100)").unwrap();
+    assert!(interp.context().memory_used() < 10_000);
+}
+
+#[test]
+fn memory_limit_counts_registered_fn_results() {
+    let mut interp = Interpreter::new();
+    interp.context().set_max_memory(Some(100_000));
+    interp.register_fn("chunk", |n: i64| vec!["x".repeat(1000); n as usize]);
+    interp.register_fn("ints", |n: i64| vec![0i64; n as usize]);
+
+    let res = interp.eval_str("(chunk 200)");
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+    let res = interp.eval_str("(ints 100000)");
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+
+    // Only the values which are kept alive count
+    interp.eval_str("(def 'l (chunk 20))").unwrap();
+    assert!(interp.context().memory_used() > 20_000);
+    interp.eval_str("(def 'l none)").unwrap();
+    assert!(interp.context().memory_used() < 10_000);
+    interp.eval_str("(chunk 20)").unwrap();
+}
+
+fn to_string(val: ValRef) -> String {
+    String::from_valref(val).unwrap()
+}
+
+#[test]
+fn builder_without_libraries() {
+    let mut interp = Interpreter::builder()
+        .stdlib(false)
+        .iolib(false)
+        .importlib(false)
+        .build();
+
+    assert_eq!(interp.eval_str("10").unwrap().to_num(), 10.0);
+    let err = interp.eval_str("(print 10)").unwrap_err();
+    assert_eq!(
+        err.trace().unwrap().message,
+        "Variable 'print' doesn't exist"
+    );
+    assert!(interp.get("import").is_none());
+}
+
+#[test]
+fn builder_with_stdio() {
+    let out = Rc::new(RefCell::new(Vec::<u8>::new()));
+    let stdio = StdIo {
+        stdin: Rc::new(RefCell::new(ReadPort::new(Rc::new(RefCell::new(
+            &b"input"[..],
+        ))))),
+        stdout: Rc::new(RefCell::new(WritePort::new(out.clone()))),
+        stderr: Rc::new(RefCell::new(WritePort::new(out.clone()))),
+    };
+    let mut interp = Interpreter::builder().stdio(stdio).build();
+
+    interp.eval_str("(print \"hello\" 10)").unwrap();
+    assert_eq!(&out.borrow()[..], b"hello 10\n");
+    assert_eq!(to_string(interp.eval_str("(read stdin)").unwrap()), "input");
+}
+
+#[test]
+fn globals() {
+    for backend in [Backend::Tree, Backend::Vm] {
+        let mut interp = Interpreter::builder().backend(backend).build();
+
+        interp.set("x", 10.into_valref());
+        interp
+            .eval_str("(def 'add (lambda 'a 'b {[a + b]}))")
+            .unwrap();
+        assert_eq!(interp.eval_str("(add x 5)").unwrap().to_num(), 15.0);
+
+        // Globals persist across evaluations, but an evaluation which fails has no effect
+        interp.eval_str("(def 'y 20)").unwrap();
+        assert!(interp.eval_str("(def 'z 30) (error \"oops\")").is_err());
+        assert_eq!(interp.get("y").unwrap().to_num(), 20.0);
+        assert!(interp.get("z").is_none());
+
+        let args = vec![1.into_valref(), 2.into_valref()];
+        assert_eq!(interp.call_global("add", args).unwrap().to_num(), 3.0);
+
+        let err = interp.call_global("nope", vec![]).unwrap_err();
+        assert_eq!(
+            err.trace().unwrap().message,
+            "Variable 'nope' doesn't exist"
+        );
+
+        let names: Vec<String> = interp
+            .variables()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        assert_eq!(names, vec!["add", "x", "y"]);
+    }
+}
+
+#[test]
+fn eval_file() {
+    let dir = std::env::temp_dir().join(format!("osyris-embed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("lib.os"), "(dict 'ten 10)\n").unwrap();
+    fs::write(
+        dir.join("main.os"),
+        "(def 'lib (import \"lib.os\"))\n(def 'twenty [lib.ten * 2])\ntwenty\n",
+    )
+    .unwrap();
+
+    let mut interp = Interpreter::new();
+    let path = BString::from_os_str(dir.join("main.os").as_os_str());
+    let res = interp.eval_file(&path);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Imports are relative to the file, and its variables become globals
+    assert_eq!(res.unwrap().to_num(), 20.0);
+    assert_eq!(interp.get("twenty").unwrap().to_num(), 20.0);
+
+    let err = interp.eval_file(&path).unwrap_err();
+    assert!(matches!(err, Error::Io(Some(_), _)));
+}
+
+#[test]
+fn register_fn() {
+    let mut interp = Interpreter::new();
+    interp.register_fn("repeat", |s: String, n: i64| s.repeat(n as usize));
+    interp.register_fn(
+        "pad",
+        |s: String, width: Option<i64>, fill: Option<String>| {
+            let fill = fill.unwrap_or(" ".to_string());
+            let width = width.unwrap_or(6) as usize;
+            format!("{}{}", fill.repeat(width.saturating_sub(s.len())), s)
+        },
+    );
+    interp.register_fn("sqrt", |n: f64| -> Result<f64, String> {
+        if n < 0.0 {
+            Err("Negative number".into())
+        } else {
+            Ok(n.sqrt())
+        }
+    });
+
+    let eval = |interp: &mut Interpreter, code: &str| match interp.eval_str(code) {
+        Ok(val) => to_string(val),
+        Err(err) => err.trace().unwrap().message.clone(),
+    };
+
+    assert_eq!(eval(&mut interp, "(repeat \"ab\" 3)"), "ababab");
+    assert_eq!(eval(&mut interp, "(pad \"ab\")"), "    ab");
+    assert_eq!(eval(&mut interp, "(pad \"ab\" 3)"), " ab");
+    assert_eq!(eval(&mut interp, "(pad \"ab\" 3 \"-\")"), "-ab");
+    assert_eq!(eval(&mut interp, "(pad \"ab\" none \"-\")"), "----ab");
+    assert_eq!(eval(&mut interp, "(string (sqrt 9))"), "3.0");
+
+    assert_eq!(
+        eval(&mut interp, "(repeat \"ab\")"),
+        "'repeat' expects 2 arguments, got 1"
+    );
+    assert_eq!(
+        eval(&mut interp, "(sqrt)"),
+        "'sqrt' expects 1 argument, got 0"
+    );
+    assert_eq!(
+        eval(&mut interp, "(pad)"),
+        "'pad' expects 1 to 3 arguments, got 0"
+    );
+    assert_eq!(
+        eval(&mut interp, "(pad \"ab\" 1 \"-\" 4)"),
+        "'pad' expects 1 to 3 arguments, got 4"
+    );
+    assert_eq!(
+        eval(&mut interp, "(repeat \"ab\" \"x\")"),
+        "'repeat' parameter 2: Expected int, got string"
+    );
+    assert_eq!(eval(&mut interp, "(sqrt -1)"), "Negative number");
+}
+
+struct Counter {
+    count: Cell<i64>,
+}
+
+impl NativeObject for Counter {
+    fn type_name(&self) -> &str {
+        "counter"
+    }
+
+    fn get(&self, key: &BString) -> Option<ValRef> {
+        match key.as_bytes() {
+            b"count" => Some(ValRef::Int(self.count.get())),
+            _ => None,
+        }
+    }
+
+    fn set(&self, key: &BString, val: ValRef) -> Result<(), String> {
+        match key.as_bytes() {
+            b"count" => {
+                self.count.set(i64::from_valref(val)?);
+                Ok(())
+            }
+            _ => Err(format!("Can't set '{}' on counter", key)),
+        }
+    }
+
+    fn has_method(&self, name: &BString) -> bool {
+        name.as_bytes() == b"incr"
+    }
+
+    fn call_method(&self, _: &BString, args: Vec<ValRef>, scope: Scope) -> FuncResult {
+        let by = match args.into_iter().next() {
+            Some(val) => i64::from_valref(val).map_err(StackTrace::from_string)?,
+            None => 1,
+        };
+        self.count.set(self.count.get() + by);
+        Ok((ValRef::Int(self.count.get()), scope))
+    }
+
+    fn call(&self, args: Vec<ValRef>, scope: Scope) -> FuncResult {
+        let val = format!("called with {}", args.len());
+        Ok((val.into_valref(), scope))
+    }
+}
+
+#[test]
+fn native_objects() {
+    for backend in [Backend::Tree, Backend::Vm] {
+        let mut interp = Interpreter::builder().backend(backend).build();
+        let counter = Rc::new(Counter {
+            count: Cell::new(0),
+        });
+        interp.set("c", ValRef::Native(counter.clone()));
+
+        let mut eval = |code: &str| match interp.eval_str(code) {
+            Ok(val) => val.to_bstring().to_string(),
+            Err(err) => err.trace().unwrap().message.clone(),
+        };
+
+        assert_eq!(eval("c.count"), "0");
+        assert_eq!(eval("(c.incr)"), "1");
+        assert_eq!(eval("(c.incr 5)"), "6");
+        assert_eq!(eval("(dict-set c 'count 10) c.count"), "10");
+        assert_eq!(counter.count.get(), 10);
+
+        // Calling the object with a string doesn't look up properties
+        assert_eq!(eval("(c \"count\")"), "called with 1");
+        assert_eq!(eval("(c)"), "called with 0");
+
+        assert_eq!(eval("c.missing"), "counter has no property 'missing'");
+        assert_eq!(eval("(c.missing 1)"), "counter has no property 'missing'");
+        assert_eq!(eval("c.0"), "Attempt to get property 0 of counter");
+        assert_eq!(eval("(dict-set c 'x 1)"), "Can't set 'x' on counter");
+    }
+}
+
+#[test]
+fn natives_and_keyword_arguments() {
+    for backend in [Backend::Tree, Backend::Vm] {
+        let mut interp = Interpreter::builder().backend(backend).build();
+
+        // A native which doesn't take keyword arguments rejects them
+        interp.set(
+            "count",
+            ValRef::Func(Rc::new(
+                |mut args: Vec<ValRef>, scope: Scope| -> FuncResult {
+                    let mut args = args.drain(0..);
+                    let mut count = 0i64;
+                    while args.has_next() {
+                        args.next_val()?;
+                        count += 1;
+                    }
+                    args.done()?;
+                    Ok((count.into_valref(), scope))
+                },
+            )),
+        );
+
+        // A native which takes a keyword argument, after calling its argument
+        interp.set(
+            "width",
+            ValRef::Func(Rc::new(
+                |mut args: Vec<ValRef>, scope: Scope| -> FuncResult {
+                    let mut args = args.drain(0..);
+                    args.check_named(&["width"])?;
+                    let width = args.named("width").unwrap_or(ValRef::None);
+
+                    let mut inner = ValRef::None;
+                    let mut scope = scope;
+                    if args.has_next() {
+                        (inner, scope) = osyris::eval::call(&args.next_val()?, Vec::new(), scope)?;
+                    }
+
+                    Ok((format!("{} {}", inner, width).into_valref(), scope))
+                },
+            )),
+        );
+        interp.register_fn("repeat", |s: String, n: i64| s.repeat(n as usize));
+
+        let mut eval = |code: &str| match interp.eval_str(code) {
+            Ok(val) => val.to_bstring().to_string(),
+            Err(err) => err.trace().unwrap().message.clone(),
+        };
+
+        assert_eq!(eval("(count 1 2 3)"), "3");
+        assert_eq!(eval("(count 1 :a 2 3)"), "Unexpected keyword argument ':a'");
+        assert_eq!(
+            eval("(repeat \"ab\" :times 5 2)"),
+            "Unexpected keyword argument ':times'"
+        );
+        assert_eq!(eval("(repeat \"ab\" 2)"), "abab");
+
+        assert_eq!(eval("(width)"), "None None");
+        assert_eq!(eval("(width :width 10)"), "None 10");
+        assert_eq!(
+            eval("(width :height 10)"),
+            "Unexpected keyword argument ':height'"
+        );
+
+        // Keyword arguments belong to the call they're given to,
+        // not to natives which that call calls
+        assert_eq!(eval("(width :width 10 width)"), "\"None None\" 10");
+        assert_eq!(
+            eval("(width :width 10 {(width :width 20)})"),
+            "\"None 20\" 10"
+        );
+        assert_eq!(eval("(width :width 10 {(error \"oops\")})"), "oops");
+        assert_eq!(eval("(width)"), "None None");
+    }
+}
+
+#[test]
+fn opaque_objects() {
+    let mut interp = Interpreter::new();
+    interp.set("o", ValRef::Native(Rc::new(Opaque(42u32))));
+
+    let val = interp.eval_str("(def 'l (list o)) (l 0)").unwrap();
+    match val {
+        ValRef::Native(obj) => {
+            assert_eq!(obj.opaque_ref::<u32>(), Some(&42));
+            assert_eq!(obj.opaque_ref::<i32>(), None);
+        }
+        _ => panic!("Expected a native object, got {}", val),
+    }
+
+    let err = interp.eval_str("o.x").unwrap_err();
+    assert_eq!(err.trace().unwrap().message, "native has no property 'x'");
+
+    let err = interp.eval_str("(o)").unwrap_err();
+    assert_eq!(
+        err.trace().unwrap().message,
+        "Attempt to call non-function native"
+    );
+}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn errors_are_send_and_sync() {
+    assert_send_sync::<Error>();
+
+    fn run(code: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut interp = Interpreter::new();
+        interp.eval_str(code)?;
+        Ok(())
+    }
+
+    let err = std::thread::spawn(|| run("(error \"oops\")").unwrap_err())
+        .join()
+        .unwrap();
+    assert!(err
+        .to_string()
+        .starts_with("Error: oops\n  at <string>:1:1"));
+}
+
+#[test]
+fn error_conversion() {
+    let mut interp = Interpreter::new();
+
+    let err = interp.eval_str("(error \"oops\")").unwrap_err();
+    assert!(matches!(err, Error::Script(_)));
+    assert_eq!(err.value(), Some(&Value::String(BString::from_str("oops"))));
+    assert_eq!(err.exit_status(), None);
+
+    let err = interp
+        .eval_str("(error (list 1 2.5 \"x\" none))")
+        .unwrap_err();
+    assert_eq!(err.trace().unwrap().message, "[1, 2.5, \"x\", None]");
+    assert_eq!(
+        err.value(),
+        Some(&Value::List(vec![
+            Value::Int(1),
+            Value::Number(2.5),
+            Value::String(BString::from_str("x")),
+            Value::None,
+        ]))
+    );
+
+    let err = interp
+        .eval_str("(error (dict 'code 404 'big 1267650600228229401496703205376 'f print))")
+        .unwrap_err();
+    match err.value() {
+        Some(Value::Dict(dict)) => {
+            assert_eq!(dict[&BString::from_str("code")], Value::Int(404));
+            assert!(matches!(dict[&BString::from_str("big")], Value::BigInt(_)));
+            assert!(matches!(dict[&BString::from_str("f")], Value::Other(_)));
+        }
+        val => panic!("Expected a dict, got {:?}", val),
+    }
+
+    // The value can be sent to another thread
+    let value = err.value().unwrap().clone();
+    std::thread::spawn(move || assert!(matches!(value, Value::Dict(_))))
+        .join()
+        .unwrap();
+
+    // Lists which contain themselves, which only Rust code can make
+    let lst = Rc::new(RefCell::new(vec![ValRef::Int(1)]));
+    lst.borrow_mut().push(ValRef::List(lst.clone()));
+    assert_eq!(
+        Value::from(&ValRef::List(lst.clone())),
+        Value::List(vec![Value::Int(1), Value::Other("[...]".to_string())])
+    );
+    lst.borrow_mut().clear();
+
+    let err = interp.eval_str("(+ 1 nope)").unwrap_err();
+    assert!(matches!(err, Error::Runtime(_)));
+    assert_eq!(err.value(), None);
+    assert_eq!(
+        err.trace().unwrap().message,
+        "Variable 'nope' doesn't exist"
+    );
+
+    let err = interp.eval_str("(exit 3)").unwrap_err();
+    assert_eq!(err.exit_status(), Some(3));
+
+    let err = interp.eval_str("(+ 1").unwrap_err();
+    assert!(matches!(err, Error::Parse(_)));
+    assert!(err.trace().is_none());
+
+    let err: Error = StackTrace::from_string("native failure".to_string()).into();
+    assert!(matches!(err, Error::Runtime(_)));
+    assert_eq!(err.trace().unwrap().message, "native failure");
+
+    let err: Error = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+    assert!(matches!(err, Error::Io(None, _)));
+    assert_eq!(err.to_string(), "gone");
+}
+
+#[test]
+fn depth_is_counted_the_same_by_both_backends() {
+    // The deepest recursion which doesn't hit the limit
+    let deepest = |backend: Backend, code: &str| {
+        let mut interp = Interpreter::builder().backend(backend).build();
+        interp.context().set_max_depth(100);
+        interp.eval_str(code).unwrap();
+        interp.eval_str("(def 'f (lambda 'n {(g g n)}))").unwrap();
+        (0..)
+            .find(|n| interp.eval_str(&format!("(f {})", n)).is_err())
+            .unwrap()
+            - 1
+    };
+
+    // Lambdas can't see later definitions, so 'g' gets itself as an argument
+    let cases = [
+        "(def 'g (lambda 'g 'n {(if [n == 0] {0} {[1 + (g g [n - 1])]})}))",
+        "(def 'g (lambda 'g 'n {(if [n == 0] {0} {[1 + [1 + [1 + (g g [n - 1])]]]})}))",
+        "(def 'g (lambda 'g 'n {(if [n == 0] {0} {(try {(g g [n - 1])} (lambda 'e {(error e)}))})}))",
+        "(def 'g (lambda 'g 'n {(list-map (list n) (lambda 'x {(if [x == 0] {0} {(g g [x - 1])})}))}))",
+    ];
+
+    for code in cases {
+        let tree = deepest(Backend::Tree, code);
+        assert!(tree > 5, "{}", code);
+        assert_eq!(deepest(Backend::Vm, code), tree, "{}", code);
+    }
+}
+
+#[test]
+fn runtime_strings_arent_interned() {
+    let interned = |name: &str| Symbol::get(&BString::from_str(name)).is_some();
+    let mut interp = Interpreter::new();
+
+    // Dict keys are strings, not symbols
+    let val = interp
+        .eval_str(
+            "(def 'd (dict (string \"key-\" \"one\") 10))
+            (def 'd (dict-set d (string \"key-\" \"two\") 20))
+            (def 'd (dict-mutate d (string \"key-\" \"one\") + 1))
+            [(d (string \"key-\" \"one\")) + (d (string \"key-\" \"two\"))]",
+        )
+        .unwrap();
+    assert_eq!(val.to_num(), 31.0);
+    assert!(!interned("key-one"));
+    assert!(!interned("key-two"));
+
+    // Names of variables which don't exist aren't interned, even though
+    // the name of a variable which is defined is
+    for code in [
+        "(set (string \"set-\" \"name\") 10)",
+        "(mutate (string \"mutate-\" \"name\") + 1)",
+    ] {
+        assert!(interp.eval_str(code).is_err());
+    }
+    assert!(!interned("set-name"));
+    assert!(!interned("mutate-name"));
+    assert!(interp.get("get-name").is_none());
+    assert!(!interned("get-name"));
+
+    interp
+        .eval_str("(def (string \"def-\" \"name\") 10)")
+        .unwrap();
+    assert!(interned("def-name"));
+    assert_eq!(interp.get("def-name").unwrap().to_num(), 10.0);
+}
+
+#[test]
+fn interned_names_count_towards_the_memory_limit() {
+    let mut interp = Interpreter::new();
+    interp.context().set_max_memory(Some(10_000));
+
+    // Each new name stays interned, even after the variable is gone
+    let res = interp.eval_str(
+        "(def 'i 0)
+        (while {true} {
+            (with (string \"generated-name-\" i) i {})
+            (set 'i [i + 1])
+        })",
+    );
+    assert_eq!(abort_of(res), Some(Abort::MemoryLimitExceeded));
+}
+
+// Remove the ANSI escape sequences which 'render_error' adds for colors
+fn strip_colors(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            chars.find(|ch| *ch == 'm');
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+#[test]
+fn render_runtime_errors() {
+    let mut interp = Interpreter::new();
+    let err = interp
+        .eval_str("(def 'x 10)\n(print [x + (nope 1)])")
+        .unwrap_err();
+
+    let plain = interp.render_error(&err, false);
+    assert_eq!(
+        plain,
+        "Error: Variable 'nope' doesn't exist
+ --> <string>:2:14
+  |
+2 | (print [x + (nope 1)])
+  |              ^^^^
+  <string>: 2:13: nope
+  <string>: 2:8: +
+  <string>: 2:1: print
+"
+    );
+
+    let colored = interp.render_error(&err, true);
+    assert!(colored.contains("\x1b[1;31mError\x1b[0m"));
+    assert_eq!(strip_colors(&colored), plain);
+
+    // The whole span is underlined
+    let err = interp.eval_str("(error \"boom\")").unwrap_err();
+    let rendered = interp.render_error(&err, false);
+    assert!(rendered.starts_with("Error: boom\n"));
+    assert!(rendered.contains("1 | (error \"boom\")\n  | ^^^^^^^^^^^^^^\n"));
+}
+
+#[test]
+fn render_parse_errors() {
+    let mut interp = Interpreter::new();
+    let err = interp.eval_str("(def 'x 10\n(print x").unwrap_err();
+    assert!(matches!(err, Error::Parse(_)));
+
+    let plain = interp.render_error(&err, false);
+    assert_eq!(
+        plain,
+        "Parse error: Unexpected EOF
+ --> <string>:2:9
+  |
+2 | (print x
+  |         ^
+  = hint: unclosed '(' opened at 2:1
+"
+    );
+    assert_eq!(strip_colors(&interp.render_error(&err, true)), plain);
+}
+
+#[test]
+fn render_errors_in_imported_files() {
+    let dir = std::env::temp_dir().join(format!("osyris-render-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("lib.os"), "(def 'x 10)\n(error \"from lib\")\n").unwrap();
+    fs::write(dir.join("main.os"), "(import \"lib.os\")\n").unwrap();
+
+    let mut interp = Interpreter::new();
+    let path = BString::from_os_str(dir.join("main.os").as_os_str());
+    let err = interp.eval_file(&path).unwrap_err();
+    let rendered = interp.render_error(&err, false);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The source of the imported file is shown, even though it's not the file which was run
+    assert!(rendered.starts_with("Error: from lib\n"));
+    assert!(rendered.contains("2 | (error \"from lib\")\n"));
+    assert!(rendered.contains("main.os: 1:1: import"));
+}
+
+#[test]
+fn parse_all_recovers_from_errors() {
+    let parse_all = |code: &str| {
+        let mut reader = parse::Reader::new(code.as_bytes(), BString::from_str("test.os"));
+        parse::parse_all(&mut reader)
+    };
+
+    let (exprs, errs) = parse_all("(print 1)\n(print [1 + 2])\n");
+    assert_eq!(exprs.len(), 2);
+    assert!(errs.is_empty());
+
+    // Parsing continues after each error, and the expressions which could
+    // be parsed are returned
+    let (exprs, errs) = parse_all("(print 1) ) (print 2) (print (] 3)\n(foo");
+    let exprs: Vec<String> = exprs.iter().map(|expr| expr.to_string()).collect();
+    assert_eq!(exprs, vec!["(print 1)", "(print 2)", "(print (3))"]);
+
+    let errs: Vec<String> = errs.iter().map(|err| err.to_string()).collect();
+    assert_eq!(
+        errs,
+        vec![
+            "test.os:1:11: Unexpected ')'",
+            "test.os:1:31: Unexpected ']' (expected ')' to close '(' opened at 1:30)",
+            "test.os:2:1: Expected ')' (unclosed '(' opened at 1:23)",
+            "test.os:2:5: Unexpected EOF (unclosed '(' opened at 2:1)",
+        ]
+    );
+
+    let (_, errs) = parse_all("(print \"hi)\n");
+    assert_eq!(errs.len(), 1);
+    assert_eq!((errs[0].line, errs[0].col), (2, 1));
+    assert_eq!(errs[0].msg, "Unexpected EOF");
+    assert_eq!(
+        errs[0].hint.as_deref(),
+        Some("unclosed string opened at 1:8")
+    );
+}
+
+// Feed the input to a stream in chunks of 'size' bytes, collecting
+// the expressions and errors in the order they're found
+fn parse_stream(stream: &mut StreamReader, input: &str, size: usize) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut collect = |stream: &mut StreamReader| loop {
+        match stream.next_expr() {
+            Ok(Next::Expr(expr)) => out.push(expr.to_string()),
+            Ok(Next::NeedInput) | Ok(Next::End) => break,
+            Err(err) => out.push(format!("error: {}", err.msg)),
+        }
+    };
+
+    for chunk in input.as_bytes().chunks(size) {
+        stream.feed(chunk);
+        collect(stream);
+    }
+    stream.close();
+    collect(stream);
+    out
+}
+
+#[test]
+fn stream_reader_parses_chunks() {
+    let input = "(def 'x\n  (list 1 2))\n\"a ; string (\" ; comment )\nx.y [1 + 2] foo\n";
+    let expected = vec![
+        "(def \"x\" (list 1 2))",
+        "\"a ; string (\"",
+        "x.y",
+        "(+ 1 2)",
+        "foo",
+    ];
+    for size in [1, 2, 7, input.len()] {
+        let mut stream = StreamReader::new(BString::from_str("stream"));
+        assert_eq!(parse_stream(&mut stream, input, size), expected);
+    }
+
+    // An expression isn't complete until something follows it,
+    // since a property on the next line could still continue it
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.feed(b"(f 1)");
+    assert!(matches!(stream.next_expr(), Ok(Next::NeedInput)));
+    stream.feed(b".x");
+    assert!(matches!(stream.next_expr(), Ok(Next::NeedInput)));
+    stream.feed(b"\n  ; comment\n");
+    assert!(matches!(stream.next_expr(), Ok(Next::NeedInput)));
+    stream.feed(b"(g)");
+    match stream.next_expr() {
+        Ok(Next::Expr(expr)) => assert_eq!(expr.to_string(), "(f 1).x"),
+        _ => panic!("expected an expression"),
+    }
+    assert!(stream.has_partial());
+
+    // In interactive mode, the end of a line completes an expression
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.set_interactive(true);
+    stream.feed(b"(f 1)");
+    assert!(matches!(stream.next_expr(), Ok(Next::NeedInput)));
+    stream.feed(b".x\n");
+    assert!(matches!(stream.next_expr(), Ok(Next::Expr(_))));
+    assert!(matches!(stream.next_expr(), Ok(Next::NeedInput)));
+    assert!(!stream.has_partial());
+}
+
+#[test]
+fn stream_reader_property_after_newline() {
+    // A property chain can be split across lines, in a file or in a stream
+    let input = "foo\n  .bar\n  .baz ; comment\n\n  .0\nqux\n";
+    let expected = vec!["foo.bar.baz.0", "qux"];
+
+    let mut reader = parse::Reader::new(input.as_bytes(), BString::from_str("file"));
+    let (exprs, errs) = parse::parse_all(&mut reader);
+    let exprs: Vec<String> = exprs.iter().map(|expr| expr.to_string()).collect();
+    assert_eq!(exprs, expected);
+    assert!(errs.is_empty());
+
+    let mut interp = Interpreter::new();
+    let code = "(def 'd (dict 'a (dict 'b 10)))\n(string d\n  .a\n  .b)";
+    assert_eq!(to_string(interp.eval_str(code).unwrap()), "10");
+
+    for size in [1, 3, input.len()] {
+        let mut stream = StreamReader::new(BString::from_str("stream"));
+        assert_eq!(parse_stream(&mut stream, input, size), expected);
+    }
+
+    // Except in interactive mode, where each line is complete on its own
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.set_interactive(true);
+    let streamed = parse_stream(&mut stream, "foo\n.bar\n", 1);
+    assert_eq!(streamed, vec!["foo", "error: Unexpected '.'"]);
+
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.set_interactive(true);
+    assert_eq!(parse_stream(&mut stream, "foo .bar\n", 1), vec!["foo.bar"]);
+}
+
+#[test]
+fn stream_reader_limits_the_buffer() {
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.set_max_buffer(100);
+
+    let long = format!("(list {})\n(list 1)\n", "1 ".repeat(100));
+    let out = parse_stream(&mut stream, &long, 10);
+    assert_eq!(out[0], "error: Expression is longer than 100 bytes");
+    assert_eq!(out.last().unwrap(), "(list 1)");
+
+    // Many expressions in one chunk aren't a single long expression
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    stream.set_max_buffer(100);
+    let many = "(list 1 2 3)\n".repeat(20);
+    assert_eq!(parse_stream(&mut stream, &many, many.len()).len(), 20);
+}
+
+#[test]
+fn stream_reader_is_linear() {
+    // Each byte of a long expression is only scanned once until it's complete,
+    // so feeding it a byte at a time doesn't re-parse it each time
+    let input = format!("(list {})\n", "[1 + 2] ".repeat(20_000));
+    let start = Instant::now();
+    let mut stream = StreamReader::new(BString::from_str("stream"));
+    assert_eq!(parse_stream(&mut stream, &input, 1).len(), 1);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}